@@ -2,8 +2,8 @@ use std::{env, fs::File, path::Path};
 
 include!("src/config.rs");
 
-/// This function has two tasks: to generate the schema for the config file,
-/// and to set the sysroot properly if cross-compiling.
+/// This function has two tasks: to generate the schema for the config file, which gets
+/// embedded in the binary, and to set the sysroot properly if cross-compiling.
 fn main() {
     // We should only regen the schema if config.rs changed.
     // The sysroot thing will never need to be re-run.
@@ -20,12 +20,6 @@ fn main() {
     let schema = schemars::schema_for!(Config);
     serde_json::to_writer_pretty(schema_file, &schema).expect("Schema deserialization failed!");
 
-    // Note where the schema is, for the user's benefit.
-    println!(
-        "cargo:warning=Schema at: {}",
-        schema_path.as_os_str().to_string_lossy()
-    );
-
     // Figure out what the host and target are.
     let host = env::var("HOST").unwrap();
     let target = env::var("TARGET").unwrap();
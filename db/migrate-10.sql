@@ -0,0 +1,9 @@
+BEGIN;
+CREATE TABLE IF NOT EXISTS key_ips(
+  key_id INTEGER NOT NULL,
+  ip TEXT NOT NULL,
+  first_seen INTEGER NOT NULL,
+  PRIMARY KEY(key_id, ip)
+);
+PRAGMA user_version = 10;
+COMMIT;
\ No newline at end of file
@@ -0,0 +1,5 @@
+BEGIN;
+ALTER TABLE api_keys ADD COLUMN previous_key_base64 TEXT;
+ALTER TABLE api_keys ADD COLUMN previous_expiration INTEGER;
+PRAGMA user_version = 11;
+COMMIT;
\ No newline at end of file
@@ -0,0 +1,4 @@
+BEGIN;
+ALTER TABLE api_keys ADD COLUMN scopes TEXT NOT NULL DEFAULT 'location:write';
+PRAGMA user_version = 12;
+COMMIT;
\ No newline at end of file
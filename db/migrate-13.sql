@@ -0,0 +1,11 @@
+BEGIN;
+CREATE TABLE IF NOT EXISTS people(
+  id INTEGER PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  primary_key_id INTEGER
+);
+ALTER TABLE api_keys ADD COLUMN person_id INTEGER;
+INSERT OR IGNORE INTO people(name) SELECT DISTINCT username FROM api_keys;
+UPDATE api_keys SET person_id = (SELECT id FROM people WHERE name IS api_keys.username);
+PRAGMA user_version = 13;
+COMMIT;
\ No newline at end of file
@@ -0,0 +1,18 @@
+BEGIN;
+CREATE TABLE IF NOT EXISTS groups(
+  id INTEGER PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE
+);
+CREATE TABLE IF NOT EXISTS group_members(
+  group_id INTEGER NOT NULL,
+  username TEXT NOT NULL,
+  PRIMARY KEY(group_id, username)
+);
+CREATE TABLE IF NOT EXISTS group_devices(
+  group_id INTEGER NOT NULL,
+  key_id INTEGER NOT NULL,
+  PRIMARY KEY(group_id, key_id)
+);
+CREATE INDEX IF NOT EXISTS group_devices_by_key ON group_devices(key_id);
+PRAGMA user_version = 14;
+COMMIT;
\ No newline at end of file
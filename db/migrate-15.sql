@@ -0,0 +1,17 @@
+BEGIN;
+CREATE TABLE IF NOT EXISTS invites(
+  id INTEGER PRIMARY KEY,
+  code TEXT NOT NULL UNIQUE,
+  group_id INTEGER NOT NULL,
+  username TEXT NOT NULL,
+  user_days INTEGER NOT NULL,
+  device_days INTEGER,
+  created_by TEXT NOT NULL,
+  issued INTEGER NOT NULL,
+  expiration INTEGER NOT NULL,
+  redeemed INTEGER,
+  redeemed_email TEXT,
+  key_id INTEGER
+);
+PRAGMA user_version = 15;
+COMMIT;
\ No newline at end of file
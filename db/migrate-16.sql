@@ -0,0 +1,6 @@
+BEGIN;
+-- A person can sign in with more than one email, each on its own row, but not with the same
+-- one twice.
+CREATE UNIQUE INDEX IF NOT EXISTS web_users_by_username_email ON web_users(username, email);
+PRAGMA user_version = 16;
+COMMIT;
\ No newline at end of file
@@ -0,0 +1,16 @@
+BEGIN;
+-- Every stretch a device's sharing was paused for a viewer, so what it recorded then stays
+-- hidden from them after the pause is over.
+CREATE TABLE IF NOT EXISTS privacy_pauses(
+  key_id INTEGER NOT NULL,
+  viewer TEXT NOT NULL,
+  start INTEGER NOT NULL,
+  until INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS privacy_pauses_by_key ON privacy_pauses(key_id);
+-- Pauses that are on right now. When they started wasn't kept, so they count from here.
+INSERT INTO privacy_pauses(key_id, viewer, start, until)
+  SELECT key_id, viewer, CAST(strftime('%s', 'now') AS INTEGER), paused_until FROM privacy_policies
+  WHERE paused_until > CAST(strftime('%s', 'now') AS INTEGER);
+PRAGMA user_version = 17;
+COMMIT;
\ No newline at end of file
@@ -0,0 +1,5 @@
+BEGIN;
+-- Repeated failures from one IP are counted on a single entry, rather than each getting one.
+ALTER TABLE audit_log ADD COLUMN count INTEGER NOT NULL DEFAULT 1;
+PRAGMA user_version = 18;
+COMMIT;
\ No newline at end of file
@@ -0,0 +1,7 @@
+BEGIN;
+-- An earlier migrate-16 made usernames unique on their own, which a person with more than one
+-- email can't have.
+DROP INDEX IF EXISTS web_users_by_username;
+CREATE UNIQUE INDEX IF NOT EXISTS web_users_by_username_email ON web_users(username, email);
+PRAGMA user_version = 19;
+COMMIT;
\ No newline at end of file
@@ -0,0 +1,12 @@
+BEGIN;
+CREATE TABLE locations(
+  id INTEGER PRIMARY KEY,
+  key_id INTEGER NOT NULL,
+  latitude REAL NOT NULL,
+  longitude REAL NOT NULL,
+  accuracy REAL NOT NULL,
+  time INTEGER NOT NULL
+);
+CREATE INDEX locations_by_key_time ON locations(key_id, time);
+PRAGMA user_version = 2;
+COMMIT;
\ No newline at end of file
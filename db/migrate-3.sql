@@ -0,0 +1,4 @@
+BEGIN;
+ALTER TABLE locations ADD COLUMN rejected TEXT;
+PRAGMA user_version = 3;
+COMMIT;
\ No newline at end of file
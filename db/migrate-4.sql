@@ -0,0 +1,13 @@
+BEGIN;
+CREATE TABLE share_links(
+  id INTEGER PRIMARY KEY,
+  key_id INTEGER NOT NULL,
+  created_by TEXT NOT NULL,
+  issued INTEGER NOT NULL,
+  expiration INTEGER NOT NULL,
+  precision_meters REAL,
+  trail_minutes INTEGER,
+  revoked INTEGER NOT NULL DEFAULT 0
+);
+PRAGMA user_version = 4;
+COMMIT;
\ No newline at end of file
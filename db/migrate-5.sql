@@ -0,0 +1,11 @@
+BEGIN;
+CREATE TABLE privacy_policies(
+  key_id INTEGER NOT NULL,
+  viewer TEXT NOT NULL,
+  mode TEXT NOT NULL,
+  grid_meters REAL,
+  paused_until INTEGER,
+  PRIMARY KEY(key_id, viewer)
+);
+PRAGMA user_version = 5;
+COMMIT;
\ No newline at end of file
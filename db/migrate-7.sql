@@ -0,0 +1,10 @@
+BEGIN;
+-- Follows migrate-5; there is no migrate-6.
+CREATE TABLE sessions(
+  token TEXT PRIMARY KEY,
+  username TEXT NOT NULL,
+  issued INTEGER NOT NULL,
+  last_used INTEGER NOT NULL
+);
+PRAGMA user_version = 7;
+COMMIT;
\ No newline at end of file
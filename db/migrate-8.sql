@@ -0,0 +1,4 @@
+BEGIN;
+ALTER TABLE web_users ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
+PRAGMA user_version = 8;
+COMMIT;
\ No newline at end of file
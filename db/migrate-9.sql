@@ -0,0 +1,13 @@
+BEGIN;
+CREATE TABLE IF NOT EXISTS audit_log(
+  id INTEGER PRIMARY KEY,
+  time INTEGER NOT NULL,
+  actor TEXT NOT NULL,
+  ip TEXT,
+  user_agent TEXT,
+  event TEXT NOT NULL,
+  detail TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_log_by_time ON audit_log(time);
+PRAGMA user_version = 9;
+COMMIT;
\ No newline at end of file
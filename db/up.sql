@@ -3,7 +3,17 @@ CREATE TABLE IF NOT EXISTS api_keys(
   username TEXT NOT NULL,
   key_base64 TEXT NOT NULL,
   issued INTEGER NOT NULL,
-  expiration INTEGER NOT NULL
+  expiration INTEGER NOT NULL,
+  previous_key_base64 TEXT,
+  previous_expiration INTEGER,
+  scopes TEXT NOT NULL DEFAULT 'location:write',
+  person_id INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS people(
+  id INTEGER PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE,
+  primary_key_id INTEGER
 );
 
 CREATE TABLE IF NOT EXISTS web_users(
@@ -11,5 +21,114 @@ CREATE TABLE IF NOT EXISTS web_users(
   username TEXT NOT NULL,
   email TEXT NOT NULL,
   issued INTEGER NOT NULL,
-  expiration INTEGER NOT NULL
+  expiration INTEGER NOT NULL,
+  role TEXT NOT NULL DEFAULT 'user'
+);
+
+CREATE TABLE IF NOT EXISTS locations(
+  id INTEGER PRIMARY KEY,
+  key_id INTEGER NOT NULL,
+  latitude REAL NOT NULL,
+  longitude REAL NOT NULL,
+  accuracy REAL NOT NULL,
+  time INTEGER NOT NULL,
+  rejected TEXT
+);
+
+CREATE INDEX IF NOT EXISTS locations_by_key_time ON locations(key_id, time);
+
+CREATE TABLE IF NOT EXISTS share_links(
+  id INTEGER PRIMARY KEY,
+  key_id INTEGER NOT NULL,
+  created_by TEXT NOT NULL,
+  issued INTEGER NOT NULL,
+  expiration INTEGER NOT NULL,
+  precision_meters REAL,
+  trail_minutes INTEGER,
+  revoked INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS privacy_policies(
+  key_id INTEGER NOT NULL,
+  viewer TEXT NOT NULL,
+  mode TEXT NOT NULL,
+  grid_meters REAL,
+  paused_until INTEGER,
+  PRIMARY KEY(key_id, viewer)
+);
+
+CREATE TABLE IF NOT EXISTS sessions(
+  token TEXT PRIMARY KEY,
+  username TEXT NOT NULL,
+  issued INTEGER NOT NULL,
+  last_used INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS audit_log(
+  id INTEGER PRIMARY KEY,
+  time INTEGER NOT NULL,
+  actor TEXT NOT NULL,
+  ip TEXT,
+  user_agent TEXT,
+  event TEXT NOT NULL,
+  detail TEXT NOT NULL,
+  count INTEGER NOT NULL DEFAULT 1
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_by_time ON audit_log(time);
+
+CREATE TABLE IF NOT EXISTS key_ips(
+  key_id INTEGER NOT NULL,
+  ip TEXT NOT NULL,
+  first_seen INTEGER NOT NULL,
+  PRIMARY KEY(key_id, ip)
+);
+
+CREATE TABLE IF NOT EXISTS groups(
+  id INTEGER PRIMARY KEY,
+  name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS group_members(
+  group_id INTEGER NOT NULL,
+  username TEXT NOT NULL,
+  PRIMARY KEY(group_id, username)
+);
+
+CREATE TABLE IF NOT EXISTS group_devices(
+  group_id INTEGER NOT NULL,
+  key_id INTEGER NOT NULL,
+  PRIMARY KEY(group_id, key_id)
+);
+CREATE INDEX IF NOT EXISTS group_devices_by_key ON group_devices(key_id);
+
+CREATE TABLE IF NOT EXISTS invites(
+  id INTEGER PRIMARY KEY,
+  code TEXT NOT NULL UNIQUE,
+  group_id INTEGER NOT NULL,
+  username TEXT NOT NULL,
+  user_days INTEGER NOT NULL,
+  device_days INTEGER,
+  created_by TEXT NOT NULL,
+  issued INTEGER NOT NULL,
+  expiration INTEGER NOT NULL,
+  redeemed INTEGER,
+  redeemed_email TEXT,
+  key_id INTEGER
 );
+
+-- A person can sign in with more than one email, each on its own row, but not with the same
+-- one twice.
+CREATE UNIQUE INDEX IF NOT EXISTS web_users_by_username_email ON web_users(username, email);
+
+-- Every stretch a device's sharing was paused for a viewer, so what it recorded then stays
+-- hidden from them after the pause is over.
+CREATE TABLE IF NOT EXISTS privacy_pauses(
+  key_id INTEGER NOT NULL,
+  viewer TEXT NOT NULL,
+  start INTEGER NOT NULL,
+  until INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS privacy_pauses_by_key ON privacy_pauses(key_id);
+
+PRAGMA user_version = 19;
@@ -19,11 +19,26 @@ read KEY_USERNAME
 # to keep this from breaking if the username has a quote in it.
 ESCAPED_KEY_USERNAME="$(echo "$KEY_USERNAME" | sed "s/'/''/g")"
 
+# Prompt for what the key can do. Scanners only need to post locations.
+echo 'What should this key be allowed to do? Space-separated, any of:'
+echo '  location:write location:read:self location:read:shared admin'
+echo '[location:write]'
+read KEY_SCOPES
+if [[ -z "$KEY_SCOPES" ]]; then
+  KEY_SCOPES='location:write'
+fi
+for SCOPE in $KEY_SCOPES; do
+  case "$SCOPE" in
+    location:write|location:read:self|location:read:shared|admin) ;;
+    *) echo "Error: $SCOPE isn't a scope."; exit 1 ;;
+  esac
+done
+
 # Generate a 1024-bit random number and base64 it.
 APIKEY="$(dd if=/dev/urandom bs=4 count=16 status=none | base64 -w0)"
 GENERATED=$(date +%s)
 EXPIRY=$((GENERATED + KEY_LIFETIME * 24 * 60 * 60))
 
 # Execute the insertion.
-sqlite3 "$DB_PATH" "BEGIN;INSERT INTO api_keys(username, key_base64, issued, expiration) VALUES ('$ESCAPED_KEY_USERNAME', '$APIKEY', $GENERATED, $EXPIRY);COMMIT;"
+sqlite3 "$DB_PATH" "BEGIN;INSERT OR IGNORE INTO people(name) VALUES ('$ESCAPED_KEY_USERNAME');INSERT INTO api_keys(username, key_base64, issued, expiration, scopes, person_id) VALUES ('$ESCAPED_KEY_USERNAME', '$APIKEY', $GENERATED, $EXPIRY, '$KEY_SCOPES', (SELECT id FROM people WHERE name IS '$ESCAPED_KEY_USERNAME'));COMMIT;"
 echo "Your key is: '$APIKEY'"
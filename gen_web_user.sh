@@ -28,8 +28,17 @@ if [[ ! "${USER_EMAIL^^}" =~ ^[A-Z0-9._%+-]+@[A-Z0-9.-]+\.[A-Z]{2,63}$ ]]; then
   echo "Error: not an email."
 fi
 
+# Admins can manage other users and reload the config from the web.
+echo 'Should this user be an admin? [y/N]'
+read IS_ADMIN
+if [[ "${IS_ADMIN,,}" == y* ]]; then
+  ROLE=admin
+else
+  ROLE=user
+fi
+
 GENERATED=$(date +%s)
 EXPIRY=$((GENERATED + KEY_LIFETIME * 24 * 60 * 60))
 
 # Execute the insertion.
-sqlite3 "$DB_PATH" "BEGIN;INSERT INTO web_users(username, email, issued, expiration) VALUES ('$ESCAPED_USERNAME', '$USER_EMAIL', $GENERATED, $EXPIRY);COMMIT;"
+sqlite3 "$DB_PATH" "BEGIN;INSERT INTO web_users(username, email, issued, expiration, role) VALUES ('$ESCAPED_USERNAME', '$USER_EMAIL', $GENERATED, $EXPIRY, '$ROLE');COMMIT;"
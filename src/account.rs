@@ -0,0 +1,315 @@
+use std::{
+    io::{Cursor, Write},
+    path::Path,
+};
+
+use actix_web::{
+    get,
+    http::header::{ContentDisposition, ContentType},
+    post, web, HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::{
+    audit::{self, AuditEntry},
+    auth::revoke_sessions,
+    db::{self, Pool},
+    invite::Invite,
+    location::{authenticate_session, Location},
+    misc::{bad_request, forbidden, internal_error},
+    privacy::PrivacyPolicy,
+    share::ShareLink,
+    AppState,
+};
+
+/// An api_keys row, minus the key itself.
+#[derive(Serialize)]
+pub(crate) struct ApiKeyInfo {
+    pub(crate) id: u64,
+    pub(crate) username: String,
+    /// Seconds since the unix epoch.
+    pub(crate) issued: u64,
+    /// Seconds since the unix epoch.
+    pub(crate) expiration: u64,
+    /// What the key's allowed to do, like "location:write".
+    pub(crate) scopes: Vec<String>,
+}
+
+/// A web_users row.
+#[derive(Serialize)]
+pub(crate) struct WebUserInfo {
+    pub(crate) id: u64,
+    pub(crate) username: String,
+    pub(crate) email: String,
+    /// "admin" or "user".
+    pub(crate) role: String,
+    /// Seconds since the unix epoch.
+    pub(crate) issued: u64,
+    /// Seconds since the unix epoch.
+    pub(crate) expiration: u64,
+}
+
+/// A locations row: a raw fix, which api key sent it, and whether the filter threw it out.
+pub(crate) struct StoredLocation {
+    pub(crate) key_id: u64,
+    pub(crate) location: Location,
+    pub(crate) rejected: Option<String>,
+}
+
+/// A privacy_pauses row: a stretch of time a device was hidden from a viewer.
+#[derive(Serialize)]
+pub(crate) struct PrivacyPause {
+    pub(crate) key_id: u64,
+    pub(crate) viewer: String,
+    /// Seconds since the unix epoch.
+    pub(crate) start: u64,
+    /// Seconds since the unix epoch.
+    pub(crate) until: u64,
+}
+
+/// A key_ips row: an address an api key has been used from.
+#[derive(Serialize)]
+pub(crate) struct KeyIp {
+    pub(crate) key_id: u64,
+    pub(crate) ip: String,
+    /// Seconds since the unix epoch.
+    pub(crate) first_seen: u64,
+}
+
+/// A group that a person, or one of their devices, is in.
+#[derive(Serialize)]
+pub(crate) struct GroupMembership {
+    pub(crate) group_id: u64,
+    pub(crate) name: String,
+    /// The api key id of their device that's in it, or None if it's them.
+    pub(crate) key_id: Option<u64>,
+}
+
+/// Everything stored about one person.
+pub(crate) struct UserExport {
+    pub(crate) username: String,
+    pub(crate) api_keys: Vec<ApiKeyInfo>,
+    pub(crate) web_users: Vec<WebUserInfo>,
+    pub(crate) locations: Vec<StoredLocation>,
+    pub(crate) share_links: Vec<ShareLink>,
+    pub(crate) privacy_policies: Vec<PrivacyPolicy>,
+    pub(crate) privacy_pauses: Vec<PrivacyPause>,
+    pub(crate) groups: Vec<GroupMembership>,
+    pub(crate) key_ips: Vec<KeyIp>,
+    /// The invites they signed up with.
+    pub(crate) invites: Vec<Invite>,
+    /// What they did, as the audit log has it.
+    pub(crate) audit_log: Vec<AuditEntry>,
+}
+
+/// What went when a person was deleted.
+#[derive(Serialize)]
+pub(crate) struct DeletedUser {
+    #[serde(skip)]
+    pub(crate) key_ids: Vec<u64>,
+    pub(crate) api_keys: usize,
+    pub(crate) web_users: usize,
+    pub(crate) locations: usize,
+    pub(crate) share_links: usize,
+    pub(crate) privacy_policies: usize,
+}
+
+/// A live session, as far as an export is concerned. The key itself stays out of it.
+#[derive(Serialize)]
+struct SessionInfo {
+    /// Seconds.
+    issued_ago: u64,
+    /// Seconds.
+    last_used_ago: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AccountDeleteIn {
+    /// Must be the session's own username, so nobody deletes themselves by accident.
+    confirm: String,
+}
+
+/// Packs an export up into a zip archive: a JSON file for each kind of thing, and the
+/// location history as CSV, since it's the big one.
+fn write_zip(export: &UserExport, sessions: &[SessionInfo]) -> zip::result::ZipResult<Vec<u8>> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let options = FileOptions::default();
+
+    let json_files: [(&str, serde_json::Result<Vec<u8>>); 10] = [
+        ("api_keys.json", serde_json::to_vec_pretty(&export.api_keys)),
+        (
+            "web_users.json",
+            serde_json::to_vec_pretty(&export.web_users),
+        ),
+        (
+            "share_links.json",
+            serde_json::to_vec_pretty(&export.share_links),
+        ),
+        (
+            "privacy_policies.json",
+            serde_json::to_vec_pretty(&export.privacy_policies),
+        ),
+        (
+            "privacy_pauses.json",
+            serde_json::to_vec_pretty(&export.privacy_pauses),
+        ),
+        ("groups.json", serde_json::to_vec_pretty(&export.groups)),
+        ("key_ips.json", serde_json::to_vec_pretty(&export.key_ips)),
+        ("invites.json", serde_json::to_vec_pretty(&export.invites)),
+        (
+            "audit_log.json",
+            serde_json::to_vec_pretty(&export.audit_log),
+        ),
+        ("sessions.json", serde_json::to_vec_pretty(sessions)),
+    ];
+    for (name, contents) in json_files {
+        zip.start_file(name, options)?;
+        zip.write_all(&contents.map_err(std::io::Error::from)?)?;
+    }
+
+    zip.start_file("locations.csv", options)?;
+    writeln!(zip, "key_id,time,latitude,longitude,accuracy,rejected")?;
+    for row in &export.locations {
+        writeln!(
+            zip,
+            "{},{},{},{},{},{}",
+            row.key_id,
+            row.location.time,
+            row.location.latitude,
+            row.location.longitude,
+            row.location.accuracy,
+            row.rejected.as_deref().unwrap_or("")
+        )?;
+    }
+
+    Ok(zip.finish()?.into_inner())
+}
+
+/// Drops everything we're holding in memory about a person who's just been deleted, including
+/// any sessions they have open.
+fn forget_user(data: &AppState, username: &str, key_ids: &[u64]) {
+    for key_id in key_ids {
+        data.last_location.remove(key_id);
+        data.tracks.remove(key_id);
+    }
+    data.names.lock().retain(|(id, _)| !key_ids.contains(id));
+    data.key_ips.retain(|(id, _)| !key_ids.contains(id));
+    revoke_sessions(data, username);
+}
+
+#[get("/api/account/export")]
+pub(crate) async fn get_account_export(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+
+    let export = match db::export_user(&data.pool, session.name.clone()).await {
+        Ok(e) => e,
+        Err(e) => {
+            log::error!("/api/account/export: failed to read user data: {}", e);
+            return internal_error();
+        }
+    };
+    let sessions: Vec<SessionInfo> = data
+        .session_tokens
+        .iter()
+        .filter(|s| s.value().name == session.name)
+        .map(|s| SessionInfo {
+            issued_ago: s.value().issued.elapsed().as_secs(),
+            last_used_ago: s.value().last_used.elapsed().as_secs(),
+        })
+        .collect();
+
+    match write_zip(&export, &sessions) {
+        Ok(archive) => HttpResponse::Ok()
+            .content_type("application/zip")
+            .insert_header(ContentDisposition::attachment("locationapp-export.zip"))
+            .body(archive),
+        Err(e) => {
+            log::error!("/api/account/export: failed to build archive: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/account/delete")]
+pub(crate) async fn post_account_delete(
+    info: web::Json<AccountDeleteIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req.clone(), &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+    if info.confirm != session.name {
+        return bad_request("confirm must be your username.");
+    }
+
+    let deleted = match db::delete_user(&data.pool, session.name.clone()).await {
+        Ok(d) => d,
+        Err(e) => {
+            log::error!("/api/account/delete: failed to delete user data: {}", e);
+            return internal_error();
+        }
+    };
+    forget_user(&data, &session.name, &deleted.key_ids);
+    log::info!("Deleted user {} at their own request.", session.name);
+    let detail = format!(
+        "{} api keys, {} web_users, {} locations",
+        deleted.api_keys, deleted.web_users, deleted.locations
+    );
+    audit::record(&data, &req, &session.name, "account.delete", detail).await;
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body(serde_json::to_string(&deleted).unwrap())
+}
+
+/// The export-user command: writes everything stored about a person to a zip archive.
+pub(crate) async fn cli_export_user(
+    pool: &Pool,
+    username: String,
+    output: &Path,
+) -> std::io::Result<()> {
+    let export = db::export_user(pool, username)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    // Sessions only live in the running server's memory, so there are none to export here.
+    let archive = write_zip(&export, &[]).map_err(std::io::Error::from)?;
+    std::fs::write(output, archive)?;
+    println!(
+        "Exported {} api keys, {} web users and {} locations for {} to {}.",
+        export.api_keys.len(),
+        export.web_users.len(),
+        export.locations.len(),
+        export.username,
+        output.display()
+    );
+    Ok(())
+}
+
+/// The delete-user command: deletes everything stored about a person.
+pub(crate) async fn cli_delete_user(pool: &Pool, username: String) -> std::io::Result<()> {
+    let deleted = db::delete_user(pool, username.clone())
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    println!(
+        "Deleted {} api keys, {} web users, {} locations, {} share links and {} privacy policies for {}.",
+        deleted.api_keys,
+        deleted.web_users,
+        deleted.locations,
+        deleted.share_links,
+        deleted.privacy_policies,
+        username
+    );
+    println!("If the server is running, restart it to drop their sessions and last locations.");
+    Ok(())
+}
@@ -0,0 +1,638 @@
+use actix_web::{get, http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit,
+    auth::revoke_sessions,
+    db::{self, Managed},
+    keys::{authenticate_key, check_scopes, generate_key, SCOPE_ADMIN, SCOPE_WRITE},
+    location::authenticate_session,
+    misc::{bad_request, forbidden, internal_error, no_store, unixtime_now, SECS_PER_DAY},
+    privacy::EVERYONE,
+    reload, AppState,
+};
+
+/// The longest anything can be issued or extended for at once, in days.
+const MAX_DAYS: u64 = 3650;
+
+/// The longest a rotated-out key can keep working for, in hours.
+const MAX_GRACE_HOURS: u64 = 24 * 30;
+
+#[derive(Deserialize)]
+pub(crate) struct KeyCreateIn {
+    /// Whose device the key is for.
+    username: String,
+    /// How long the key should work for.
+    days: u64,
+    /// What the key's allowed to do. Defaults to just posting locations.
+    scopes: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct KeyCreateOut {
+    id: u64,
+    /// The key to put in the device. Only admins get to see it again, from /api/key/provision.
+    key: String,
+    expiration: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UserCreateIn {
+    username: String,
+    /// The Google account they'll sign in with.
+    email: String,
+    /// How long they should have access for.
+    days: u64,
+    /// "admin" or "user". Defaults to "user".
+    role: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UserCreateOut {
+    id: u64,
+    expiration: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ExtendIn {
+    id: u64,
+    /// How much longer it should work for, counting from its current expiration, or from now
+    /// if it's already expired.
+    days: u64,
+}
+
+#[derive(Serialize)]
+struct ExtendOut {
+    id: u64,
+    expiration: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RotateIn {
+    id: u64,
+    /// How long the old key keeps working for, in hours. Defaults to key_rotation.grace_hours
+    /// from the config.
+    grace_hours: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct RotateOut {
+    id: u64,
+    /// The new key. Only admins get to see it again, from /api/key/provision, though the
+    /// device can fetch it with the old key until that runs out.
+    key: String,
+    expiration: u64,
+    /// When the old key stops working.
+    previous_expiration: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ScopesIn {
+    id: u64,
+    /// What the key's allowed to do from now on.
+    scopes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RevokeIn {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RenameIn {
+    id: u64,
+    username: String,
+}
+
+/// Confirms that a request comes from an admin: a signed-in one, or an api key with the
+/// admin scope. Returns their name if so, and the response to send back if not.
+pub(crate) async fn require_admin(
+    data: &AppState,
+    req: &HttpRequest,
+) -> Result<String, HttpResponse> {
+    if let Some(key) = authenticate_key(data, req).await? {
+        key.require(data, req, SCOPE_ADMIN).await?;
+        return Ok(key.username);
+    }
+
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req.clone(), data) {
+        Some(s) => s,
+        None => return Err(forbidden()),
+    };
+    match db::is_admin(&data.pool, session.name.clone()).await {
+        Ok(true) => Ok(session.name),
+        Ok(false) => {
+            log::debug!("{} isn't an admin.", session.name);
+            let detail = format!("tried {}", req.path());
+            audit::record(data, req, &session.name, "auth.fail.not_admin", detail).await;
+            Err(forbidden())
+        }
+        Err(e) => {
+            log::error!(
+                "Failed to look up whether {} is an admin: {}",
+                session.name,
+                e
+            );
+            Err(internal_error())
+        }
+    }
+}
+
+#[post("/api/admin/reload")]
+pub(crate) async fn post_admin_reload(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+
+    log::info!("{} asked for a config reload.", admin);
+    match reload::reload(&data).await {
+        Ok(reloaded) => {
+            let detail = format!("changed: {}", reloaded.applied.join(", "));
+            audit::record(&data, &req, &admin, "config.reload", detail).await;
+            HttpResponse::Ok()
+                .insert_header(ContentType::json())
+                .body(serde_json::to_string(&reloaded).unwrap())
+        }
+        Err(e) => {
+            log::error!("/api/admin/reload: keeping the old config: {}", e);
+            bad_request(&e)
+        }
+    }
+}
+
+/// Checks a number of days that something's to be issued or extended for.
+pub(crate) fn check_days(days: u64) -> Result<u64, HttpResponse> {
+    if days == 0 || days > MAX_DAYS {
+        return Err(bad_request("days must be between 1 and 3650."));
+    }
+    Ok(days * SECS_PER_DAY)
+}
+
+/// Checks a username that's about to be stored.
+pub(crate) fn check_username(username: &str) -> Result<(), HttpResponse> {
+    if username.trim().is_empty() {
+        return Err(bad_request("username can't be empty."));
+    }
+    // Privacy policies use it to mean everyone.
+    if username == EVERYONE {
+        return Err(bad_request("username can't be \"*\"."));
+    }
+    Ok(())
+}
+
+/// What to call a managed row in the audit log.
+fn noun(managed: Managed) -> &'static str {
+    match managed {
+        Managed::ApiKeys => "api_key",
+        Managed::WebUsers => "web_user",
+    }
+}
+
+/// Signs a web_user out everywhere, and notes it in the audit log if they were signed in.
+async fn end_sessions(data: &AppState, req: &HttpRequest, admin: &str, username: &str) {
+    let revoked = revoke_sessions(data, username);
+    if revoked > 0 {
+        let detail = format!("{} sessions for {}", revoked, username);
+        audit::record(data, req, admin, "session.revoke", detail).await;
+    }
+}
+
+#[get("/api/admin/keys")]
+pub(crate) async fn get_admin_keys(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(response) = require_admin(&data, &req).await {
+        return response;
+    }
+
+    match db::list_api_keys(&data.pool).await {
+        Ok(keys) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&keys).unwrap()),
+        Err(e) => {
+            log::error!("/api/admin/keys: failed to list api keys: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/admin/keys/create")]
+pub(crate) async fn post_admin_keys_create(
+    info: web::Json<KeyCreateIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let lifetime = match check_days(info.days) {
+        Ok(secs) => secs,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_username(&info.username) {
+        return response;
+    }
+
+    let scopes = info
+        .scopes
+        .clone()
+        .unwrap_or_else(|| vec![SCOPE_WRITE.to_string()]);
+    let scopes = match check_scopes(&scopes) {
+        Ok(scopes) => scopes,
+        Err(why) => return bad_request(&why),
+    };
+
+    let key = generate_key();
+    let expiration = unixtime_now() + lifetime;
+    let id = match db::insert_api_key(
+        &data.pool,
+        info.username.clone(),
+        key.clone(),
+        scopes.clone(),
+        expiration,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => {
+            log::error!("/api/admin/keys/create: failed to store api key: {}", e);
+            return internal_error();
+        }
+    };
+    let detail = format!(
+        "id {} for {}, {} days, scopes {}",
+        id, info.username, info.days, scopes
+    );
+    audit::record(&data, &req, &admin, "api_key.create", detail).await;
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .insert_header(no_store())
+        .body(
+            serde_json::to_string(&KeyCreateOut {
+                id,
+                key,
+                expiration,
+            })
+            .unwrap(),
+        )
+}
+
+#[post("/api/admin/keys/extend")]
+pub(crate) async fn post_admin_keys_extend(
+    info: web::Json<ExtendIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    extend(Managed::ApiKeys, info.into_inner(), &data, &req).await
+}
+
+#[post("/api/admin/keys/rotate")]
+pub(crate) async fn post_admin_keys_rotate(
+    info: web::Json<RotateIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let grace_hours = info
+        .grace_hours
+        .unwrap_or(data.config().key_rotation.grace_hours);
+    if grace_hours > MAX_GRACE_HOURS {
+        return bad_request("grace_hours can be at most 720.");
+    }
+
+    // The id stays the same, so the device keeps its history, name and place in the list.
+    let key = generate_key();
+    let grace_until = unixtime_now() + grace_hours * 60 * 60;
+    let (username, expiration) =
+        match db::rotate_api_key(&data.pool, info.id, key.clone(), grace_until).await {
+            Ok(Some(rotated)) => rotated,
+            Ok(None) => return bad_request("No such id."),
+            Err(e) => {
+                log::error!("/api/admin/keys/rotate: failed to rotate api key: {}", e);
+                return internal_error();
+            }
+        };
+    let previous_expiration = grace_until.min(expiration);
+    let detail = format!(
+        "id {} for {}, old key works until {}",
+        info.id, username, previous_expiration
+    );
+    audit::record(&data, &req, &admin, "api_key.rotate", detail).await;
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .insert_header(no_store())
+        .body(
+            serde_json::to_string(&RotateOut {
+                id: info.id,
+                key,
+                expiration,
+                previous_expiration,
+            })
+            .unwrap(),
+        )
+}
+
+#[post("/api/admin/keys/scopes")]
+pub(crate) async fn post_admin_keys_scopes(
+    info: web::Json<ScopesIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let scopes = match check_scopes(&info.scopes) {
+        Ok(scopes) => scopes,
+        Err(why) => return bad_request(&why),
+    };
+
+    let username = match db::set_key_scopes(&data.pool, info.id, scopes.clone()).await {
+        Ok(Some(username)) => username,
+        Ok(None) => return bad_request("No such id."),
+        Err(e) => {
+            log::error!("/api/admin/keys/scopes: failed to set scopes: {}", e);
+            return internal_error();
+        }
+    };
+    let detail = format!("id {} for {}, now {}", info.id, username, scopes);
+    audit::record(&data, &req, &admin, "api_key.scopes", detail).await;
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body("{}")
+}
+
+#[post("/api/admin/keys/revoke")]
+pub(crate) async fn post_admin_keys_revoke(
+    info: web::Json<RevokeIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let response = revoke(Managed::ApiKeys, info.id, &data, &req).await;
+    // Stop showing the device, since it can't report any more.
+    if response.status().is_success() {
+        data.last_location.remove(&info.id);
+        data.tracks.remove(&info.id);
+        data.names.lock().retain(|(id, _)| *id != info.id);
+    }
+    response
+}
+
+#[post("/api/admin/keys/rename")]
+pub(crate) async fn post_admin_keys_rename(
+    info: web::Json<RenameIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let response = rename(Managed::ApiKeys, &info, &data, &req).await;
+    if response.status().is_success() {
+        for (id, name) in data.names.lock().iter_mut() {
+            if *id == info.id {
+                name.clone_from(&info.username);
+            }
+        }
+    }
+    response
+}
+
+#[get("/api/admin/users")]
+pub(crate) async fn get_admin_users(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    if let Err(response) = require_admin(&data, &req).await {
+        return response;
+    }
+
+    match db::list_web_users(&data.pool).await {
+        Ok(users) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&users).unwrap()),
+        Err(e) => {
+            log::error!("/api/admin/users: failed to list web users: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/admin/users/create")]
+pub(crate) async fn post_admin_users_create(
+    info: web::Json<UserCreateIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let lifetime = match check_days(info.days) {
+        Ok(secs) => secs,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_username(&info.username) {
+        return response;
+    }
+    // Their devices can already have the name, but another web_user can't.
+    match db::web_user_exists(&data.pool, info.username.clone()).await {
+        Ok(false) => {}
+        Ok(true) => return bad_request("There's already a web user by that name."),
+        Err(e) => {
+            log::error!("/api/admin/users/create: failed to check username: {}", e);
+            return internal_error();
+        }
+    }
+    // Google does the real checking when they sign in. This just catches obvious typos.
+    if !info.email.contains('@') {
+        return bad_request("email must be an email address.");
+    }
+    // Signing in goes by email, so it can only lead to one web_user.
+    match db::email_in_use(&data.pool, info.email.clone()).await {
+        Ok(false) => {}
+        Ok(true) => return bad_request("There's already a web user with that email."),
+        Err(e) => {
+            log::error!("/api/admin/users/create: failed to check email: {}", e);
+            return internal_error();
+        }
+    }
+    let role = info.role.clone().unwrap_or_else(|| "user".to_string());
+    if role != "user" && role != "admin" {
+        return bad_request("role must be \"user\" or \"admin\".");
+    }
+
+    let expiration = unixtime_now() + lifetime;
+    let id = match db::insert_web_user(
+        &data.pool,
+        info.username.clone(),
+        info.email.clone(),
+        role.clone(),
+        expiration,
+    )
+    .await
+    {
+        Ok(id) => id,
+        Err(e) => {
+            log::error!("/api/admin/users/create: failed to store web user: {}", e);
+            return internal_error();
+        }
+    };
+    let detail = format!(
+        "id {} for {} <{}> as {}, {} days",
+        id, info.username, info.email, role, info.days
+    );
+    audit::record(&data, &req, &admin, "web_user.create", detail).await;
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body(serde_json::to_string(&UserCreateOut { id, expiration }).unwrap())
+}
+
+#[post("/api/admin/users/extend")]
+pub(crate) async fn post_admin_users_extend(
+    info: web::Json<ExtendIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    extend(Managed::WebUsers, info.into_inner(), &data, &req).await
+}
+
+#[post("/api/admin/users/revoke")]
+pub(crate) async fn post_admin_users_revoke(
+    info: web::Json<RevokeIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    revoke(Managed::WebUsers, info.id, &data, &req).await
+}
+
+#[post("/api/admin/users/rename")]
+pub(crate) async fn post_admin_users_rename(
+    info: web::Json<RenameIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    rename(Managed::WebUsers, &info, &data, &req).await
+}
+
+/// Extends an api key or web_user, for the endpoints above.
+async fn extend(
+    managed: Managed,
+    info: ExtendIn,
+    data: &AppState,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let admin = match require_admin(data, req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let secs = match check_days(info.days) {
+        Ok(secs) => secs,
+        Err(response) => return response,
+    };
+
+    let (username, expiration) =
+        match db::extend_expiration(&data.pool, managed, info.id, secs).await {
+            Ok(Some(extended)) => extended,
+            Ok(None) => return bad_request("No such id."),
+            Err(e) => {
+                log::error!("/api/admin: failed to extend {}: {}", noun(managed), e);
+                return internal_error();
+            }
+        };
+    let detail = format!(
+        "id {} for {}, {} more days, until {}",
+        info.id, username, info.days, expiration
+    );
+    let event = format!("{}.extend", noun(managed));
+    audit::record(data, req, &admin, &event, detail).await;
+
+    HttpResponse::Ok().insert_header(ContentType::json()).body(
+        serde_json::to_string(&ExtendOut {
+            id: info.id,
+            expiration,
+        })
+        .unwrap(),
+    )
+}
+
+/// Revokes an api key or web_user, for the endpoints above. Revoking a web_user signs them out
+/// everywhere too.
+async fn revoke(managed: Managed, id: u64, data: &AppState, req: &HttpRequest) -> HttpResponse {
+    let admin = match require_admin(data, req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+
+    let username = match db::revoke(&data.pool, managed, id).await {
+        Ok(Some(username)) => username,
+        Ok(None) => return bad_request("No such id."),
+        Err(e) => {
+            log::error!("/api/admin: failed to revoke {}: {}", noun(managed), e);
+            return internal_error();
+        }
+    };
+    let detail = format!("id {} for {}", id, username);
+    let event = format!("{}.revoke", noun(managed));
+    audit::record(data, req, &admin, &event, detail).await;
+    if let Managed::WebUsers = managed {
+        end_sessions(data, req, &admin, &username).await;
+    }
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body("{}")
+}
+
+/// Renames an api key or web_user, for the endpoints above. Renaming a web_user signs them out
+/// everywhere, since their sessions carry the old name. A device can be renamed to someone who
+/// already has one, which hands it over to them, but a web_user can't take a name that's in use.
+async fn rename(
+    managed: Managed,
+    info: &RenameIn,
+    data: &AppState,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let admin = match require_admin(data, req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_username(&info.username) {
+        return response;
+    }
+    if let Managed::WebUsers = managed {
+        // Otherwise they'd be signing in as whoever has that name, devices and all.
+        match db::username_in_use(&data.pool, info.username.clone()).await {
+            Ok(false) => {}
+            Ok(true) => return bad_request("That username is already taken."),
+            Err(e) => {
+                log::error!("/api/admin: failed to check username: {}", e);
+                return internal_error();
+            }
+        }
+    }
+
+    let old = match db::rename(&data.pool, managed, info.id, info.username.clone()).await {
+        Ok(Some(old)) => old,
+        Ok(None) => return bad_request("No such id."),
+        Err(e) => {
+            log::error!("/api/admin: failed to rename {}: {}", noun(managed), e);
+            return internal_error();
+        }
+    };
+    let detail = format!("id {} from {} to {}", info.id, old, info.username);
+    let event = format!("{}.rename", noun(managed));
+    audit::record(data, req, &admin, &event, detail).await;
+    if let Managed::WebUsers = managed {
+        end_sessions(data, req, &admin, &old).await;
+    }
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body("{}")
+}
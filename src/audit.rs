@@ -0,0 +1,188 @@
+use actix_web::{
+    get,
+    http::header::{ContentType, USER_AGENT},
+    rt, web, HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::require_admin,
+    db,
+    listen::client_ip,
+    misc::{internal_error, unixtime_now},
+    AppState,
+};
+
+/// How many entries come back from the audit log at once, unless asked for fewer.
+const MAX_ENTRIES: u64 = 1000;
+
+/// The actor for things done by someone we don't know, like a failed login.
+pub(crate) const ANONYMOUS: &str = "anonymous";
+
+/// The actor for things done by someone who's since been deleted.
+pub(crate) const DELETED: &str = "deleted";
+
+/// Failures of the same kind from the same IP within this many seconds of the first go on its
+/// entry as a count, so someone guessing at keys can't flood the audit log.
+const COLLAPSE_SECS: u64 = 10 * 60;
+
+/// Something security-relevant that happened, as it's kept in the audit_log table.
+#[derive(Serialize)]
+pub(crate) struct AuditEntry {
+    pub(crate) id: u64,
+    /// Seconds since the unix epoch.
+    pub(crate) time: u64,
+    /// Who did it: a web_user's username, or whoever they claimed to be.
+    pub(crate) actor: String,
+    /// Where the request came from, past any trusted proxies.
+    pub(crate) ip: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    /// What happened, like "api_key.create". Failures all start with "auth.fail.", followed
+    /// by the reason.
+    pub(crate) event: String,
+    /// The particulars, for a person to read.
+    pub(crate) detail: String,
+    /// How many times it happened. Only failures are ever counted up, and the particulars are
+    /// the first one's.
+    pub(crate) count: u64,
+}
+
+/// What to look for in the audit log. Everything's optional.
+#[derive(Deserialize)]
+pub(crate) struct AuditFilter {
+    /// An event, or the start of some, like "auth.fail".
+    pub(crate) event: Option<String>,
+    pub(crate) actor: Option<String>,
+    pub(crate) ip: Option<String>,
+    /// Seconds since the unix epoch, inclusive.
+    #[serde(default)]
+    pub(crate) since: u64,
+    /// Seconds since the unix epoch, exclusive.
+    #[serde(default = "no_limit")]
+    pub(crate) until: u64,
+    /// Only entries older than this one, for paging back through.
+    #[serde(default = "no_limit")]
+    pub(crate) before_id: u64,
+    #[serde(default = "max_entries")]
+    pub(crate) limit: u64,
+}
+
+/// As high as SQLite integers go.
+fn no_limit() -> u64 {
+    i64::MAX as u64
+}
+
+fn max_entries() -> u64 {
+    MAX_ENTRIES
+}
+
+/// Writes down something that happened in response to a request. This doesn't fail: if the
+/// audit log can't be written to, we say so in the server log and carry on, since whatever
+/// happened has already happened.
+pub(crate) async fn record(
+    data: &AppState,
+    req: &HttpRequest,
+    actor: &str,
+    event: &str,
+    detail: String,
+) {
+    let (entry, collapse_since) = new_entry(data, req, actor, event, detail);
+    if let Err(e) = db::insert_audit_entry(&data.pool, entry, collapse_since).await {
+        log::error!("Failed to write to the audit log: {}", e);
+    }
+}
+
+/// The same as record, for code that can't wait around for it. It's written down in the
+/// background.
+pub(crate) fn record_later(
+    data: &AppState,
+    req: &HttpRequest,
+    actor: &str,
+    event: &str,
+    detail: String,
+) {
+    let (entry, collapse_since) = new_entry(data, req, actor, event, detail);
+    let pool = data.pool.clone();
+    rt::spawn(async move {
+        if let Err(e) = db::insert_audit_entry(&pool, entry, collapse_since).await {
+            log::error!("Failed to write to the audit log: {}", e);
+        }
+    });
+}
+
+/// Fills in an audit log entry for a request, and works out how far back to look for one to
+/// count it on, if it's a failure.
+fn new_entry(
+    data: &AppState,
+    req: &HttpRequest,
+    actor: &str,
+    event: &str,
+    detail: String,
+) -> (AuditEntry, Option<u64>) {
+    let entry = AuditEntry {
+        id: 0,
+        time: unixtime_now(),
+        actor: actor.to_string(),
+        ip: client_ip(req, &data.config().trusted_proxies).map(|ip| ip.to_string()),
+        user_agent: req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(str::to_string),
+        event: event.to_string(),
+        detail,
+        count: 1,
+    };
+    log::info!("Audit: {} {}: {}", entry.actor, entry.event, entry.detail);
+    let collapse_since = event
+        .starts_with("auth.fail.")
+        .then(|| entry.time.saturating_sub(COLLAPSE_SECS));
+    (entry, collapse_since)
+}
+
+/// Notes the first time an api key is used from each address, so a key that's turned up
+/// somewhere it shouldn't stands out.
+pub(crate) async fn note_key_use(data: &AppState, req: &HttpRequest, key_id: u64, username: &str) {
+    let Some(ip) = client_ip(req, &data.config().trusted_proxies) else {
+        return;
+    };
+    // Only bother the database the first time we see each one since startup.
+    if data.key_ips.contains(&(key_id, ip)) {
+        return;
+    }
+    match db::note_key_ip(&data.pool, key_id, ip.to_string()).await {
+        Ok(new) => {
+            data.key_ips.insert((key_id, ip));
+            if new {
+                let detail = format!("api key id {} used from {} for the first time", key_id, ip);
+                record(data, req, username, "api_key.new_ip", detail).await;
+            }
+        }
+        Err(e) => log::error!("Failed to note where api key id {} was used: {}", key_id, e),
+    }
+}
+
+#[get("/api/admin/audit")]
+pub(crate) async fn get_admin_audit(
+    info: web::Query<AuditFilter>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = require_admin(&data, &req).await {
+        return response;
+    }
+
+    let mut filter = info.into_inner();
+    filter.limit = filter.limit.min(MAX_ENTRIES);
+    filter.until = filter.until.min(no_limit());
+    filter.before_id = filter.before_id.min(no_limit());
+    match db::list_audit_entries(&data.pool, filter).await {
+        Ok(entries) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&entries).unwrap()),
+        Err(e) => {
+            log::error!("/api/admin/audit: failed to read the audit log: {}", e);
+            internal_error()
+        }
+    }
+}
@@ -11,12 +11,20 @@ use oauth2::{
     ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
     TokenResponse, TokenUrl,
 };
+use parking_lot::RwLock;
 use primitive_types::U512;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha512};
 use std::{collections::HashMap, time::Instant};
 
-use crate::{config::Config, misc::forbidden, AppState, LONG_EXPIRY_SECS_I};
+use crate::{
+    audit::{self, ANONYMOUS},
+    config::Config,
+    invite::redeem_invite,
+    misc::forbidden,
+    AppState, LONG_EXPIRY_SECS_I,
+};
 
 /// In minutes.
 const MAX_AUTH_DURATION_MINUTES: i64 = 5;
@@ -27,10 +35,36 @@ pub(crate) struct SessionToken {
     pub(crate) name: String,
 }
 
+/// What a session is kept under, in memory and in the sessions table while the server's down:
+/// the SHA-512 of its key, so a copy of either isn't enough to sign in with.
+pub(crate) fn hash_session_key(session_key: U512) -> U512 {
+    let mut bytes = [0u8; 64];
+    session_key.to_big_endian(&mut bytes);
+    U512::from_big_endian(&Sha512::digest(bytes))
+}
+
 pub(crate) struct OAuth {
-    oauth_client: BasicClient,
-    /// Associates PKCE verification objects (and creation time, for expiry) with the random state parameters.
-    pkce_verifs: DashMap<String, (Instant, PkceCodeVerifier)>,
+    /// Swapped out when the config is reloaded.
+    oauth_client: RwLock<BasicClient>,
+    /// Associates PKCE verification objects (and creation time, for expiry) with the random state parameters,
+    /// along with the invite code the login was started with, if any.
+    pkce_verifs: DashMap<String, (Instant, PkceCodeVerifier, Option<String>)>,
+}
+
+impl OAuth {
+    /// How many logins have been started but not finished (or expired) yet.
+    pub(crate) fn pending_logins(&self) -> usize {
+        self.pkce_verifs.len()
+    }
+
+    /// Switches to a client built from a new config. Logins that are partway through carry on.
+    pub(crate) fn set_client(&self, client: BasicClient) {
+        *self.oauth_client.write() = client;
+    }
+
+    fn client(&self) -> BasicClient {
+        self.oauth_client.read().clone()
+    }
 }
 
 #[derive(Deserialize)]
@@ -50,50 +84,65 @@ struct URLOut {
     url: String,
 }
 
-pub(crate) fn generate_oauth(config: &Config) -> OAuth {
+/// Builds an OAuth client from the config, or says which URL in it is no good.
+pub(crate) fn build_client(config: &Config) -> Result<BasicClient, &'static str> {
     // Read the config properties and process them.
     let google_client_id = ClientId::new(config.oauth_provider.client_id.to_string());
     let google_client_secret = ClientSecret::new(config.oauth_provider.client_secret.to_string());
     let endpoint_auth_url = AuthUrl::new(config.oauth_provider.auth_url.to_string())
-        .expect("Invalid authorization endpoint URL");
+        .map_err(|_| "Invalid authorization endpoint URL")?;
     let token_url = TokenUrl::new(config.oauth_provider.token_url.to_string())
-        .expect("Invalid token endpoint URL");
+        .map_err(|_| "Invalid token endpoint URL")?;
+    // The redirect URL is "https://your-site.com/api/auth/redirect";
+    let redirect_url =
+        RedirectUrl::new(format!("https://{}/api/auth/redirect", config.domain_name))
+            .map_err(|_| "Invalid redirect URL - bad domain name?")?;
 
     // Construct a client from our config properties.
-    let client = BasicClient::new(
+    Ok(BasicClient::new(
         google_client_id,
         Some(google_client_secret),
         endpoint_auth_url,
         Some(token_url),
     )
-    .set_redirect_uri(
-        // The redirect URL is "https://your-site.com/api/auth/redirect";
-        RedirectUrl::new(format!("https://{}/api/auth/redirect", config.domain_name))
-            .expect("Invalid redirect URL - bad domain name?"),
-    );
+    .set_redirect_uri(redirect_url))
+}
+
+pub(crate) fn generate_oauth(config: &Config) -> OAuth {
     OAuth {
-        oauth_client: client,
+        oauth_client: RwLock::new(build_client(config).unwrap_or_else(|e| panic!("{}", e))),
         pkce_verifs: DashMap::with_capacity(4),
     }
 }
 
 #[get("/api/auth/url")]
 pub(crate) async fn get_auth_url(data: web::Data<AppState>) -> impl Responder {
+    let (auth_url, cookie) = start_login(&data, None);
+    HttpResponse::Ok()
+        .cookie(cookie)
+        .insert_header(ContentType::json())
+        .body(serde_json::to_string(&URLOut { url: auth_url }).unwrap())
+}
+
+/// Starts a login, remembering the invite code it's for if there is one. Returns the URL to
+/// send the user off to, and the cookie that has to come back with them.
+pub(crate) fn start_login(data: &AppState, invite: Option<String>) -> (String, Cookie<'static>) {
     // Construct this statically, to prevent extra cost. This is the longest
     // an authentication should be allowed to take.
     static MAX_AUTH_DURATION: Duration = Duration::minutes(MAX_AUTH_DURATION_MINUTES);
 
     // Generate a new PKCE challenge for this client.
     let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let config = data.config();
 
     // Generate an auth URL and CSRF token.
     let (auth_url, csrf_token) = data
         .auth
-        .oauth_client
+        .client()
         .authorize_url(CsrfToken::new_random)
         // Set the desired scopes.
         .add_scopes(
-            data.config
+            config
                 .oauth_provider
                 .scopes
                 .iter()
@@ -107,8 +156,8 @@ pub(crate) async fn get_auth_url(data: web::Data<AppState>) -> impl Responder {
     // to fake on a victim's machine - setting cookies on another site isn't allowed.
     // It also shouldn't be readable by anyone ever - only the service needs to see it.
     // It should, however, be sent on top-level navigation (redirect) from Google.
-    let cookie = Cookie::build("csrf_state", csrf_token.secret())
-        .domain(data.config.domain_name.to_string())
+    let cookie = Cookie::build("csrf_state", csrf_token.secret().to_string())
+        .domain(config.domain_name.to_string())
         .max_age(MAX_AUTH_DURATION)
         .same_site(actix_web::cookie::SameSite::Lax)
         .http_only(true)
@@ -118,54 +167,48 @@ pub(crate) async fn get_auth_url(data: web::Data<AppState>) -> impl Responder {
     // Associate the PKCE challenge with the CSRF token.
     data.auth.pkce_verifs.insert(
         csrf_token.secret().to_string(),
-        (Instant::now(), pkce_verifier),
+        (Instant::now(), pkce_verifier, invite),
     );
     // Remove any pkce verifs that have expired. This prevents
     // a resource-exhaustion attack.
     data.auth
         .pkce_verifs
         .retain(|_, v| v.0.elapsed() <= MAX_AUTH_DURATION);
-    HttpResponse::Ok()
-        .cookie(cookie)
-        .insert_header(ContentType::json())
-        .body(
-            serde_json::to_string(&URLOut {
-                url: auth_url.to_string(),
-            })
-            .unwrap(),
-        )
+    (auth_url.to_string(), cookie)
 }
 
-/// Validates CSRF and PKCE properties and then gets a bearer token from the oauth API.
+/// Validates CSRF and PKCE properties and then gets a bearer token from the oauth API, along
+/// with the invite code the login was started with, if any.
+/// If that doesn't work out, says why, in a form that can go in an audit log event name.
 async fn request_bearer_token(
     auth: &OAuth,
     query: web::Query<RedirectQuery>,
     req: HttpRequest,
-) -> Option<String> {
+) -> Result<(String, Option<String>), &'static str> {
     // Read the CSRF token from the cookie.
     let cookie_csrf_token = match read_csrf_token(req) {
         Some(t) => t,
         None => {
             log::debug!("/api/auth/redirect: No CSRF token cookie.");
-            return None;
+            return Err("no_csrf_cookie");
         }
     };
     // Confirm that the cookie matches what's in the request parameters.
     if query.state.secret().ne(cookie_csrf_token.secret()) {
         log::debug!("/api/auth/redirect: CSRF token didn't match.");
-        return None;
+        return Err("csrf_mismatch");
     }
     // Try to remove the corresponding PKCE verifier from the hashmap.
-    let pkce_verif = match auth.pkce_verifs.remove(cookie_csrf_token.secret()) {
-        Some(k) => k.1 .1,
+    let (pkce_verif, invite) = match auth.pkce_verifs.remove(cookie_csrf_token.secret()) {
+        Some((_, (_, verifier, invite))) => (verifier, invite),
         None => {
             log::debug!("/api/auth/redirect: CSRF token has no PKCE.");
-            return None;
+            return Err("no_pkce_verifier");
         }
     };
     // Get a bearer token from the code.
     let token_result = auth
-        .oauth_client
+        .client()
         .exchange_code(AuthorizationCode::new(query.code.clone()))
         // Set the PKCE code verifier.
         .set_pkce_verifier(pkce_verif)
@@ -175,14 +218,15 @@ async fn request_bearer_token(
         Ok(tok) => tok,
         _ => {
             log::debug!("/api/auth/redirect: Failed to get token.");
-            return None;
+            return Err("token_exchange");
         }
     };
-    Some(token.access_token().secret().to_string())
+    Ok((token.access_token().secret().to_string(), invite))
 }
 
 // Converts a bearer token into an email address using Google's userinfo API.
-async fn request_userinfo(token: String, config: &Config) -> Option<String> {
+// Failures are reported the same way as request_bearer_token's.
+async fn request_userinfo(token: String, config: &Config) -> Result<String, &'static str> {
     // Make the request to the API.
     let response = match realreqwest::Client::new()
         .get(config.userinfo_endpoint.to_string())
@@ -193,7 +237,7 @@ async fn request_userinfo(token: String, config: &Config) -> Option<String> {
         Ok(resp) => resp,
         _ => {
             log::debug!("/api/auth/redirect: userinfo request failed.");
-            return None;
+            return Err("userinfo");
         }
     };
 
@@ -205,7 +249,7 @@ async fn request_userinfo(token: String, config: &Config) -> Option<String> {
         }
         _ => {
             log::debug!("/api/auth/redirect: userinfo returned no body?");
-            return None;
+            return Err("userinfo");
         }
     };
 
@@ -214,7 +258,7 @@ async fn request_userinfo(token: String, config: &Config) -> Option<String> {
         Ok(h) => h,
         _ => {
             log::debug!("/api/auth/redirect: Failed to parse JSON map.");
-            return None;
+            return Err("userinfo");
         }
     };
 
@@ -225,17 +269,17 @@ async fn request_userinfo(token: String, config: &Config) -> Option<String> {
             Some(estr) => estr.to_owned(),
             None => {
                 log::debug!("/api/auth/redirect: email wasn't a string?");
-                return None;
+                return Err("userinfo");
             }
         },
         None => {
             log::debug!("/api/auth/redirect: userinfo didn't give an email.");
-            return None;
+            return Err("userinfo");
         }
     };
 
     // Yay, we made it!
-    Some(email)
+    Ok(email)
 }
 
 #[get("/api/auth/redirect")]
@@ -245,28 +289,42 @@ pub(crate) async fn get_auth_redirect(
     req: HttpRequest,
 ) -> impl Responder {
     // Try to exchange the code for a bearer token.
-    let token = match request_bearer_token(&data.auth, query, req).await {
-        Some(t) => t,
-        None => {
+    let (token, invite) = match request_bearer_token(&data.auth, query, req.clone()).await {
+        Ok(t) => t,
+        Err(reason) => {
+            let event = format!("auth.fail.{}", reason);
+            audit::record(&data, &req, ANONYMOUS, &event, "login failed".to_string()).await;
             return forbidden();
         }
     };
 
     // Use the bearer token to get the account's associated email.
-    let email = match request_userinfo(token, &data.config).await {
-        Some(email) => email,
-        None => {
+    let config = data.config();
+    let email = match request_userinfo(token, &config).await {
+        Ok(email) => email,
+        Err(reason) => {
+            let event = format!("auth.fail.{}", reason);
+            audit::record(&data, &req, ANONYMOUS, &event, "login failed".to_string()).await;
             return forbidden();
         }
     };
 
-    // Check if the email exists in the database, and if so get the user's name.
+    // Check if the email exists in the database, and if so get the user's name. If it doesn't,
+    // but they came with an invite, that makes them a web_user.
     let name = match crate::db::verify_email(&data.pool, email.clone()).await {
         Ok(Some(name)) => name,
-        Ok(None) => {
-            log::debug!("/api/auth/redirect: email not in db: '{}'.", email);
-            return forbidden();
-        }
+        Ok(None) => match invite {
+            Some(code) => match redeem_invite(&data, &req, code, &email).await {
+                Ok(name) => name,
+                Err(response) => return response,
+            },
+            None => {
+                log::debug!("/api/auth/redirect: email not in db: '{}'.", email);
+                let detail = format!("{} isn't a web_user, or has expired", email);
+                audit::record(&data, &req, ANONYMOUS, "auth.fail.unknown_email", detail).await;
+                return forbidden();
+            }
+        },
         Err(_) => {
             log::debug!("/api/auth/redirect: something went wrong in the db.");
             return forbidden();
@@ -277,7 +335,7 @@ pub(crate) async fn get_auth_redirect(
     // Generate a session key.
     let response = SessionToken {
         session_key: U512(rand::random()),
-        name,
+        name: name.clone(),
     };
 
     // Record the current time, for session key expiration.
@@ -285,17 +343,20 @@ pub(crate) async fn get_auth_redirect(
 
     // Pop the session key into our hashmap of valid ones.
     data.session_tokens.insert(
-        response.session_key,
+        hash_session_key(response.session_key),
         crate::TokenExpiry {
             last_used: now,
             issued: now,
+            name: name.clone(),
         },
     );
+    let detail = format!("signed in with {}", email);
+    audit::record(&data, &req, &name, "session.create", detail).await;
 
     // Build a cookie to hold the session key.
     // We'll send this cookie along with a redirect back to our frontend.
     let cookie = Cookie::build("session", serde_json::to_string(&response).unwrap())
-        .domain(data.config.domain_name.to_string())
+        .domain(config.domain_name.to_string())
         .max_age(Duration::seconds(LONG_EXPIRY_SECS_I))
         .same_site(actix_web::cookie::SameSite::Strict)
         .http_only(true)
@@ -307,7 +368,7 @@ pub(crate) async fn get_auth_redirect(
     // send a message too. JSON because I want to and all my other APIs return it.
     let response_body = RedirectOut {
         message: "Auth successful. Return home.".to_string(),
-        href: data.config.redirect_after_auth.to_string(),
+        href: config.redirect_after_auth.to_string(),
     };
 
     // Redirect to the configured location with the cookie and the message.
@@ -318,6 +379,17 @@ pub(crate) async fn get_auth_redirect(
         .body(serde_json::to_string(&response_body).unwrap())
 }
 
+/// Signs a web_user out everywhere. Returns how many sessions they had.
+pub(crate) fn revoke_sessions(data: &AppState, username: &str) -> usize {
+    let mut revoked = 0;
+    data.session_tokens.retain(|_, v| {
+        let keep = v.name != username;
+        revoked += usize::from(!keep);
+        keep
+    });
+    revoked
+}
+
 fn read_csrf_token(req: HttpRequest) -> Option<CsrfToken> {
     match req.cookies() {
         Ok(cookievec) => {
@@ -1,11 +1,65 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+use crate::provision::{Format, QrContent};
 // TODO: clap
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub(crate) struct Cli {
-    /// Config file
+    /// Config file, JSON or TOML. LOCATIONAPP_* environment variables and *_file settings
+    /// are layered on top of it. Needed for everything but schema
     #[arg(short, long, value_name = "FILE")]
-    pub(crate) config: PathBuf,
+    pub(crate) config: Option<PathBuf>,
+    /// Something to do instead of running the server
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub(crate) enum Command {
+    /// Export everything stored about a user to a zip archive
+    ExportUser {
+        /// The username on their api keys and web_users rows
+        #[arg(short, long)]
+        username: String,
+        /// Where to write the archive
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
+    /// Delete everything stored about a user, permanently
+    DeleteUser {
+        /// The username on their api keys and web_users rows
+        #[arg(short, long)]
+        username: String,
+    },
+    /// Print what's needed to set a phone up with an api key, or write it to a file
+    Provision {
+        /// The api key's id
+        #[arg(short, long)]
+        id: u64,
+        #[arg(short, long, value_enum, default_value_t)]
+        format: Format,
+        /// What to put in the QR code, for the png and svg formats
+        #[arg(short, long, value_enum, default_value_t)]
+        qr: QrContent,
+        /// Where to write it, instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        output: Option<PathBuf>,
+    },
+    /// Print the JSON Schema that the config has to fit, same as config schema
+    Schema,
+    /// Work with the config
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand)]
+pub(crate) enum ConfigCommand {
+    /// Put the config together, check it, and print it with the secrets redacted
+    Check,
+    /// Print the JSON Schema that the config has to fit
+    Schema,
 }
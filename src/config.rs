@@ -1,8 +1,10 @@
+use std::{collections::HashMap, net::IpAddr};
+
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 #[allow(dead_code)]
-#[derive(Deserialize, JsonSchema)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub(crate) struct Config {
     pub(crate) oauth_provider: OauthConfig,
     pub(crate) domain_name: String,
@@ -10,10 +12,33 @@ pub(crate) struct Config {
     pub(crate) redirect_after_auth: String,
     pub(crate) listen: Vec<ListenSpec>,
     pub(crate) db_path: String,
+    #[serde(default)]
+    pub(crate) timeline: TimelineConfig,
+    #[serde(default)]
+    pub(crate) filter: FilterConfig,
+    /// Offline reverse geocoding. Leave it out to just show coordinates.
+    pub(crate) geocoder: Option<GeocoderConfig>,
+    /// The secret that share links are signed with. If it's left out, a random one is made up at
+    /// startup, and share links stop working when the server restarts.
+    pub(crate) share_secret: Option<String>,
+    /// How long location history is kept. Leave it out to keep everything forever.
+    pub(crate) retention: Option<RetentionConfig>,
+    #[serde(default)]
+    pub(crate) rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub(crate) key_rotation: KeyRotationConfig,
+    #[serde(default)]
+    pub(crate) provisioning: ProvisioningConfig,
+    /// The Prometheus endpoint, /metrics. Leave it out to turn it off.
+    pub(crate) metrics: Option<MetricsConfig>,
+    /// The reverse proxies in front of us. Requests from these (or over a Unix socket) have
+    /// their X-Forwarded-For header believed, for logging and rate limiting.
+    #[serde(default)]
+    pub(crate) trusted_proxies: Vec<IpAddr>,
 }
 
 #[allow(dead_code)]
-#[derive(Deserialize, JsonSchema)]
+#[derive(Deserialize, Serialize, JsonSchema)]
 pub(crate) struct OauthConfig {
     pub(crate) client_id: String,
     pub(crate) client_secret: String,
@@ -22,9 +47,233 @@ pub(crate) struct OauthConfig {
     pub(crate) scopes: Vec<String>,
 }
 
+/// Somewhere to listen: either a TCP address and port, or a Unix socket.
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(untagged)]
+pub(crate) enum ListenSpec {
+    Tcp(TcpListenSpec),
+    Unix(UnixListenSpec),
+}
+
 #[allow(dead_code)]
-#[derive(Deserialize, Clone, JsonSchema)]
-pub(crate) struct ListenSpec {
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct TcpListenSpec {
     pub(crate) addr: String,
     pub(crate) port: u16,
+    /// Serve HTTPS here, instead of plain HTTP. Leave it out if there's a reverse proxy
+    /// in front doing that.
+    pub(crate) tls: Option<TlsConfig>,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct UnixListenSpec {
+    /// Where to make the socket. Anything already there is removed first.
+    pub(crate) path: String,
+    /// The socket's permissions, in octal, like "660". Leave it out to go with the umask.
+    pub(crate) mode: Option<String>,
+    /// The user to give the socket to. Leave it out to keep it as whoever we're running as.
+    pub(crate) owner: Option<String>,
+    /// The group to give the socket to, say the one the reverse proxy runs as.
+    pub(crate) group: Option<String>,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct TlsConfig {
+    /// The certificate chain, PEM encoded, leaf first. Certbot's fullchain.pem, say.
+    pub(crate) cert_path: String,
+    /// The private key, PEM encoded. PKCS#8, PKCS#1 (RSA) and SEC1 (EC) are all fine.
+    pub(crate) key_path: String,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct MetricsConfig {
+    /// If set, scrapers have to send it as a bearer token.
+    pub(crate) token: Option<String>,
+    /// If set, /metrics is served only here, and not alongside the API. At least one of
+    /// this and the token has to be set, so the metrics aren't open to the world.
+    pub(crate) listen: Option<ListenSpec>,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct TimelineConfig {
+    /// How far apart, in meters, points can be and still count as the same stay.
+    pub(crate) stay_radius_meters: f64,
+    /// How long, in minutes, a device must linger within the radius to count as a stay.
+    pub(crate) stay_min_minutes: u64,
+}
+
+impl Default for TimelineConfig {
+    fn default() -> Self {
+        TimelineConfig {
+            stay_radius_meters: 100.0,
+            stay_min_minutes: 10,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct FilterConfig {
+    /// Fixes that claim to be less accurate than this, in meters, are rejected.
+    pub(crate) max_accuracy_meters: f64,
+    /// Fixes that would mean travelling faster than this, in meters per second, are rejected.
+    pub(crate) max_speed_mps: f64,
+    /// How fast, in meters per second, we assume a device's true position drifts when smoothing.
+    /// Lower is smoother but lags more.
+    pub(crate) smoothing_speed_mps: f64,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            max_accuracy_meters: 2000.0,
+            max_speed_mps: 90.0,
+            smoothing_speed_mps: 3.0,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, JsonSchema)]
+pub(crate) struct GeocoderConfig {
+    /// A GeoNames dump (like cities500.txt), or a tab-separated name, latitude, longitude,
+    /// region file. It's loaded into memory at startup.
+    pub(crate) places_path: String,
+    /// Locations further than this, in kilometers, from every known place don't get a label.
+    #[serde(default = "default_geocoder_max_distance_km")]
+    pub(crate) max_distance_km: f64,
+}
+
+fn default_geocoder_max_distance_km() -> f64 {
+    25.0
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct RetentionConfig {
+    /// The rule for everyone without an override.
+    #[serde(flatten)]
+    pub(crate) default: RetentionRule,
+    /// Rules for particular users, by the username on their api keys. An override replaces
+    /// the default rule entirely, it doesn't merge with it.
+    #[serde(default)]
+    pub(crate) user_overrides: HashMap<String, RetentionRule>,
+    /// How often, in minutes, to enforce the rules.
+    #[serde(default = "default_retention_interval_minutes")]
+    pub(crate) interval_minutes: u64,
+    /// How many rows to remove per database transaction, so the server doesn't stall.
+    #[serde(default = "default_retention_batch_size")]
+    pub(crate) batch_size: u64,
+    /// Audit log entries older than this many days are deleted. Leave it out to keep them
+    /// forever.
+    pub(crate) audit_days: Option<u64>,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, JsonSchema, Clone)]
+pub(crate) struct RetentionRule {
+    /// History younger than this many days is kept at full resolution.
+    pub(crate) full_days: Option<u64>,
+    /// Older history is thinned out to one point per this many minutes.
+    pub(crate) downsample_minutes: Option<u64>,
+    /// History older than this many days is deleted.
+    pub(crate) delete_days: Option<u64>,
+}
+
+fn default_retention_interval_minutes() -> u64 {
+    60
+}
+
+fn default_retention_batch_size() -> u64 {
+    1000
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct RateLimitConfig {
+    /// Applies to every request, by client IP.
+    pub(crate) per_ip: BucketConfig,
+    /// Applies to location updates, by api key, before the key is even checked.
+    pub(crate) per_key: BucketConfig,
+    /// An IP that sends this many bad api keys, sessions or invite codes within the window gets
+    /// locked out. Zero turns it off.
+    pub(crate) lockout_failures: u32,
+    /// Minutes.
+    pub(crate) lockout_window_minutes: u64,
+    /// How long a lockout lasts, in minutes.
+    pub(crate) lockout_minutes: u64,
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, JsonSchema, Clone, Copy)]
+pub(crate) struct BucketConfig {
+    /// How many requests can come in at once before the limit kicks in.
+    pub(crate) burst: u32,
+    /// How many requests per minute are allowed in the long run.
+    pub(crate) per_minute: u32,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            per_ip: BucketConfig {
+                burst: 60,
+                per_minute: 120,
+            },
+            per_key: BucketConfig {
+                burst: 10,
+                per_minute: 30,
+            },
+            lockout_failures: 20,
+            lockout_window_minutes: 10,
+            lockout_minutes: 15,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct KeyRotationConfig {
+    /// How long, in hours, an api key keeps working after it's been rotated, so the device has
+    /// time to pick up its replacement. Admins can ask for a different grace period when they
+    /// rotate a key.
+    pub(crate) grace_hours: u64,
+}
+
+impl Default for KeyRotationConfig {
+    fn default() -> Self {
+        KeyRotationConfig { grace_hours: 72 }
+    }
+}
+
+/// How often phones are told to report, when they're set up from a provisioning payload.
+#[allow(dead_code)]
+#[derive(Deserialize, Serialize, JsonSchema)]
+#[serde(default)]
+pub(crate) struct ProvisioningConfig {
+    /// Seconds between reports while the phone is on the move.
+    pub(crate) moving_interval_secs: u64,
+    /// Seconds between reports while it's sitting still.
+    pub(crate) idle_interval_secs: u64,
+    /// How far, in meters, the phone has to move before it reports again.
+    pub(crate) displacement_meters: u64,
+}
+
+impl Default for ProvisioningConfig {
+    fn default() -> Self {
+        ProvisioningConfig {
+            moving_interval_secs: 30,
+            idle_interval_secs: 300,
+            displacement_meters: 50,
+        }
+    }
 }
@@ -1,8 +1,27 @@
+use std::collections::HashSet;
+
 use actix_web::web;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::{params, Rows};
+use rusqlite::{params, Connection, OptionalExtension, Rows};
 
-use crate::{config::Config, misc::unixtime_now};
+use crate::{
+    account::{
+        ApiKeyInfo, DeletedUser, GroupMembership, KeyIp, PrivacyPause, StoredLocation, UserExport,
+        WebUserInfo,
+    },
+    audit::{AuditEntry, AuditFilter, DELETED},
+    config::Config,
+    groups::{Group, GroupMember},
+    invite::{Invite, Redeemed},
+    keys::{parse_scopes, VerifiedKey, SCOPE_WRITE},
+    location::Location,
+    metrics::metrics,
+    misc::{unixtime_now, SECS_PER_DAY},
+    people::Person,
+    persist::SavedSession,
+    privacy::{Precision, PrivacyPolicy, EVERYONE},
+    share::ShareLink,
+};
 
 pub(crate) type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 
@@ -21,19 +40,1453 @@ pub(crate) async fn verify_email(
     .await
 }
 
-/// Checks if an api_key is authorized, and returns the associated api_key id and username if so.
-pub(crate) async fn verify_api_key(
-    pool: &Pool,
-    key: String,
-) -> Result<Option<(u64, String)>, actix_web::Error> {
+/// Checks if a username belongs to a web_user that's an admin and hasn't expired.
+pub(crate) async fn is_admin(pool: &Pool, username: String) -> Result<bool, actix_web::Error> {
     query_internal(
         pool,
-        key,
+        username,
         unixtime_now(),
-        "SELECT id, username FROM api_keys WHERE key_base64 IS ?1 AND expiration > ?2".to_string(),
-        internal_get_u64_string,
+        "SELECT role FROM web_users WHERE username IS ?1 AND expiration > ?2 AND role IS 'admin'"
+            .to_string(),
+        internal_get_one_string,
     )
     .await
+    .map(|role| role.is_some())
+}
+
+/// Checks if an api_key is authorized, and returns the associated api_key id and username if so.
+/// A key that's been rotated out still works until its grace period is up.
+pub(crate) async fn verify_api_key(
+    pool: &Pool,
+    key: String,
+) -> Result<Option<VerifiedKey>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "SELECT id, username, scopes FROM api_keys WHERE expiration > ?2 AND (key_base64 IS ?1 OR (previous_key_base64 IS ?1 AND previous_expiration > ?2))",
+        )?
+        .query_row(params![key, unixtime_now()], |row| {
+            Ok(VerifiedKey {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                scopes: parse_scopes(&row.get::<_, String>(2)?),
+            })
+        })
+        .optional()
+    })
+    .await
+}
+
+/// Looks up the current key for whichever api key is given, old or new, if it's still good.
+/// Returns the api_key id, who it's for, the current key, its expiration, and whether the given
+/// key was the old one.
+pub(crate) async fn current_api_key(
+    pool: &Pool,
+    key: String,
+) -> Result<Option<(u64, String, String, u64, bool)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "SELECT id, username, key_base64, expiration, key_base64 IS NOT ?1 FROM api_keys WHERE expiration > ?2 AND (key_base64 IS ?1 OR (previous_key_base64 IS ?1 AND previous_expiration > ?2))",
+        )?
+        .query_row(params![key, unixtime_now()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })
+        .optional()
+    })
+    .await
+}
+
+/// Gets an api key by its id, if it's still good: who it's for, the key itself, and when it
+/// expires.
+pub(crate) async fn get_api_key(
+    pool: &Pool,
+    id: u64,
+) -> Result<Option<(String, String, u64)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "SELECT username, key_base64, expiration FROM api_keys WHERE id IS ?1 AND expiration > ?2",
+        )?
+        .query_row(params![id, unixtime_now()], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .optional()
+    })
+    .await
+}
+
+/// Swaps in a new key for an api_key id, keeping the old one working until `grace_until`, or
+/// the key's expiration if that's sooner. Any key from an earlier rotation stops working.
+/// Returns the username and expiration, or None if there's no such key.
+pub(crate) async fn rotate_api_key(
+    pool: &Pool,
+    id: u64,
+    key_base64: String,
+    grace_until: u64,
+) -> Result<Option<(String, u64)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let row: Option<(String, u64)> = tx
+            .query_row(
+                "SELECT username, expiration FROM api_keys WHERE id IS ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if row.is_some() {
+            tx.execute(
+                "UPDATE api_keys SET previous_key_base64 = key_base64, previous_expiration = MIN(expiration, ?3), key_base64 = ?2 WHERE id IS ?1",
+                params![id, key_base64, grace_until],
+            )?;
+        }
+        tx.commit()?;
+        Ok(row)
+    })
+    .await
+}
+
+/// Appends a raw location fix to the stored history for the given api_key id. Fixes that
+/// the filter threw out are kept too, marked with the reason, so there's an audit trail.
+pub(crate) async fn insert_location(
+    pool: &Pool,
+    key_id: u64,
+    loc: Location,
+    rejected: Option<&'static str>,
+) -> Result<(), actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "INSERT INTO locations(key_id, latitude, longitude, accuracy, time, rejected) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![key_id, loc.latitude, loc.longitude, loc.accuracy, loc.time, rejected])?;
+        Ok(())
+    })
+    .await
+}
+
+/// The condition for a fix l of api key ?1 being hidden from a viewer (?2) because sharing was
+/// paused for them when it was recorded. A pause for everyone covers viewers without a policy
+/// of their own, and nothing is hidden from the device's owner.
+const PAUSED_FIX: &str = "((SELECT username FROM api_keys WHERE id IS ?1) IS NOT ?2 \
+    AND EXISTS (SELECT 1 FROM privacy_pauses p WHERE p.key_id IS ?1 AND l.time >= p.start AND l.time < p.until \
+    AND (p.viewer IS ?2 OR (p.viewer IS '*' AND NOT EXISTS (SELECT 1 FROM privacy_policies pp WHERE pp.key_id IS ?1 AND pp.viewer IS ?2)))))";
+
+/// Gets the stored history for the given api_key id with start <= time < end, oldest first,
+/// as a viewer (or EVERYONE) gets to see it. Fixes that the filter rejected are left out, and
+/// so are ones recorded while sharing was paused for the viewer.
+pub(crate) async fn get_locations(
+    pool: &Pool,
+    key_id: u64,
+    viewer: String,
+    start: u64,
+    end: u64,
+) -> Result<Vec<Location>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(&format!(
+            "SELECT l.latitude, l.longitude, l.accuracy, l.time FROM locations l WHERE l.key_id IS ?1 AND l.time >= ?3 AND l.time < ?4 AND l.rejected IS NULL AND NOT {} ORDER BY l.time",
+            PAUSED_FIX
+        ))?;
+        let rows = statement.query_map(params![key_id, viewer, start, end], |row| {
+            Ok(Location {
+                latitude: row.get(0)?,
+                longitude: row.get(1)?,
+                accuracy: row.get(2)?,
+                time: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Counts the fixes received from the given api_key id with start <= time < end, and how many
+/// of those the filter rejected. The counts are bucketed by day, counting from start, and
+/// only days with any fixes at all show up.
+pub(crate) async fn count_locations_by_day(
+    pool: &Pool,
+    key_id: u64,
+    viewer: String,
+    start: u64,
+    end: u64,
+) -> Result<Vec<(u64, u64, u64)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(&format!(
+            "SELECT (l.time - ?3) / 86400, COUNT(*), COUNT(l.rejected) FROM locations l WHERE l.key_id IS ?1 AND l.time >= ?3 AND l.time < ?4 AND NOT {} GROUP BY 1",
+            PAUSED_FIX
+        ))?;
+        let rows = statement.query_map(params![key_id, viewer, start, end], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Lists every api_key id and the username it was issued to, expired or not.
+pub(crate) async fn list_key_owners(pool: &Pool) -> Result<Vec<(u64, String)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached("SELECT id, username FROM api_keys ORDER BY id")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Deletes up to `limit` of the given api_key id's stored fixes from before `before`.
+/// Returns how many went.
+pub(crate) async fn delete_locations_before(
+    pool: &Pool,
+    key_id: u64,
+    before: u64,
+    limit: u64,
+) -> Result<usize, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "DELETE FROM locations WHERE id IN (SELECT id FROM locations WHERE key_id IS ?1 AND time < ?2 LIMIT ?3)",
+        )?
+        .execute(params![key_id, before, limit])
+    })
+    .await
+}
+
+/// Thins out the given api_key id's stored fixes from before `before`, keeping only the first
+/// accepted fix in each `bucket_secs`-long stretch of time. Rejected fixes are left alone, so
+/// the stats can still count them, until they're old enough to delete. Deletes up to `limit`
+/// fixes and returns how many went.
+pub(crate) async fn downsample_locations_before(
+    pool: &Pool,
+    key_id: u64,
+    before: u64,
+    bucket_secs: u64,
+    limit: u64,
+) -> Result<usize, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "DELETE FROM locations WHERE id IN (SELECT id FROM locations WHERE key_id IS ?1 AND time < ?2 AND rejected IS NULL AND id NOT IN (SELECT MIN(id) FROM locations WHERE key_id IS ?1 AND time < ?2 AND rejected IS NULL GROUP BY time / ?3) LIMIT ?4)",
+        )?
+        .execute(params![key_id, before, bucket_secs, limit])
+    })
+    .await
+}
+
+/// Gets the username an api_key id was issued to, whether or not it's expired.
+pub(crate) async fn get_key_owner(
+    pool: &Pool,
+    key_id: u64,
+) -> Result<Option<String>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached("SELECT username FROM api_keys WHERE id IS ?1")?
+            .query_row(params![key_id], |row| row.get(0))
+            .optional()
+    })
+    .await
+}
+
+/// Records a new share link, and returns its id.
+pub(crate) async fn insert_share_link(
+    pool: &Pool,
+    link: ShareLink,
+) -> Result<u64, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "INSERT INTO share_links(key_id, created_by, issued, expiration, precision_meters, trail_minutes) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![
+            link.key_id,
+            link.created_by,
+            link.issued,
+            link.expiration,
+            link.precision_meters,
+            link.trail_minutes
+        ])?;
+        Ok(conn.last_insert_rowid() as u64)
+    })
+    .await
+}
+
+/// Gets a share link by id, if it exists and hasn't expired or been revoked.
+pub(crate) async fn get_share_link(
+    pool: &Pool,
+    id: u64,
+) -> Result<Option<ShareLink>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT id, key_id, created_by, issued, expiration, precision_meters, trail_minutes FROM share_links WHERE id IS ?1 AND expiration > ?2 AND revoked = 0",
+        )?;
+        let mut rows = statement.query_map(params![id, unixtime_now()], internal_share_link_from_row)?;
+        rows.next().transpose()
+    })
+    .await
+}
+
+/// Lists the share links that a web_user made which are still live.
+pub(crate) async fn list_share_links(
+    pool: &Pool,
+    created_by: String,
+) -> Result<Vec<ShareLink>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT id, key_id, created_by, issued, expiration, precision_meters, trail_minutes FROM share_links WHERE created_by IS ?1 AND expiration > ?2 AND revoked = 0 ORDER BY id",
+        )?;
+        let rows = statement.query_map(params![created_by, unixtime_now()], internal_share_link_from_row)?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Revokes a share link, as long as it was made by the given web_user. Returns whether
+/// there was anything to revoke.
+pub(crate) async fn revoke_share_link(
+    pool: &Pool,
+    id: u64,
+    created_by: String,
+) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let changed = conn
+            .prepare_cached("UPDATE share_links SET revoked = 1 WHERE id IS ?1 AND created_by IS ?2 AND revoked = 0")?
+            .execute(params![id, created_by])?;
+        Ok(changed > 0)
+    })
+    .await
+}
+
+/// Gets the privacy policy that applies to a viewer of a device: their own if there is one,
+/// otherwise the one for everyone. A viewer of None only gets the one for everyone.
+pub(crate) async fn get_privacy_policy(
+    pool: &Pool,
+    key_id: u64,
+    viewer: Option<String>,
+) -> Result<Option<PrivacyPolicy>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "SELECT key_id, viewer, mode, grid_meters, paused_until FROM privacy_policies WHERE key_id IS ?1 AND (viewer IS ?2 OR viewer IS ?3) ORDER BY viewer IS ?3 LIMIT 1",
+        )?
+        .query_row(
+            params![key_id, viewer, EVERYONE],
+            internal_privacy_policy_from_row,
+        )
+        .optional()
+    })
+    .await
+}
+
+/// Lists all the privacy policies set on a device.
+pub(crate) async fn list_privacy_policies(
+    pool: &Pool,
+    key_id: u64,
+) -> Result<Vec<PrivacyPolicy>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT key_id, viewer, mode, grid_meters, paused_until FROM privacy_policies WHERE key_id IS ?1 ORDER BY viewer",
+        )?;
+        let rows = statement.query_map(params![key_id], internal_privacy_policy_from_row)?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Sets the privacy policy for a device and viewer, replacing whatever was there.
+pub(crate) async fn set_privacy_policy(
+    pool: &Pool,
+    policy: PrivacyPolicy,
+) -> Result<(), actix_web::Error> {
+    let (mode, grid_meters) = match policy.precision {
+        Precision::Exact => ("exact", None),
+        Precision::Grid { meters } => ("grid", Some(meters)),
+        Precision::City => ("city", None),
+    };
+    with_conn_internal(pool, move |conn| {
+        let now = unixtime_now();
+        let tx = conn.transaction()?;
+        internal_end_pause(&tx, policy.key_id, &policy.viewer, now)?;
+        tx.execute(
+            "INSERT OR REPLACE INTO privacy_policies(key_id, viewer, mode, grid_meters, paused_until) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                policy.key_id,
+                policy.viewer,
+                mode,
+                grid_meters,
+                policy.paused_until
+            ],
+        )?;
+        if let Some(until) = policy.paused_until.filter(|&until| until > now) {
+            tx.execute(
+                "INSERT INTO privacy_pauses(key_id, viewer, start, until) VALUES (?1, ?2, ?3, ?4)",
+                params![policy.key_id, policy.viewer, now, until],
+            )?;
+        }
+        tx.commit()
+    })
+    .await
+}
+
+/// Cuts short any pause for a device and viewer that's still going, so the stretch it covers
+/// ends now.
+fn internal_end_pause(
+    conn: &Connection,
+    key_id: u64,
+    viewer: &str,
+    now: u64,
+) -> Result<(), rusqlite::Error> {
+    conn.prepare_cached(
+        "UPDATE privacy_pauses SET until = ?3 WHERE key_id IS ?1 AND viewer IS ?2 AND until > ?3",
+    )?
+    .execute(params![key_id, viewer, now])?;
+    Ok(())
+}
+
+/// Whether a fix a device recorded at the given time is hidden from a viewer (or EVERYONE)
+/// because sharing was paused for them then.
+pub(crate) async fn paused_at(
+    pool: &Pool,
+    key_id: u64,
+    viewer: String,
+    time: u64,
+) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(&format!("SELECT {} FROM (SELECT ?3 AS time) l", PAUSED_FIX))?
+            .query_row(params![key_id, viewer, time], |row| row.get(0))
+    })
+    .await
+}
+
+/// Removes the privacy policy for a device and viewer, if there is one.
+pub(crate) async fn delete_privacy_policy(
+    pool: &Pool,
+    key_id: u64,
+    viewer: String,
+) -> Result<(), actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        internal_end_pause(&tx, key_id, &viewer, unixtime_now())?;
+        tx.execute(
+            "DELETE FROM privacy_policies WHERE key_id IS ?1 AND viewer IS ?2",
+            params![key_id, viewer],
+        )?;
+        tx.commit()
+    })
+    .await
+}
+
+/// Gathers up everything stored about a person, going by the username on their api keys and
+/// web_users rows. It's all read in one transaction, so it hangs together.
+pub(crate) async fn export_user(
+    pool: &Pool,
+    username: String,
+) -> Result<UserExport, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let export = {
+            let api_keys = tx
+                .prepare("SELECT id, username, issued, expiration, scopes FROM api_keys WHERE username IS ?1 ORDER BY id")?
+                .query_map(params![username], internal_api_key_info_from_row)?
+                .collect::<Result<_, _>>()?;
+            let web_users = tx
+                .prepare("SELECT id, username, email, issued, expiration, role FROM web_users WHERE username IS ?1 ORDER BY id")?
+                .query_map(params![username], |row| {
+                    Ok(WebUserInfo {
+                        id: row.get(0)?,
+                        username: row.get(1)?,
+                        email: row.get(2)?,
+                        issued: row.get(3)?,
+                        expiration: row.get(4)?,
+                        role: row.get(5)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            let locations = tx
+                .prepare("SELECT key_id, latitude, longitude, accuracy, time, rejected FROM locations WHERE key_id IN (SELECT id FROM api_keys WHERE username IS ?1) ORDER BY key_id, time")?
+                .query_map(params![username], |row| {
+                    Ok(StoredLocation {
+                        key_id: row.get(0)?,
+                        location: Location {
+                            latitude: row.get(1)?,
+                            longitude: row.get(2)?,
+                            accuracy: row.get(3)?,
+                            time: row.get(4)?,
+                        },
+                        rejected: row.get(5)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            let share_links = tx
+                .prepare("SELECT id, key_id, created_by, issued, expiration, precision_meters, trail_minutes FROM share_links WHERE created_by IS ?1 OR key_id IN (SELECT id FROM api_keys WHERE username IS ?1) ORDER BY id")?
+                .query_map(params![username], internal_share_link_from_row)?
+                .collect::<Result<_, _>>()?;
+            let privacy_policies = tx
+                .prepare("SELECT key_id, viewer, mode, grid_meters, paused_until FROM privacy_policies WHERE viewer IS ?1 OR key_id IN (SELECT id FROM api_keys WHERE username IS ?1) ORDER BY key_id, viewer")?
+                .query_map(params![username], internal_privacy_policy_from_row)?
+                .collect::<Result<_, _>>()?;
+            let privacy_pauses = tx
+                .prepare("SELECT key_id, viewer, start, until FROM privacy_pauses WHERE viewer IS ?1 OR key_id IN (SELECT id FROM api_keys WHERE username IS ?1) ORDER BY key_id, start")?
+                .query_map(params![username], |row| {
+                    Ok(PrivacyPause {
+                        key_id: row.get(0)?,
+                        viewer: row.get(1)?,
+                        start: row.get(2)?,
+                        until: row.get(3)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            let groups = tx
+                .prepare("SELECT g.id, g.name, NULL FROM group_members m JOIN groups g ON g.id IS m.group_id WHERE m.username IS ?1 UNION ALL SELECT g.id, g.name, d.key_id FROM group_devices d JOIN groups g ON g.id IS d.group_id WHERE d.key_id IN (SELECT id FROM api_keys WHERE username IS ?1) ORDER BY 1, 3")?
+                .query_map(params![username], |row| {
+                    Ok(GroupMembership {
+                        group_id: row.get(0)?,
+                        name: row.get(1)?,
+                        key_id: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            let key_ips = tx
+                .prepare("SELECT key_id, ip, first_seen FROM key_ips WHERE key_id IN (SELECT id FROM api_keys WHERE username IS ?1) ORDER BY key_id, first_seen")?
+                .query_map(params![username], |row| {
+                    Ok(KeyIp {
+                        key_id: row.get(0)?,
+                        ip: row.get(1)?,
+                        first_seen: row.get(2)?,
+                    })
+                })?
+                .collect::<Result<_, _>>()?;
+            // Only used ones, whose codes are no good to anyone any more.
+            let invites = tx
+                .prepare(&format!("SELECT {} FROM invites WHERE redeemed IS NOT NULL AND (username IS ?1 OR redeemed_email IN (SELECT email FROM web_users WHERE username IS ?1)) ORDER BY id", INVITE_COLUMNS))?
+                .query_map(params![username], internal_invite_from_row)?
+                .collect::<Result<_, _>>()?;
+            let audit_log = tx
+                .prepare(&format!("SELECT {} FROM audit_log WHERE actor IS ?1 ORDER BY id", AUDIT_COLUMNS))?
+                .query_map(params![username], internal_audit_entry_from_row)?
+                .collect::<Result<_, _>>()?;
+            UserExport {
+                username,
+                api_keys,
+                web_users,
+                locations,
+                share_links,
+                privacy_policies,
+                privacy_pauses,
+                groups,
+                key_ips,
+                invites,
+                audit_log,
+            }
+        };
+        tx.finish()?;
+        Ok(export)
+    })
+    .await
+}
+
+/// Deletes everything stored about a person, all in one transaction. Returns the api_key ids
+/// that went, so the caller can forget about them in memory too.
+pub(crate) async fn delete_user(
+    pool: &Pool,
+    username: String,
+) -> Result<DeletedUser, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let key_ids = tx
+            .prepare("SELECT id FROM api_keys WHERE username IS ?1")?
+            .query_map(params![username], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        let locations = tx.execute(
+            "DELETE FROM locations WHERE key_id IN (SELECT id FROM api_keys WHERE username IS ?1)",
+            params![username],
+        )?;
+        let share_links = tx.execute(
+            "DELETE FROM share_links WHERE created_by IS ?1 OR key_id IN (SELECT id FROM api_keys WHERE username IS ?1)",
+            params![username],
+        )?;
+        let privacy_policies = tx.execute(
+            "DELETE FROM privacy_policies WHERE viewer IS ?1 OR key_id IN (SELECT id FROM api_keys WHERE username IS ?1)",
+            params![username],
+        )?;
+        tx.execute(
+            "DELETE FROM privacy_pauses WHERE viewer IS ?1 OR key_id IN (SELECT id FROM api_keys WHERE username IS ?1)",
+            params![username],
+        )?;
+        tx.execute(
+            "DELETE FROM key_ips WHERE key_id IN (SELECT id FROM api_keys WHERE username IS ?1)",
+            params![username],
+        )?;
+        tx.execute(
+            "DELETE FROM group_devices WHERE key_id IN (SELECT id FROM api_keys WHERE username IS ?1)",
+            params![username],
+        )?;
+        // Their email turns up in the details of sign-ins and invites, and in failed attempts
+        // that couldn't be pinned on anyone. Blank it out, and keep what they did themselves
+        // without their name, addresses or user agents on it.
+        let emails: Vec<String> = tx
+            .prepare("SELECT email FROM web_users WHERE username IS ?1")?
+            .query_map(params![username], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        for email in &emails {
+            tx.execute(
+                "UPDATE audit_log SET detail = replace(detail, ?1, '(deleted)') WHERE instr(detail, ?1) > 0",
+                params![email],
+            )?;
+        }
+        tx.execute(
+            "UPDATE audit_log SET actor = ?2, ip = NULL, user_agent = NULL WHERE actor IS ?1",
+            params![username, DELETED],
+        )?;
+        tx.execute(
+            "UPDATE invites SET redeemed_email = NULL WHERE username IS ?1 OR redeemed_email IN (SELECT email FROM web_users WHERE username IS ?1)",
+            params![username],
+        )?;
+        let api_keys = tx.execute("DELETE FROM api_keys WHERE username IS ?1", params![username])?;
+        let web_users = tx.execute("DELETE FROM web_users WHERE username IS ?1", params![username])?;
+        tx.execute("DELETE FROM people WHERE name IS ?1", params![username])?;
+        tx.execute("DELETE FROM group_members WHERE username IS ?1", params![username])?;
+        tx.execute("DELETE FROM sessions WHERE username IS ?1", params![username])?;
+        tx.commit()?;
+        Ok(DeletedUser {
+            key_ids,
+            api_keys,
+            web_users,
+            locations,
+            share_links,
+            privacy_policies,
+        })
+    })
+    .await
+}
+
+/// Lists every api key, expired or not, without the keys themselves.
+pub(crate) async fn list_api_keys(pool: &Pool) -> Result<Vec<ApiKeyInfo>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT id, username, issued, expiration, scopes FROM api_keys ORDER BY id",
+        )?;
+        let rows = statement.query_map([], internal_api_key_info_from_row)?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Stores a new api key, and returns its id. The scopes are space-separated.
+pub(crate) async fn insert_api_key(
+    pool: &Pool,
+    username: String,
+    key_base64: String,
+    scopes: String,
+    expiration: u64,
+) -> Result<u64, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let person_id = internal_person_id(&tx, &username)?;
+        tx.execute(
+            "INSERT INTO api_keys(username, key_base64, issued, expiration, scopes, person_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![username, key_base64, unixtime_now(), expiration, scopes, person_id],
+        )?;
+        let id = tx.last_insert_rowid() as u64;
+        tx.commit()?;
+        Ok(id)
+    })
+    .await
+}
+
+/// Replaces an api key's scopes, which are space-separated. Returns the username, or None if
+/// there's no such key.
+pub(crate) async fn set_key_scopes(
+    pool: &Pool,
+    id: u64,
+    scopes: String,
+) -> Result<Option<String>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let username: Option<String> = tx
+            .query_row(
+                "SELECT username FROM api_keys WHERE id IS ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        tx.execute(
+            "UPDATE api_keys SET scopes = ?2 WHERE id IS ?1",
+            params![id, scopes],
+        )?;
+        tx.commit()?;
+        Ok(username)
+    })
+    .await
+}
+
+/// Lists every web_user, expired or not.
+pub(crate) async fn list_web_users(pool: &Pool) -> Result<Vec<WebUserInfo>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT id, username, email, issued, expiration, role FROM web_users ORDER BY id",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok(WebUserInfo {
+                id: row.get(0)?,
+                username: row.get(1)?,
+                email: row.get(2)?,
+                issued: row.get(3)?,
+                expiration: row.get(4)?,
+                role: row.get(5)?,
+            })
+        })?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Stores a new web_user, and returns its id.
+pub(crate) async fn insert_web_user(
+    pool: &Pool,
+    username: String,
+    email: String,
+    role: String,
+    expiration: u64,
+) -> Result<u64, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "INSERT INTO web_users(username, email, issued, expiration, role) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?
+        .execute(params![username, email, unixtime_now(), expiration, role])?;
+        Ok(conn.last_insert_rowid() as u64)
+    })
+    .await
+}
+
+/// The tables that admins manage rows of. Both have an id, a username and an expiration.
+#[derive(Clone, Copy)]
+pub(crate) enum Managed {
+    ApiKeys,
+    WebUsers,
+}
+
+impl Managed {
+    fn table(self) -> &'static str {
+        match self {
+            Managed::ApiKeys => "api_keys",
+            Managed::WebUsers => "web_users",
+        }
+    }
+}
+
+/// Pushes a row's expiration back by `secs`, counting from now if it's already expired.
+/// Returns the username and the new expiration, or None if there's no such row.
+pub(crate) async fn extend_expiration(
+    pool: &Pool,
+    managed: Managed,
+    id: u64,
+    secs: u64,
+) -> Result<Option<(String, u64)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let row: Option<(String, u64)> = tx
+            .query_row(
+                &format!(
+                    "SELECT username, expiration FROM {} WHERE id IS ?1",
+                    managed.table()
+                ),
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let Some((username, expiration)) = row else {
+            return Ok(None);
+        };
+        let expiration = expiration.max(unixtime_now()) + secs;
+        tx.execute(
+            &format!(
+                "UPDATE {} SET expiration = ?2 WHERE id IS ?1",
+                managed.table()
+            ),
+            params![id, expiration],
+        )?;
+        tx.commit()?;
+        Ok(Some((username, expiration)))
+    })
+    .await
+}
+
+/// Expires a row now, unless it's already expired. The row itself stays, so the history tied
+/// to it does too. Returns the username, or None if there's no such row.
+pub(crate) async fn revoke(
+    pool: &Pool,
+    managed: Managed,
+    id: u64,
+) -> Result<Option<String>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let username: Option<String> = tx
+            .query_row(
+                &format!("SELECT username FROM {} WHERE id IS ?1", managed.table()),
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        tx.execute(
+            &format!(
+                "UPDATE {} SET expiration = MIN(expiration, ?2) WHERE id IS ?1",
+                managed.table()
+            ),
+            params![id, unixtime_now()],
+        )?;
+        tx.commit()?;
+        Ok(username)
+    })
+    .await
+}
+
+/// Changes the username on a row. A web_user takes everything that goes by their name with
+/// them, including any other web_users rows they have for other emails. Returns the old name,
+/// or None if there's no such row.
+pub(crate) async fn rename(
+    pool: &Pool,
+    managed: Managed,
+    id: u64,
+    username: String,
+) -> Result<Option<String>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let old: Option<String> = tx
+            .query_row(
+                &format!("SELECT username FROM {} WHERE id IS ?1", managed.table()),
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match (managed, &old) {
+            // A device that's renamed belongs to whoever it's named for now.
+            (Managed::ApiKeys, Some(_)) => {
+                let person_id = internal_person_id(&tx, &username)?;
+                tx.execute(
+                    "UPDATE api_keys SET username = ?2, person_id = ?3 WHERE id IS ?1",
+                    params![id, username, person_id],
+                )?;
+            }
+            // Otherwise whoever gets the old name next would be in their groups, and see
+            // what was shared with them.
+            (Managed::WebUsers, Some(old)) => {
+                for statement in [
+                    "UPDATE web_users SET username = ?2 WHERE username IS ?1",
+                    "UPDATE group_members SET username = ?2 WHERE username IS ?1",
+                    "UPDATE privacy_policies SET viewer = ?2 WHERE viewer IS ?1",
+                    "UPDATE privacy_pauses SET viewer = ?2 WHERE viewer IS ?1",
+                    "UPDATE share_links SET created_by = ?2 WHERE created_by IS ?1",
+                ] {
+                    tx.execute(statement, params![old, username])?;
+                }
+            }
+            (_, None) => {}
+        }
+        tx.commit()?;
+        Ok(old)
+    })
+    .await
+}
+
+/// Appends an entry to the audit log. If `collapse_since` is set and the same event came from
+/// the same IP at or after then, that entry's count goes up instead.
+pub(crate) async fn insert_audit_entry(
+    pool: &Pool,
+    entry: AuditEntry,
+    collapse_since: Option<u64>,
+) -> Result<(), actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        if let (Some(since), Some(ip)) = (collapse_since, &entry.ip) {
+            let bumped = tx
+                .prepare_cached(
+                    "UPDATE audit_log SET count = count + 1 WHERE id IS (SELECT id FROM audit_log WHERE time >= ?3 AND event IS ?1 AND ip IS ?2 ORDER BY id DESC LIMIT 1)",
+                )?
+                .execute(params![entry.event, ip, since])?;
+            if bumped > 0 {
+                return tx.commit();
+            }
+        }
+        tx.prepare_cached(
+            "INSERT INTO audit_log(time, actor, ip, user_agent, event, detail, count) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?
+        .execute(params![
+            entry.time,
+            entry.actor,
+            entry.ip,
+            entry.user_agent,
+            entry.event,
+            entry.detail,
+            entry.count
+        ])?;
+        tx.commit()
+    })
+    .await
+}
+
+/// Deletes up to `limit` audit log entries from before `before`. Returns how many went.
+pub(crate) async fn delete_audit_entries_before(
+    pool: &Pool,
+    before: u64,
+    limit: u64,
+) -> Result<usize, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "DELETE FROM audit_log WHERE id IN (SELECT id FROM audit_log WHERE time < ?1 LIMIT ?2)",
+        )?
+        .execute(params![before, limit])
+    })
+    .await
+}
+
+/// Notes that an api key was used from an address. Returns whether that's the first time.
+pub(crate) async fn note_key_ip(
+    pool: &Pool,
+    key_id: u64,
+    ip: String,
+) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let added = conn
+            .prepare_cached(
+                "INSERT OR IGNORE INTO key_ips(key_id, ip, first_seen) VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![key_id, ip, unixtime_now()])?;
+        Ok(added > 0)
+    })
+    .await
+}
+
+/// Looks through the audit log, newest first. Any of the filters can be left out. Events match
+/// exactly or by prefix, so "auth.fail" gets every kind of failure.
+pub(crate) async fn list_audit_entries(
+    pool: &Pool,
+    filter: AuditFilter,
+) -> Result<Vec<AuditEntry>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(&format!(
+            "SELECT {} FROM audit_log WHERE (?1 IS NULL OR event IS ?1 OR event LIKE ?1 || '.%') AND (?2 IS NULL OR actor IS ?2) AND (?3 IS NULL OR ip IS ?3) AND time >= ?4 AND time < ?5 AND id < ?6 ORDER BY id DESC LIMIT ?7",
+            AUDIT_COLUMNS
+        ))?;
+        let rows = statement.query_map(
+            params![
+                filter.event,
+                filter.actor,
+                filter.ip,
+                filter.since,
+                filter.until,
+                filter.before_id,
+                filter.limit
+            ],
+            internal_audit_entry_from_row,
+        )?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Lists everyone with a device whose api key is still good, along with those devices.
+pub(crate) async fn list_people(pool: &Pool) -> Result<Vec<Person>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut statement = conn.prepare_cached(
+            "SELECT p.id, p.name, p.primary_key_id, k.id FROM people p JOIN api_keys k ON k.person_id IS p.id WHERE k.expiration > ?1 ORDER BY p.id, k.id",
+        )?;
+        let mut rows = statement.query(params![unixtime_now()])?;
+        let mut people: Vec<Person> = Vec::new();
+        while let Some(row) = rows.next()? {
+            let id: u64 = row.get(0)?;
+            if people.last().map(|p| p.id) != Some(id) {
+                people.push(Person {
+                    id,
+                    name: row.get(1)?,
+                    primary_key_id: row.get(2)?,
+                    key_ids: Vec::new(),
+                });
+            }
+            people.last_mut().unwrap().key_ids.push(row.get(3)?);
+        }
+        Ok(people)
+    })
+    .await
+}
+
+/// Makes an api key its person's primary device.
+pub(crate) async fn set_primary_device(pool: &Pool, key_id: u64) -> Result<(), actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "UPDATE people SET primary_key_id = ?1 WHERE id IS (SELECT person_id FROM api_keys WHERE id IS ?1)",
+        )?
+        .execute(params![key_id])?;
+        Ok(())
+    })
+    .await
+}
+
+/// The condition for a viewer (?1) getting to see the api key k: it's theirs, it's in a group
+/// they're in, or neither it nor they are in any group.
+const VISIBLE_KEY: &str = "(k.username IS ?1 \
+    OR (NOT EXISTS (SELECT 1 FROM group_devices gd WHERE gd.key_id IS k.id) \
+        AND NOT EXISTS (SELECT 1 FROM group_members gm WHERE gm.username IS ?1)) \
+    OR EXISTS (SELECT 1 FROM group_devices gd JOIN group_members gm ON gm.group_id IS gd.group_id WHERE gd.key_id IS k.id AND gm.username IS ?1))";
+
+/// Gets the ids of the api keys a viewer gets to see, out of the ones in a group if one's given.
+pub(crate) async fn visible_key_ids(
+    pool: &Pool,
+    viewer: String,
+    group_id: Option<u64>,
+) -> Result<HashSet<u64>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        match group_id {
+            Some(group_id) => conn
+                .prepare_cached(&format!(
+                    "SELECT k.id FROM api_keys k JOIN group_devices g ON g.key_id IS k.id WHERE g.group_id IS ?2 AND {}",
+                    VISIBLE_KEY
+                ))?
+                .query_map(params![viewer, group_id], |row| row.get(0))?
+                .collect(),
+            None => conn
+                .prepare_cached(&format!("SELECT k.id FROM api_keys k WHERE {}", VISIBLE_KEY))?
+                .query_map(params![viewer], |row| row.get(0))?
+                .collect(),
+        }
+    })
+    .await
+}
+
+/// Checks whether a viewer gets to see an api key, going by the groups they're in.
+pub(crate) async fn can_see_key(
+    pool: &Pool,
+    viewer: String,
+    key_id: u64,
+) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(&format!(
+            "SELECT EXISTS (SELECT 1 FROM api_keys k WHERE k.id IS ?2 AND {})",
+            VISIBLE_KEY
+        ))?
+        .query_row(params![viewer, key_id], |row| row.get(0))
+    })
+    .await
+}
+
+/// Lists every group, with who and what's in it.
+pub(crate) async fn list_groups(pool: &Pool) -> Result<Vec<Group>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let mut groups = conn
+            .prepare_cached("SELECT id, name FROM groups ORDER BY id")?
+            .query_map([], |row| {
+                Ok(Group {
+                    id: row.get(0)?,
+                    name: row.get(1)?,
+                    members: Vec::new(),
+                    devices: Vec::new(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for group in groups.iter_mut() {
+            group.members = conn
+                .prepare_cached(
+                    "SELECT username FROM group_members WHERE group_id IS ?1 ORDER BY username",
+                )?
+                .query_map(params![group.id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+            group.devices = conn
+                .prepare_cached(
+                    "SELECT key_id FROM group_devices WHERE group_id IS ?1 ORDER BY key_id",
+                )?
+                .query_map(params![group.id], |row| row.get(0))?
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(groups)
+    })
+    .await
+}
+
+/// Lists the groups a web_user is in, as ids and names.
+pub(crate) async fn list_member_groups(
+    pool: &Pool,
+    username: String,
+) -> Result<Vec<(u64, String)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "SELECT g.id, g.name FROM groups g JOIN group_members m ON m.group_id IS g.id WHERE m.username IS ?1 ORDER BY g.id",
+        )?
+        .query_map(params![username], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect()
+    })
+    .await
+}
+
+/// Makes a new group, and returns its id, or None if there's already one by that name.
+pub(crate) async fn insert_group(
+    pool: &Pool,
+    name: String,
+) -> Result<Option<u64>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let inserted = conn
+            .prepare_cached("INSERT OR IGNORE INTO groups(name) VALUES (?1)")?
+            .execute(params![name])?;
+        Ok((inserted > 0).then(|| conn.last_insert_rowid() as u64))
+    })
+    .await
+}
+
+/// Deletes a group and everything's membership in it. Returns its name, or None if there's no
+/// such group.
+pub(crate) async fn delete_group(pool: &Pool, id: u64) -> Result<Option<String>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let name: Option<String> = tx
+            .query_row(
+                "SELECT name FROM groups WHERE id IS ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        tx.execute(
+            "DELETE FROM group_members WHERE group_id IS ?1",
+            params![id],
+        )?;
+        tx.execute(
+            "DELETE FROM group_devices WHERE group_id IS ?1",
+            params![id],
+        )?;
+        tx.execute("DELETE FROM groups WHERE id IS ?1", params![id])?;
+        tx.commit()?;
+        Ok(name)
+    })
+    .await
+}
+
+/// Puts a web_user or device in a group, or takes it out. Returns false if there's no such
+/// group, or no such device.
+pub(crate) async fn set_group_membership(
+    pool: &Pool,
+    group_id: u64,
+    member: GroupMember,
+    add: bool,
+) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let group_exists: bool = tx.query_row(
+            "SELECT EXISTS (SELECT 1 FROM groups WHERE id IS ?1)",
+            params![group_id],
+            |row| row.get(0),
+        )?;
+        if !group_exists {
+            return Ok(false);
+        }
+        match member {
+            GroupMember::User(username) if add => tx.execute(
+                "INSERT OR IGNORE INTO group_members(group_id, username) VALUES (?1, ?2)",
+                params![group_id, username],
+            )?,
+            GroupMember::User(username) => tx.execute(
+                "DELETE FROM group_members WHERE group_id IS ?1 AND username IS ?2",
+                params![group_id, username],
+            )?,
+            GroupMember::Device(key_id) => {
+                let key_exists: bool = tx.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM api_keys WHERE id IS ?1)",
+                    params![key_id],
+                    |row| row.get(0),
+                )?;
+                if !key_exists {
+                    return Ok(false);
+                }
+                if add {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO group_devices(group_id, key_id) VALUES (?1, ?2)",
+                        params![group_id, key_id],
+                    )?
+                } else {
+                    tx.execute(
+                        "DELETE FROM group_devices WHERE group_id IS ?1 AND key_id IS ?2",
+                        params![group_id, key_id],
+                    )?
+                }
+            }
+        };
+        tx.commit()?;
+        Ok(true)
+    })
+    .await
+}
+
+/// Stores a new invite, and returns its id, or None if there's no such group.
+pub(crate) async fn insert_invite(
+    pool: &Pool,
+    invite: Invite,
+) -> Result<Option<u64>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let inserted = conn.prepare_cached(
+            "INSERT INTO invites(code, group_id, username, user_days, device_days, created_by, issued, expiration) SELECT ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8 WHERE EXISTS (SELECT 1 FROM groups WHERE id IS ?2)",
+        )?
+        .execute(params![
+            invite.code,
+            invite.group_id,
+            invite.username,
+            invite.user_days,
+            invite.device_days,
+            invite.created_by,
+            invite.issued,
+            invite.expiration
+        ])?;
+        Ok((inserted > 0).then(|| conn.last_insert_rowid() as u64))
+    })
+    .await
+}
+
+/// Lists every invite, used or not.
+pub(crate) async fn list_invites(pool: &Pool) -> Result<Vec<Invite>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(&format!(
+            "SELECT {} FROM invites ORDER BY id",
+            INVITE_COLUMNS
+        ))?
+        .query_map([], internal_invite_from_row)?
+        .collect()
+    })
+    .await
+}
+
+/// Gets an invite by its code, if it hasn't been used or expired.
+pub(crate) async fn get_open_invite(
+    pool: &Pool,
+    code: String,
+) -> Result<Option<Invite>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(&format!(
+            "SELECT {} FROM invites WHERE code IS ?1 AND redeemed IS NULL AND expiration > ?2",
+            INVITE_COLUMNS
+        ))?
+        .query_row(params![code, unixtime_now()], internal_invite_from_row)
+        .optional()
+    })
+    .await
+}
+
+/// Makes an invite stop working, if it hasn't already. Returns false if there's no such invite.
+pub(crate) async fn revoke_invite(pool: &Pool, id: u64) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let changed = conn
+            .prepare_cached("UPDATE invites SET expiration = MIN(expiration, ?2) WHERE id IS ?1")?
+            .execute(params![id, unixtime_now()])?;
+        Ok(changed > 0)
+    })
+    .await
+}
+
+/// Checks whether there's a web_user with a username, expired or not.
+pub(crate) async fn web_user_exists(
+    pool: &Pool,
+    username: String,
+) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached("SELECT EXISTS (SELECT 1 FROM web_users WHERE username IS ?1)")?
+            .query_row(params![username], |row| row.get(0))
+    })
+    .await
+}
+
+/// Checks whether there's a web_user with an email, expired or not.
+pub(crate) async fn email_in_use(pool: &Pool, email: String) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached("SELECT EXISTS (SELECT 1 FROM web_users WHERE email IS ?1)")?
+            .query_row(params![email], |row| row.get(0))
+    })
+    .await
+}
+
+/// Checks whether any web_user or api key has a username, or anything else still goes by it.
+pub(crate) async fn username_in_use(
+    pool: &Pool,
+    username: String,
+) -> Result<bool, actix_web::Error> {
+    with_conn_internal(pool, move |conn| internal_username_in_use(conn, &username)).await
+}
+
+fn internal_username_in_use(conn: &Connection, username: &str) -> Result<bool, rusqlite::Error> {
+    conn.prepare_cached(
+        "SELECT EXISTS (SELECT 1 FROM web_users WHERE username IS ?1) OR EXISTS (SELECT 1 FROM api_keys WHERE username IS ?1) OR EXISTS (SELECT 1 FROM group_members WHERE username IS ?1) OR EXISTS (SELECT 1 FROM privacy_policies WHERE viewer IS ?1) OR EXISTS (SELECT 1 FROM privacy_pauses WHERE viewer IS ?1) OR EXISTS (SELECT 1 FROM share_links WHERE created_by IS ?1)",
+    )?
+    .query_row(params![username], |row| row.get(0))
+}
+
+/// Uses up an invite for someone who's signed in with the given email: makes them a web_user,
+/// puts them in the invite's group, and gives them a device with the given key if the invite
+/// comes with one. Returns None if the invite's been used or has expired, or if its username has
+/// been taken since it was made.
+pub(crate) async fn redeem_invite(
+    pool: &Pool,
+    code: String,
+    email: String,
+    key_base64: String,
+) -> Result<Option<Redeemed>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let now = unixtime_now();
+        let invite = match tx
+            .query_row(
+                &format!(
+                    "SELECT {} FROM invites WHERE code IS ?1 AND redeemed IS NULL AND expiration > ?2",
+                    INVITE_COLUMNS
+                ),
+                params![code, now],
+                internal_invite_from_row,
+            )
+            .optional()?
+        {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        // Someone may have taken the username since the invite was made. Using it anyway would
+        // hand the invitee everything of theirs.
+        if internal_username_in_use(&tx, &invite.username)? {
+            return Ok(None);
+        }
+
+        tx.execute(
+            "INSERT INTO web_users(username, email, issued, expiration, role) VALUES (?1, ?2, ?3, ?4, 'user')",
+            params![invite.username, email, now, now + invite.user_days * SECS_PER_DAY],
+        )?;
+        tx.execute(
+            "INSERT OR IGNORE INTO group_members(group_id, username) VALUES (?1, ?2)",
+            params![invite.group_id, invite.username],
+        )?;
+        let key_id = match invite.device_days {
+            Some(days) => {
+                let person_id = internal_person_id(&tx, &invite.username)?;
+                tx.execute(
+                    "INSERT INTO api_keys(username, key_base64, issued, expiration, scopes, person_id) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        invite.username,
+                        key_base64,
+                        now,
+                        now + days * SECS_PER_DAY,
+                        SCOPE_WRITE,
+                        person_id
+                    ],
+                )?;
+                let key_id = tx.last_insert_rowid() as u64;
+                tx.execute(
+                    "INSERT OR IGNORE INTO group_devices(group_id, key_id) VALUES (?1, ?2)",
+                    params![invite.group_id, key_id],
+                )?;
+                Some(key_id)
+            }
+            None => None,
+        };
+        tx.execute(
+            "UPDATE invites SET redeemed = ?2, redeemed_email = ?3, key_id = ?4 WHERE id IS ?1",
+            params![invite.id, now, email, key_id],
+        )?;
+        tx.commit()?;
+        Ok(Some(Redeemed {
+            invite_id: invite.id,
+            username: invite.username,
+            group_id: invite.group_id,
+            key_id,
+        }))
+    })
+    .await
+}
+
+/// Gets the device key a web_user's invite made for them, if they used it after the given
+/// time: its id, the key itself, and when it expires.
+pub(crate) async fn get_invite_key(
+    pool: &Pool,
+    username: String,
+    redeemed_since: u64,
+) -> Result<Option<(u64, String, u64)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        conn.prepare_cached(
+            "SELECT k.id, k.key_base64, k.expiration FROM invites i JOIN api_keys k ON k.id IS i.key_id WHERE i.username IS ?1 AND i.redeemed > ?2 ORDER BY i.redeemed DESC LIMIT 1",
+        )?
+        .query_row(params![username, redeemed_since], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })
+        .optional()
+    })
+    .await
+}
+
+/// The invites columns, in the order internal_invite_from_row reads them.
+const INVITE_COLUMNS: &str = "id, code, group_id, username, user_days, device_days, created_by, issued, expiration, redeemed, redeemed_email, key_id";
+
+fn internal_invite_from_row(row: &rusqlite::Row<'_>) -> Result<Invite, rusqlite::Error> {
+    Ok(Invite {
+        id: row.get(0)?,
+        code: row.get(1)?,
+        group_id: row.get(2)?,
+        username: row.get(3)?,
+        user_days: row.get(4)?,
+        device_days: row.get(5)?,
+        created_by: row.get(6)?,
+        issued: row.get(7)?,
+        expiration: row.get(8)?,
+        redeemed: row.get(9)?,
+        redeemed_email: row.get(10)?,
+        key_id: row.get(11)?,
+    })
+}
+
+/// The audit_log columns, in the order internal_audit_entry_from_row reads them.
+const AUDIT_COLUMNS: &str = "id, time, actor, ip, user_agent, event, detail, count";
+
+/// Reads an audit log entry out of a row with AUDIT_COLUMNS in it.
+fn internal_audit_entry_from_row(row: &rusqlite::Row<'_>) -> Result<AuditEntry, rusqlite::Error> {
+    Ok(AuditEntry {
+        id: row.get(0)?,
+        time: row.get(1)?,
+        actor: row.get(2)?,
+        ip: row.get(3)?,
+        user_agent: row.get(4)?,
+        event: row.get(5)?,
+        detail: row.get(6)?,
+        count: row.get(7)?,
+    })
+}
+
+/// Finds the person with the given name, making them if there's nobody by that name yet, and
+/// returns their id.
+fn internal_person_id(conn: &Connection, name: &str) -> Result<u64, rusqlite::Error> {
+    conn.prepare_cached("INSERT OR IGNORE INTO people(name) VALUES (?1)")?
+        .execute(params![name])?;
+    conn.prepare_cached("SELECT id FROM people WHERE name IS ?1")?
+        .query_row(params![name], |row| row.get(0))
+}
+
+/// Reads a privacy policy out of a row, in the column order the privacy_policies queries above use.
+/// Modes we don't recognize are treated as exact, which is what having no policy means anyway.
+fn internal_privacy_policy_from_row(
+    row: &rusqlite::Row<'_>,
+) -> Result<PrivacyPolicy, rusqlite::Error> {
+    let mode: String = row.get(2)?;
+    let grid_meters: Option<f64> = row.get(3)?;
+    Ok(PrivacyPolicy {
+        key_id: row.get(0)?,
+        viewer: row.get(1)?,
+        precision: match (mode.as_str(), grid_meters) {
+            ("grid", Some(meters)) => Precision::Grid { meters },
+            ("city", _) => Precision::City,
+            _ => Precision::Exact,
+        },
+        paused_until: row.get(4)?,
+    })
+}
+
+/// Reads an api key's details out of a row, in the column order the api_keys queries above use.
+fn internal_api_key_info_from_row(row: &rusqlite::Row<'_>) -> Result<ApiKeyInfo, rusqlite::Error> {
+    Ok(ApiKeyInfo {
+        id: row.get(0)?,
+        username: row.get(1)?,
+        issued: row.get(2)?,
+        expiration: row.get(3)?,
+        scopes: parse_scopes(&row.get::<_, String>(4)?),
+    })
+}
+
+/// Reads a share link out of a row, in the column order the share_links queries above use.
+fn internal_share_link_from_row(row: &rusqlite::Row<'_>) -> Result<ShareLink, rusqlite::Error> {
+    Ok(ShareLink {
+        id: row.get(0)?,
+        key_id: row.get(1)?,
+        created_by: row.get(2)?,
+        issued: row.get(3)?,
+        expiration: row.get(4)?,
+        precision_meters: row.get(5)?,
+        trail_minutes: row.get(6)?,
+    })
 }
 
 /// Gets the first column of the first row of the results, if it exists, treating
@@ -48,15 +1501,96 @@ fn internal_get_one_string(mut rows: Rows<'_>) -> Result<Option<String>, rusqlit
     Ok(None)
 }
 
-/// Gets the first two columns of the first row of the results, if it exists, treating
-/// the first column as a u64 and the second column as a string.
-fn internal_get_u64_string(mut rows: Rows<'_>) -> Result<Option<(u64, String)>, rusqlite::Error> {
-    // Grab the first row if it exists, and try to get the first two columns' values.
-    if let Some(name) = rows.next()? {
-        return Ok(Some((name.get(0)?, name.get(1)?)));
-    }
-    // If no rows came back, return None.
-    Ok(None)
+/// Gets the newest accepted fix for each device whose api key is still good, along with
+/// the key's username.
+pub(crate) async fn latest_locations(
+    pool: &Pool,
+) -> Result<Vec<(u64, String, Location)>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        // SQLite fills the bare columns in from the row that MAX() picked.
+        let mut statement = conn.prepare_cached(
+            "SELECT l.key_id, k.username, l.latitude, l.longitude, l.accuracy, MAX(l.time) FROM locations l JOIN api_keys k ON k.id IS l.key_id WHERE l.rejected IS NULL AND k.expiration > ?1 GROUP BY l.key_id ORDER BY l.key_id",
+        )?;
+        let rows = statement.query_map(params![unixtime_now()], |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                Location {
+                    latitude: row.get(2)?,
+                    longitude: row.get(3)?,
+                    accuracy: row.get(4)?,
+                    time: row.get(5)?,
+                },
+            ))
+        })?;
+        rows.collect()
+    })
+    .await
+}
+
+/// Replaces the saved sessions with the given ones.
+pub(crate) async fn save_sessions(
+    pool: &Pool,
+    sessions: Vec<SavedSession>,
+) -> Result<(), actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM sessions", [])?;
+        {
+            let mut statement = tx.prepare(
+                "INSERT INTO sessions(token, username, issued, last_used) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for session in sessions {
+                statement.execute(params![
+                    session.token,
+                    session.username,
+                    session.issued,
+                    session.last_used
+                ])?;
+            }
+        }
+        tx.commit()
+    })
+    .await
+}
+
+/// Gets the saved sessions and forgets them, so they only ever come back to life once. Sessions
+/// of web_users that were deleted or expired while we were down are dropped.
+pub(crate) async fn take_sessions(pool: &Pool) -> Result<Vec<SavedSession>, actix_web::Error> {
+    with_conn_internal(pool, move |conn| {
+        let tx = conn.transaction()?;
+        let sessions = tx
+            .prepare(
+                "SELECT token, username, issued, last_used FROM sessions s
+                 WHERE EXISTS (SELECT 1 FROM web_users w
+                               WHERE w.username = s.username AND w.expiration > ?1)",
+            )?
+            .query_map([unixtime_now()], |row| {
+                Ok(SavedSession {
+                    token: row.get(0)?,
+                    username: row.get(1)?,
+                    issued: row.get(2)?,
+                    last_used: row.get(3)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        tx.execute("DELETE FROM sessions", [])?;
+        tx.commit()?;
+        Ok(sessions)
+    })
+    .await
+}
+
+/// The schema version that db/up.sql creates and the latest db/migrate-N.sql brings a database up to.
+pub(crate) const SCHEMA_VERSION: u32 = 19;
+
+/// Checks that the database answers at all, and gets its schema version.
+pub(crate) async fn schema_version(pool: &Pool) -> Result<u32, actix_web::Error> {
+    with_conn_internal(pool, |conn| {
+        conn.query_row("SELECT 1", [], |_| Ok(()))?;
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+    })
+    .await
 }
 
 /// Performs a query that takes two parameters, a string and a u64, and returns some arbitrary function of the resulting rows.
@@ -75,13 +1609,17 @@ where
 {
     // Grab a connection from the pool.
     let pool = pool.clone();
+    let wait = metrics().db_pool_wait_seconds.start_timer();
     let conn = web::block(move || pool.get())
         .await?
         .map_err(actix_web::error::ErrorInternalServerError)?;
+    wait.observe_duration();
 
     // rusqlite only has blocking methods, so we'll offload the remainder of this task
     // to the actix-web thread pool and asynchronously await its completion on this thread.
     web::block(move || {
+        let _timer = metrics().db_query_seconds.start_timer();
+
         // Parse the query, and cache the result.
         let mut statement = conn.prepare_cached(&query)?;
 
@@ -94,7 +1632,220 @@ where
     .map_err(actix_web::error::ErrorInternalServerError)
 }
 
+/// Runs some arbitrary function with a connection from the pool, on the actix-web thread pool.
+/// DO NOT USE THIS outside of this file! Same as query_internal, it's only here to cut down on repetition.
+async fn with_conn_internal<V, F>(pool: &Pool, do_with_conn: F) -> Result<V, actix_web::Error>
+where
+    F: FnOnce(&mut Connection) -> Result<V, rusqlite::Error> + Send + 'static,
+    V: Send + 'static,
+{
+    // Grab a connection from the pool.
+    let pool = pool.clone();
+    let wait = metrics().db_pool_wait_seconds.start_timer();
+    let mut conn = web::block(move || pool.get())
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    wait.observe_duration();
+
+    // Offload the blocking work, same as in query_internal.
+    web::block(move || {
+        let _timer = metrics().db_query_seconds.start_timer();
+        do_with_conn(&mut conn)
+    })
+    .await?
+    .map_err(actix_web::error::ErrorInternalServerError)
+}
+
 /// Constructs a new pool from the configured options.
 pub(crate) fn create_pool(config: &Config) -> Pool {
     Pool::new(SqliteConnectionManager::file(&config.db_path)).expect("Failed to open database.")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh database, made the way a new install's is.
+    fn test_pool() -> Pool {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        pool.get()
+            .unwrap()
+            .execute_batch(include_str!("../db/up.sql"))
+            .unwrap();
+        pool
+    }
+
+    fn add_web_user(pool: &Pool, username: &str, email: &str, expiration: u64) {
+        pool.get()
+            .unwrap()
+            .execute(
+                "INSERT INTO web_users(username, email, issued, expiration) VALUES (?1, ?2, 0, ?3)",
+                params![username, email, expiration],
+            )
+            .unwrap();
+    }
+
+    fn session(token: &str, username: &str) -> SavedSession {
+        let now = unixtime_now();
+        SavedSession {
+            token: token.to_string(),
+            username: username.to_string(),
+            issued: now,
+            last_used: now,
+        }
+    }
+
+    #[actix_web::test]
+    async fn sessions_of_gone_web_users_stay_gone() {
+        let pool = test_pool();
+        let later = unixtime_now() + SECS_PER_DAY;
+        add_web_user(&pool, "alice", "alice@example.com", later);
+        add_web_user(&pool, "bob", "bob@example.com", later);
+        add_web_user(&pool, "carol", "carol@example.com", 1);
+        let saved = ["alice", "bob", "carol"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| session(&i.to_string(), name))
+            .collect();
+        save_sessions(&pool, saved).await.unwrap();
+
+        // Bob's deleted while the server's down, and Carol's already expired.
+        delete_user(&pool, "bob".to_string()).await.unwrap();
+        let restored = take_sessions(&pool).await.unwrap();
+        let names: Vec<&str> = restored.iter().map(|s| s.username.as_str()).collect();
+        assert_eq!(names, ["alice"]);
+        // And they're only ever restored once.
+        assert!(take_sessions(&pool).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn export_and_delete_cover_everything() {
+        let pool = test_pool();
+        add_web_user(
+            &pool,
+            "alice",
+            "alice@example.com",
+            unixtime_now() + SECS_PER_DAY,
+        );
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO api_keys(id, username, key_base64, issued, expiration) VALUES (7, 'alice', 'k', 0, 9999999999);
+                 INSERT INTO groups(id, name) VALUES (1, 'family');
+                 INSERT INTO group_members(group_id, username) VALUES (1, 'alice');
+                 INSERT INTO group_devices(group_id, key_id) VALUES (1, 7);
+                 INSERT INTO key_ips(key_id, ip, first_seen) VALUES (7, '192.0.2.1', 5);
+                 INSERT INTO privacy_pauses(key_id, viewer, start, until) VALUES (7, '*', 1, 2);
+                 INSERT INTO invites(code, group_id, username, user_days, created_by, issued, expiration, redeemed, redeemed_email)
+                     VALUES ('c', 1, 'alice', 30, 'admin', 0, 10, 5, 'alice@example.com');
+                 INSERT INTO audit_log(time, actor, ip, user_agent, event, detail)
+                     VALUES (1, 'alice', '192.0.2.1', 'phone', 'session.create', 'alice@example.com signed in'),
+                            (2, 'anonymous', '192.0.2.9', 'curl', 'auth.fail.unknown_email', 'alice@example.com isn''t a web user'),
+                            (3, 'admin', '192.0.2.5', 'laptop', 'group.create', 'id 1 named family');",
+            )
+            .unwrap();
+
+        let export = export_user(&pool, "alice".to_string()).await.unwrap();
+        assert_eq!(export.groups.len(), 2);
+        assert_eq!(export.key_ips.len(), 1);
+        assert_eq!(export.privacy_pauses.len(), 1);
+        assert_eq!(export.invites.len(), 1);
+        assert_eq!(export.audit_log.len(), 1);
+
+        delete_user(&pool, "alice".to_string()).await.unwrap();
+        let conn = pool.get().unwrap();
+        let count = |sql: &str| -> u64 { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        for table in [
+            "group_members",
+            "group_devices",
+            "key_ips",
+            "privacy_pauses",
+        ] {
+            assert_eq!(
+                count(&format!("SELECT COUNT(*) FROM {}", table)),
+                0,
+                "{}",
+                table
+            );
+        }
+        assert_eq!(
+            count("SELECT COUNT(*) FROM invites WHERE redeemed_email IS NOT NULL"),
+            0
+        );
+        // The entries stay, without her name, email or addresses in them.
+        assert_eq!(count("SELECT COUNT(*) FROM audit_log"), 3);
+        assert_eq!(
+            count(
+                "SELECT COUNT(*) FROM audit_log WHERE actor IS 'alice' OR detail LIKE '%alice@%'"
+            ),
+            0
+        );
+        assert_eq!(
+            count("SELECT COUNT(*) FROM audit_log WHERE actor IS 'deleted' AND ip IS NULL"),
+            1
+        );
+    }
+
+    #[actix_web::test]
+    async fn renamed_web_users_take_their_things_with_them() {
+        let pool = test_pool();
+        let later = unixtime_now() + SECS_PER_DAY;
+        add_web_user(&pool, "alice", "alice@example.com", later);
+        add_web_user(&pool, "alice", "alice@work.example", later);
+        pool.get()
+            .unwrap()
+            .execute_batch(
+                "INSERT INTO groups(id, name) VALUES (1, 'family');
+                 INSERT INTO group_members(group_id, username) VALUES (1, 'alice');
+                 INSERT INTO privacy_policies(key_id, viewer, mode) VALUES (7, 'alice', 'exact');
+                 INSERT INTO privacy_pauses(key_id, viewer, start, until) VALUES (7, 'alice', 1, 2);
+                 INSERT INTO share_links(key_id, created_by, issued, expiration) VALUES (7, 'alice', 0, 10);",
+            )
+            .unwrap();
+
+        let old = rename(&pool, Managed::WebUsers, 1, "alicia".to_string())
+            .await
+            .unwrap();
+        assert_eq!(old.as_deref(), Some("alice"));
+        assert!(!username_in_use(&pool, "alice".to_string()).await.unwrap());
+        let conn = pool.get().unwrap();
+        let count = |sql: &str| -> u64 { conn.query_row(sql, [], |row| row.get(0)).unwrap() };
+        assert_eq!(
+            count("SELECT COUNT(*) FROM web_users WHERE username IS 'alicia'"),
+            2
+        );
+        assert_eq!(
+            count("SELECT COUNT(*) FROM group_members WHERE username IS 'alicia'"),
+            1
+        );
+        assert_eq!(
+            count("SELECT COUNT(*) FROM privacy_policies WHERE viewer IS 'alicia'"),
+            1
+        );
+        assert_eq!(
+            count("SELECT COUNT(*) FROM privacy_pauses WHERE viewer IS 'alicia'"),
+            1
+        );
+        assert_eq!(
+            count("SELECT COUNT(*) FROM share_links WHERE created_by IS 'alicia'"),
+            1
+        );
+    }
+
+    #[actix_web::test]
+    async fn leftovers_keep_a_name_taken() {
+        let pool = test_pool();
+        assert!(!username_in_use(&pool, "bob".to_string()).await.unwrap());
+        pool.get()
+            .unwrap()
+            .execute(
+                "INSERT INTO privacy_policies(key_id, viewer, mode) VALUES (7, 'bob', 'exact')",
+                [],
+            )
+            .unwrap();
+        assert!(username_in_use(&pool, "bob".to_string()).await.unwrap());
+    }
+}
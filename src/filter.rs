@@ -0,0 +1,317 @@
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::Serialize;
+
+use crate::{
+    config::FilterConfig,
+    geo::{haversine_meters, wrap_longitude},
+    location::Location,
+};
+
+/// Nobody's GPS is better than this, and it keeps the smoother from dividing by zero.
+const MIN_ACCURACY_METERS: f64 = 1.0;
+
+/// Why an incoming fix didn't make it through the filter.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RejectReason {
+    /// The fix's own accuracy estimate was worse than the configured threshold.
+    PoorAccuracy,
+    /// Getting here from the last good fix would have taken an impossible speed.
+    ImpossibleSpeed,
+    /// It was taken before the last good fix, so it's no news.
+    OutOfOrder,
+}
+
+impl RejectReason {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            RejectReason::PoorAccuracy => "poor_accuracy",
+            RejectReason::ImpossibleSpeed => "impossible_speed",
+            RejectReason::OutOfOrder => "out_of_order",
+        }
+    }
+}
+
+/// What we remember about a device's track between fixes.
+#[derive(Clone)]
+pub(crate) struct TrackState {
+    /// The last raw fix that passed the filter.
+    last_accepted: Location,
+    /// The smoothed position, which is what viewers get to see.
+    smoothed: Location,
+    /// The variance of the smoothed position, in meters squared.
+    variance: f64,
+    /// How many fixes have been folded in, so a commit can tell if another got in first.
+    fixes: u64,
+}
+
+/// What the filter made of an accepted fix: the device's track with it folded in, which only
+/// takes effect once it's committed.
+pub(crate) struct Filtered {
+    fix: Location,
+    state: TrackState,
+}
+
+impl Filtered {
+    /// Makes this the device's track, once the fix has been stored, and returns the display
+    /// position. If another fix was committed in the meantime, this one's folded in on top of
+    /// it instead, or left out if it doesn't fit there any more.
+    pub(crate) fn commit(
+        self,
+        tracks: &DashMap<u64, TrackState>,
+        key_id: u64,
+        config: &FilterConfig,
+    ) -> Location {
+        match tracks.entry(key_id) {
+            Entry::Occupied(mut entry) => {
+                if entry.get().fixes + 1 == self.state.fixes {
+                    entry.insert(self.state);
+                } else if let Ok(state) = advance(Some(entry.get()), &self.fix, config) {
+                    entry.insert(state);
+                }
+                entry.get().smoothed.clone()
+            }
+            // Either it's the device's first fix, or the track's been dropped since and starts
+            // again from this one.
+            Entry::Vacant(entry) => {
+                let state = advance(None, &self.fix, config).unwrap_or(self.state);
+                entry.insert(state).smoothed.clone()
+            }
+        }
+    }
+}
+
+/// Runs an incoming fix through the filter for its device. Nothing changes until the result is
+/// committed, so a fix that doesn't get stored doesn't move the track either.
+pub(crate) fn filter_fix(
+    tracks: &DashMap<u64, TrackState>,
+    key_id: u64,
+    fix: &Location,
+    config: &FilterConfig,
+) -> Result<Filtered, RejectReason> {
+    let state = advance(tracks.get(&key_id).as_deref(), fix, config)?;
+    Ok(Filtered {
+        fix: fix.clone(),
+        state,
+    })
+}
+
+/// Checks a fix against a device's track, and works out what the track would be with it folded
+/// in.
+fn advance(
+    track: Option<&TrackState>,
+    fix: &Location,
+    config: &FilterConfig,
+) -> Result<TrackState, RejectReason> {
+    if fix.accuracy > config.max_accuracy_meters {
+        return Err(RejectReason::PoorAccuracy);
+    }
+    let accuracy = fix.accuracy.max(MIN_ACCURACY_METERS);
+
+    let mut state = match track {
+        Some(state) => state.clone(),
+        None => {
+            // First fix we've seen from this device: nothing to compare against, take it as-is.
+            let smoothed = Location {
+                accuracy,
+                ..fix.clone()
+            };
+            return Ok(TrackState {
+                last_accepted: fix.clone(),
+                smoothed,
+                variance: accuracy * accuracy,
+                fixes: 1,
+            });
+        }
+    };
+
+    // Work out how fast it'd have to have moved since the last good fix. Both fixes could be
+    // off by their accuracy, so give it the benefit of the doubt.
+    let last = &state.last_accepted;
+    if fix.time < last.time {
+        return Err(RejectReason::OutOfOrder);
+    }
+    let distance = haversine_meters(last.latitude, last.longitude, fix.latitude, fix.longitude);
+    let slack = last.accuracy.max(0.0) + fix.accuracy.max(0.0);
+    let elapsed = fix.time.saturating_sub(last.time).max(1) as f64;
+    if (distance - slack).max(0.0) / elapsed > config.max_speed_mps {
+        return Err(RejectReason::ImpossibleSpeed);
+    }
+
+    // A simple Kalman filter, treating the device as standing still with some random drift.
+    // The uncertainty grows with the time since the last estimate, then shrinks as we fold in
+    // the new fix, weighted by how much we trust it compared to what we had.
+    let since_smoothed = fix.time.saturating_sub(state.smoothed.time) as f64;
+    let variance =
+        state.variance + since_smoothed * config.smoothing_speed_mps * config.smoothing_speed_mps;
+    let gain = variance / (variance + accuracy * accuracy);
+    let smoothed = &mut state.smoothed;
+    smoothed.latitude += gain * (fix.latitude - smoothed.latitude);
+    // The short way round, in case the antimeridian is in between.
+    smoothed.longitude = wrap_longitude(
+        smoothed.longitude + gain * wrap_longitude(fix.longitude - smoothed.longitude),
+    );
+    smoothed.time = fix.time;
+    state.variance = (1.0 - gain) * variance;
+    // Report the smoothed accuracy in the same units as the raw one.
+    smoothed.accuracy = state.variance.sqrt();
+
+    state.last_accepted = fix.clone();
+    state.fixes += 1;
+    Ok(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(latitude: f64, longitude: f64, accuracy: f64, time: u64) -> Location {
+        Location {
+            latitude,
+            longitude,
+            accuracy,
+            time,
+        }
+    }
+
+    /// Filters a fix and commits it if it got through.
+    fn feed(tracks: &DashMap<u64, TrackState>, fix: Location) -> Result<Location, RejectReason> {
+        let config = FilterConfig::default();
+        filter_fix(tracks, 1, &fix, &config).map(|f| f.commit(tracks, 1, &config))
+    }
+
+    #[test]
+    fn first_fix_is_taken_as_is() {
+        let tracks = DashMap::new();
+        let shown = feed(&tracks, at(51.5, -0.1, 0.0, 1000)).unwrap();
+        assert_eq!((shown.latitude, shown.longitude), (51.5, -0.1));
+        // Accuracy is never claimed to be better than a meter.
+        assert_eq!(shown.accuracy, MIN_ACCURACY_METERS);
+    }
+
+    #[test]
+    fn poor_accuracy() {
+        let tracks = DashMap::new();
+        let config = FilterConfig::default();
+        let fix = at(51.5, -0.1, config.max_accuracy_meters + 1.0, 1000);
+        assert_eq!(feed(&tracks, fix).err(), Some(RejectReason::PoorAccuracy));
+        assert!(tracks.is_empty());
+        // Right on the threshold is fine.
+        let fix = at(51.5, -0.1, config.max_accuracy_meters, 1000);
+        assert!(feed(&tracks, fix).is_ok());
+    }
+
+    #[test]
+    fn impossible_speed() {
+        let tracks = DashMap::new();
+        feed(&tracks, at(51.5, -0.1, 5.0, 1000)).unwrap();
+        // About 11 km in a minute.
+        assert_eq!(
+            feed(&tracks, at(51.6, -0.1, 5.0, 1060)).err(),
+            Some(RejectReason::ImpossibleSpeed)
+        );
+        // The same jump an hour later is believable.
+        assert!(feed(&tracks, at(51.6, -0.1, 5.0, 4600)).is_ok());
+    }
+
+    #[test]
+    fn accuracy_gives_some_slack() {
+        let tracks = DashMap::new();
+        feed(&tracks, at(51.5, -0.1, 1000.0, 1000)).unwrap();
+        // About 1.1 km in a second, but both fixes could be off by a kilometer.
+        assert!(feed(&tracks, at(51.51, -0.1, 1000.0, 1001)).is_ok());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let tracks = DashMap::new();
+        feed(&tracks, at(51.5, -0.1, 5.0, 1000)).unwrap();
+        assert_eq!(
+            feed(&tracks, at(51.5, -0.1, 5.0, 999)).err(),
+            Some(RejectReason::OutOfOrder)
+        );
+        // Taken at the same moment is no reason to turn it away.
+        assert!(feed(&tracks, at(51.5, -0.1, 5.0, 1000)).is_ok());
+    }
+
+    #[test]
+    fn nothing_changes_until_committed() {
+        let tracks = DashMap::new();
+        let config = FilterConfig::default();
+        feed(&tracks, at(51.5, -0.1, 5.0, 1000)).unwrap();
+        let filtered = filter_fix(&tracks, 1, &at(51.5001, -0.1, 5.0, 1060), &config).unwrap();
+        assert_eq!(tracks.get(&1).unwrap().last_accepted.time, 1000);
+        filtered.commit(&tracks, 1, &config);
+        assert_eq!(tracks.get(&1).unwrap().last_accepted.time, 1060);
+    }
+
+    #[test]
+    fn smooths_towards_new_fixes() {
+        let tracks = DashMap::new();
+        feed(&tracks, at(51.5, -0.1, 10.0, 1000)).unwrap();
+        let shown = feed(&tracks, at(51.5002, -0.1, 10.0, 1060)).unwrap();
+        assert!(shown.latitude > 51.5 && shown.latitude < 51.5002);
+        assert!(shown.accuracy < 10.0);
+    }
+
+    #[test]
+    fn smooths_across_the_antimeridian() {
+        let tracks = DashMap::new();
+        feed(&tracks, at(-16.5, 179.9999, 10.0, 1000)).unwrap();
+        let shown = feed(&tracks, at(-16.5, -179.9999, 10.0, 1010)).unwrap();
+        // Somewhere in the 20 or so meters between the two, not back by Greenwich.
+        assert!(shown.longitude.abs() > 179.9998);
+    }
+
+    #[test]
+    fn fixes_committed_out_of_turn_both_count() {
+        let tracks = DashMap::new();
+        let config = FilterConfig::default();
+        feed(&tracks, at(51.5, -0.1, 10.0, 1000)).unwrap();
+        // Two fixes filtered against the same track, then stored and committed in turn.
+        let first = filter_fix(&tracks, 1, &at(51.5001, -0.1, 10.0, 1010), &config).unwrap();
+        let second = filter_fix(&tracks, 1, &at(51.5002, -0.1, 10.0, 1020), &config).unwrap();
+        first.commit(&tracks, 1, &config);
+        second.commit(&tracks, 1, &config);
+        let track = tracks.get(&1).unwrap();
+        assert_eq!((track.fixes, track.last_accepted.time), (3, 1020));
+
+        // The same fixes one after the other end up in the same place.
+        let in_turn = DashMap::new();
+        for fix in [
+            at(51.5, -0.1, 10.0, 1000),
+            at(51.5001, -0.1, 10.0, 1010),
+            at(51.5002, -0.1, 10.0, 1020),
+        ] {
+            feed(&in_turn, fix).unwrap();
+        }
+        let expected = in_turn.get(&1).unwrap();
+        assert_eq!(track.smoothed.latitude, expected.smoothed.latitude);
+        assert_eq!(track.variance, expected.variance);
+    }
+
+    #[test]
+    fn late_commit_that_no_longer_fits() {
+        let tracks = DashMap::new();
+        let config = FilterConfig::default();
+        feed(&tracks, at(51.5, -0.1, 10.0, 1000)).unwrap();
+        let older = filter_fix(&tracks, 1, &at(51.5001, -0.1, 10.0, 1010), &config).unwrap();
+        feed(&tracks, at(51.5002, -0.1, 10.0, 1020)).unwrap();
+        // It was taken before the one that got in first, so the track stays put.
+        let shown = older.commit(&tracks, 1, &config);
+        assert_eq!(shown.time, 1020);
+        assert_eq!(tracks.get(&1).unwrap().fixes, 2);
+    }
+
+    #[test]
+    fn dropped_track_starts_again() {
+        let tracks = DashMap::new();
+        let config = FilterConfig::default();
+        feed(&tracks, at(51.5, -0.1, 10.0, 1000)).unwrap();
+        let filtered = filter_fix(&tracks, 1, &at(52.0, 1.0, 10.0, 99000), &config).unwrap();
+        tracks.remove(&1);
+        let shown = filtered.commit(&tracks, 1, &config);
+        assert_eq!((shown.latitude, shown.longitude), (52.0, 1.0));
+        assert_eq!(tracks.get(&1).unwrap().fixes, 1);
+    }
+}
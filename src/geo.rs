@@ -0,0 +1,71 @@
+use crate::location::Location;
+
+/// The mean radius of the earth, in meters.
+const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+/// Meters per degree of latitude, near enough.
+const METERS_PER_DEGREE: f64 = 111_320.0;
+
+/// Computes the great-circle distance in meters between two points given in degrees,
+/// using the haversine formula. It's accurate enough for anything we care about.
+pub(crate) fn haversine_meters(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let dphi = (lat2 - lat1).to_radians();
+    let dlambda = (lon2 - lon1).to_radians();
+
+    let a = (dphi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (dlambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().atan2((1.0 - a).sqrt())
+}
+
+/// Sums up the distance along a run of points, in meters.
+pub(crate) fn path_length(points: &[Location]) -> f64 {
+    points
+        .windows(2)
+        .map(|w| distance_between(&w[0], &w[1]))
+        .sum()
+}
+
+/// The distance between two fixes, in meters.
+pub(crate) fn distance_between(a: &Location, b: &Location) -> f64 {
+    haversine_meters(a.latitude, a.longitude, b.latitude, b.longitude)
+}
+
+/// Wraps a longitude into [-180, 180).
+pub(crate) fn wrap_longitude(longitude: f64) -> f64 {
+    (longitude + 180.0).rem_euclid(360.0) - 180.0
+}
+
+/// The centroid of a run of nearby points, as (latitude, longitude) in degrees. Longitudes are
+/// averaged as offsets from the first point, so a run that straddles the antimeridian doesn't
+/// come out on the other side of the world. There has to be at least one point.
+pub(crate) fn centroid(points: &[Location]) -> (f64, f64) {
+    let n = points.len() as f64;
+    let first = &points[0];
+    let latitude = points.iter().map(|p| p.latitude).sum::<f64>() / n;
+    let offset = points
+        .iter()
+        .map(|p| wrap_longitude(p.longitude - first.longitude))
+        .sum::<f64>()
+        / n;
+    (latitude, wrap_longitude(first.longitude + offset))
+}
+
+/// Snaps a location to the middle of a grid cell roughly `meters` on a side, so that it gives
+/// away no more than that much precision. The accuracy is widened to match.
+pub(crate) fn reduce_precision(loc: &Location, meters: f64) -> Location {
+    if meters <= 0.0 {
+        return loc.clone();
+    }
+    // Snap latitude first, so the longitude grid is the same for everyone in that row.
+    let lat_step = meters / METERS_PER_DEGREE;
+    let latitude = ((loc.latitude / lat_step).floor() + 0.5) * lat_step;
+    let lon_step =
+        (meters / (METERS_PER_DEGREE * latitude.to_radians().cos().max(0.01))).min(360.0);
+    let longitude = ((loc.longitude / lon_step).floor() + 0.5) * lon_step;
+    Location {
+        latitude: latitude.clamp(-90.0, 90.0),
+        longitude,
+        accuracy: loc.accuracy.max(meters),
+        time: loc.time,
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+};
+
+use crate::{config::GeocoderConfig, geo::haversine_meters};
+
+/// The size of the grid cells that places get bucketed into, in degrees.
+const CELL_DEGREES: f64 = 0.5;
+
+/// A named place that we can describe a location as being near.
+pub(crate) struct Place {
+    /// Degrees.
+    pub(crate) latitude: f64,
+    /// Degrees.
+    pub(crate) longitude: f64,
+    /// Something like "Springfield, IL".
+    pub(crate) label: String,
+}
+
+/// An in-memory spatial index of named places, loaded from a local file at startup, so that
+/// reverse geocoding never has to ask anyone else where our users are.
+pub(crate) struct Geocoder {
+    /// Places bucketed by the grid cell they fall in.
+    cells: HashMap<(i32, i32), Vec<Place>>,
+    /// Meters. Anything further than this from every place doesn't get a label.
+    max_distance: f64,
+}
+
+/// Works out which grid cell a point falls in.
+fn cell_of(latitude: f64, longitude: f64) -> (i32, i32) {
+    (
+        (latitude / CELL_DEGREES).floor() as i32,
+        (longitude / CELL_DEGREES).floor() as i32,
+    )
+}
+
+/// Parses one line of a places file. We take either a GeoNames dump (cities500.txt and
+/// friends), or a simpler name, latitude, longitude, region file, both tab-separated.
+fn parse_place(line: &str) -> Option<Place> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    let (name, latitude, longitude, region) = match fields.len() {
+        4 => (fields[0], fields[1], fields[2], fields[3]),
+        // GeoNames: name is column 1, lat/lon are 4 and 5, country is 8, admin1 is 10.
+        // US states get their familiar two-letter codes in admin1, everyone else gets
+        // their country code, since admin1 is just a number there.
+        n if n >= 11 => (
+            fields[1],
+            fields[4],
+            fields[5],
+            if fields[8] == "US" {
+                fields[10]
+            } else {
+                fields[8]
+            },
+        ),
+        _ => return None,
+    };
+    Some(Place {
+        latitude: latitude.parse().ok()?,
+        longitude: longitude.parse().ok()?,
+        label: if region.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}, {}", name, region)
+        },
+    })
+}
+
+impl Geocoder {
+    /// Loads the configured places file into an index. Lines we can't make sense of are skipped.
+    pub(crate) fn load(config: &GeocoderConfig) -> std::io::Result<Geocoder> {
+        let reader = BufReader::new(File::open(&config.places_path)?);
+        let mut cells: HashMap<(i32, i32), Vec<Place>> = HashMap::new();
+        let mut count = 0usize;
+        for line in reader.lines() {
+            let line = line?;
+            if line.starts_with('#') {
+                continue;
+            }
+            if let Some(place) = parse_place(&line) {
+                cells
+                    .entry(cell_of(place.latitude, place.longitude))
+                    .or_default()
+                    .push(place);
+                count += 1;
+            }
+        }
+        log::info!(
+            "Loaded {} places for reverse geocoding from {}.",
+            count,
+            config.places_path
+        );
+        Ok(Geocoder {
+            cells,
+            max_distance: config.max_distance_km * 1000.0,
+        })
+    }
+
+    /// Finds the label of the closest known place to a point, if there's one close enough.
+    pub(crate) fn nearest(&self, latitude: f64, longitude: f64) -> Option<&str> {
+        self.nearest_place(latitude, longitude)
+            .map(|place| place.label.as_str())
+    }
+
+    /// Finds the closest known place to a point, if there's one close enough.
+    pub(crate) fn nearest_place(&self, latitude: f64, longitude: f64) -> Option<&Place> {
+        let (row, col) = cell_of(latitude, longitude);
+        // Look far enough around the point's own cell to cover the max distance. Longitude
+        // cells shrink towards the poles, so those need a wider search.
+        let lat_reach = (self.max_distance / 111_000.0 / CELL_DEGREES).ceil() as i32;
+        let lon_reach = (self.max_distance
+            / (111_000.0 * latitude.to_radians().cos().max(0.01))
+            / CELL_DEGREES)
+            .ceil()
+            .min(180.0 / CELL_DEGREES) as i32;
+
+        let mut best: Option<(f64, &Place)> = None;
+        for r in row - lat_reach..=row + lat_reach {
+            for c in col - lon_reach..=col + lon_reach {
+                // Wrap around the antimeridian.
+                let cells_around = (360.0 / CELL_DEGREES) as i32;
+                let c = (c + cells_around / 2).rem_euclid(cells_around) - cells_around / 2;
+                let Some(places) = self.cells.get(&(r, c)) else {
+                    continue;
+                };
+                for place in places {
+                    let d = haversine_meters(latitude, longitude, place.latitude, place.longitude);
+                    if d <= self.max_distance && best.is_none_or(|(bd, _)| d < bd) {
+                        best = Some((d, place));
+                    }
+                }
+            }
+        }
+        best.map(|(_, place)| place)
+    }
+}
@@ -0,0 +1,251 @@
+use actix_web::{get, http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::require_admin,
+    audit, db,
+    location::authenticate_session,
+    misc::{bad_request, forbidden, internal_error},
+    AppState,
+};
+
+/// A set of devices and the web_users who get to see them. Once a device is in a group, only
+/// its owner and the members of its groups can see it. Once a web_user is in a group, they
+/// only see their own devices and those in their groups. Devices and web_users outside of any
+/// group see each other, like before there were groups.
+#[derive(Serialize)]
+pub(crate) struct Group {
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    /// Usernames of the web_users in it.
+    pub(crate) members: Vec<String>,
+    /// Api key ids of the devices in it.
+    pub(crate) devices: Vec<u64>,
+}
+
+/// Something that can be in a group.
+pub(crate) enum GroupMember {
+    User(String),
+    Device(u64),
+}
+
+#[derive(Serialize)]
+struct GroupOut {
+    id: u64,
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GroupCreateIn {
+    name: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GroupDeleteIn {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GroupMemberIn {
+    /// The group's id.
+    id: u64,
+    /// A web_user to put in or take out. Give this or key_id, not both.
+    username: Option<String>,
+    /// A device's api key id to put in or take out.
+    key_id: Option<u64>,
+}
+
+/// Confirms that a viewer shares a group with a device, or owns it, or that neither is in any
+/// group. Returns the response to send back if not.
+pub(crate) async fn require_visible(
+    data: &AppState,
+    key_id: u64,
+    name: &str,
+) -> Result<(), HttpResponse> {
+    match db::can_see_key(&data.pool, name.to_string(), key_id).await {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            log::debug!("{} isn't in any of api key id {}'s groups.", name, key_id);
+            Err(forbidden())
+        }
+        Err(e) => {
+            log::error!("Failed to check the groups of api key id {}: {}", key_id, e);
+            Err(internal_error())
+        }
+    }
+}
+
+/// Lists the groups the signed-in web_user is in, to pick from for /api/location/list.
+#[get("/api/groups")]
+pub(crate) async fn get_groups(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+
+    match db::list_member_groups(&data.pool, session.name).await {
+        Ok(groups) => {
+            let groups: Vec<GroupOut> = groups
+                .into_iter()
+                .map(|(id, name)| GroupOut { id, name })
+                .collect();
+            HttpResponse::Ok()
+                .insert_header(ContentType::json())
+                .body(serde_json::to_string(&groups).unwrap())
+        }
+        Err(e) => {
+            log::error!("/api/groups: failed to list groups: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[get("/api/admin/groups")]
+pub(crate) async fn get_admin_groups(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = require_admin(&data, &req).await {
+        return response;
+    }
+
+    match db::list_groups(&data.pool).await {
+        Ok(groups) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&groups).unwrap()),
+        Err(e) => {
+            log::error!("/api/admin/groups: failed to list groups: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/admin/groups/create")]
+pub(crate) async fn post_admin_groups_create(
+    info: web::Json<GroupCreateIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let name = info.name.trim();
+    if name.is_empty() {
+        return bad_request("name can't be empty.");
+    }
+
+    let id = match db::insert_group(&data.pool, name.to_string()).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return bad_request("There's already a group by that name."),
+        Err(e) => {
+            log::error!("/api/admin/groups/create: failed to store group: {}", e);
+            return internal_error();
+        }
+    };
+    audit::record(
+        &data,
+        &req,
+        &admin,
+        "group.create",
+        format!("id {} named {}", id, name),
+    )
+    .await;
+
+    HttpResponse::Ok().insert_header(ContentType::json()).body(
+        serde_json::to_string(&GroupOut {
+            id,
+            name: name.to_string(),
+        })
+        .unwrap(),
+    )
+}
+
+#[post("/api/admin/groups/delete")]
+pub(crate) async fn post_admin_groups_delete(
+    info: web::Json<GroupDeleteIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+
+    match db::delete_group(&data.pool, info.id).await {
+        Ok(Some(name)) => {
+            let detail = format!("id {} named {}", info.id, name);
+            audit::record(&data, &req, &admin, "group.delete", detail).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(None) => bad_request("No such id."),
+        Err(e) => {
+            log::error!("/api/admin/groups/delete: failed to delete group: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/admin/groups/add")]
+pub(crate) async fn post_admin_groups_add(
+    info: web::Json<GroupMemberIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    set_membership(info.into_inner(), true, &data, &req).await
+}
+
+#[post("/api/admin/groups/remove")]
+pub(crate) async fn post_admin_groups_remove(
+    info: web::Json<GroupMemberIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    set_membership(info.into_inner(), false, &data, &req).await
+}
+
+/// Puts a web_user or device in a group, or takes it out.
+async fn set_membership(
+    info: GroupMemberIn,
+    add: bool,
+    data: &AppState,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let (path, event) = match add {
+        true => ("/api/admin/groups/add", "group.add"),
+        false => ("/api/admin/groups/remove", "group.remove"),
+    };
+    let admin = match require_admin(data, req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    let (member, what) = match (info.username, info.key_id) {
+        (Some(username), None) if !username.trim().is_empty() => {
+            let what = format!("web user {}", username);
+            (GroupMember::User(username), what)
+        }
+        (None, Some(key_id)) => (
+            GroupMember::Device(key_id),
+            format!("api key id {}", key_id),
+        ),
+        _ => return bad_request("Give either username or key_id."),
+    };
+
+    match db::set_group_membership(&data.pool, info.id, member, add).await {
+        Ok(true) => {
+            let detail = format!(
+                "{} {} group id {}",
+                what,
+                if add { "to" } else { "from" },
+                info.id
+            );
+            audit::record(data, req, &admin, event, detail).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => bad_request("No such id."),
+        Err(e) => {
+            log::error!("{}: failed to update group: {}", path, e);
+            internal_error()
+        }
+    }
+}
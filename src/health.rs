@@ -0,0 +1,83 @@
+use actix_web::{get, http::header::ContentType, web, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::{db, AppState};
+
+/// The version of this build, from Cargo.toml.
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Serialize)]
+struct HealthOut {
+    status: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize)]
+struct ReadyOut {
+    status: &'static str,
+    version: &'static str,
+    /// Whether we could get a connection and run a query.
+    database: bool,
+    /// The schema version we need.
+    schema_expected: u32,
+    /// The schema version the database says it's at, if we got that far.
+    schema_found: Option<u32>,
+    /// Always true, since we wouldn't be running without a config, but the uptime checker
+    /// likes to see it spelled out.
+    config: bool,
+    /// What's wrong, if anything.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    err: Option<String>,
+}
+
+/// Liveness: if this answers at all, the process is up.
+#[get("/healthz")]
+pub(crate) async fn get_healthz() -> impl Responder {
+    HttpResponse::Ok().insert_header(ContentType::json()).body(
+        serde_json::to_string(&HealthOut {
+            status: "ok",
+            version: VERSION,
+        })
+        .unwrap(),
+    )
+}
+
+/// Readiness: whether we can actually serve requests, which mostly means whether the
+/// database is there and up to date.
+#[get("/readyz")]
+pub(crate) async fn get_readyz(data: web::Data<AppState>) -> impl Responder {
+    let (database, schema_found, err) = match db::schema_version(&data.pool).await {
+        Ok(v) if v == db::SCHEMA_VERSION => (true, Some(v), None),
+        Ok(v) => (
+            true,
+            Some(v),
+            Some(format!(
+                "Database schema is at version {}, expected {}. Apply the db/migrate-N.sql files.",
+                v,
+                db::SCHEMA_VERSION
+            )),
+        ),
+        Err(e) => {
+            log::error!("/readyz: database check failed: {}", e);
+            (false, None, Some("Database unavailable.".to_string()))
+        }
+    };
+
+    let out = ReadyOut {
+        status: if err.is_none() { "ok" } else { "unavailable" },
+        version: VERSION,
+        database,
+        schema_expected: db::SCHEMA_VERSION,
+        schema_found,
+        config: true,
+        err,
+    };
+    let mut response = if out.err.is_none() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    response
+        .insert_header(ContentType::json())
+        .body(serde_json::to_string(&out).unwrap())
+}
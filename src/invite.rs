@@ -0,0 +1,349 @@
+use actix_web::{
+    get,
+    http::header::{self, ContentType},
+    post, web, HttpRequest, HttpResponse, Responder,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::{check_days, check_username, require_admin},
+    audit::{self, ANONYMOUS},
+    auth::start_login,
+    db,
+    keys::generate_key,
+    location::authenticate_session,
+    misc::{bad_request, forbidden, internal_error, no_store, unixtime_now, SECS_PER_DAY},
+    provision::Provisioning,
+    ratelimit::flag_credential_failure,
+    AppState,
+};
+
+/// How long an invite works for, in hours, unless the admin says otherwise.
+const DEFAULT_INVITE_HOURS: u64 = 72;
+
+/// The longest an invite can work for, in hours.
+const MAX_INVITE_HOURS: u64 = 24 * 30;
+
+/// How long after using an invite the device key it made can be picked up, in seconds.
+const PICKUP_SECS: u64 = SECS_PER_DAY;
+
+/// A single-use code that makes whoever signs in with it a web_user in a group, without an
+/// admin needing to know their email ahead of time. It's only used up by someone who isn't a
+/// web_user already; anyone else just signs in as usual.
+#[derive(Serialize)]
+pub(crate) struct Invite {
+    pub(crate) id: u64,
+    pub(crate) code: String,
+    /// The group they'll be put in.
+    pub(crate) group_id: u64,
+    /// The username they'll get.
+    pub(crate) username: String,
+    /// How long their web_user works for once it's made.
+    pub(crate) user_days: u64,
+    /// If set, they get an api key for their phone too, that works for this long.
+    pub(crate) device_days: Option<u64>,
+    /// The admin who made it.
+    pub(crate) created_by: String,
+    /// Seconds since the unix epoch.
+    pub(crate) issued: u64,
+    /// Seconds since the unix epoch.
+    pub(crate) expiration: u64,
+    /// When it was used, in seconds since the unix epoch.
+    pub(crate) redeemed: Option<u64>,
+    /// Who it was used by.
+    pub(crate) redeemed_email: Option<String>,
+    /// The api key id it made, if any.
+    pub(crate) key_id: Option<u64>,
+}
+
+/// What using up an invite made.
+pub(crate) struct Redeemed {
+    pub(crate) invite_id: u64,
+    pub(crate) username: String,
+    pub(crate) group_id: u64,
+    pub(crate) key_id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct InviteCreateIn {
+    group_id: u64,
+    /// The username the invitee will get.
+    username: String,
+    /// How long their web_user should work for.
+    days: u64,
+    /// How long the invite should work for. Defaults to three days.
+    hours: Option<u64>,
+    /// Set to make them an api key for their phone as well, that works for this many days.
+    device_days: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct InviteCreateOut {
+    id: u64,
+    code: String,
+    /// The link to send the invitee. Following it signs them in.
+    url: String,
+    expiration: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct InviteRevokeIn {
+    id: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct InviteStartIn {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct InviteDeviceOut {
+    id: u64,
+    key: String,
+    expiration: u64,
+    /// Everything the phone needs, as text to put in a QR code.
+    qr_payload: String,
+}
+
+/// Makes a new invite code: 16 random bytes, in URL-safe base64.
+fn generate_code() -> String {
+    let mut code = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut code);
+    URL_SAFE_NO_PAD.encode(code)
+}
+
+/// Uses up an invite for someone who's just signed in with an email that isn't a web_user's,
+/// and returns the username it made for them. Returns the response to send back if the invite
+/// is no good.
+pub(crate) async fn redeem_invite(
+    data: &AppState,
+    req: &HttpRequest,
+    code: String,
+    email: &str,
+) -> Result<String, HttpResponse> {
+    match db::redeem_invite(&data.pool, code, email.to_string(), generate_key()).await {
+        Ok(Some(redeemed)) => {
+            let mut detail = format!(
+                "invite id {} used by {}, joined group id {}",
+                redeemed.invite_id, email, redeemed.group_id
+            );
+            if let Some(key_id) = redeemed.key_id {
+                detail += &format!(", got api key id {}", key_id);
+            }
+            audit::record(data, req, &redeemed.username, "invite.redeem", detail).await;
+            Ok(redeemed.username)
+        }
+        Ok(None) => {
+            log::debug!(
+                "/api/auth/redirect: invite for '{}' was used up, expired or its username taken.",
+                email
+            );
+            let detail = format!(
+                "{} came with an invite that's used up, expired or its username taken",
+                email
+            );
+            audit::record(data, req, ANONYMOUS, "auth.fail.bad_invite", detail).await;
+            flag_credential_failure(req);
+            Err(forbidden())
+        }
+        Err(e) => {
+            log::error!("/api/auth/redirect: failed to use invite: {}", e);
+            Err(internal_error())
+        }
+    }
+}
+
+/// Where an invite link goes. Sends the invitee straight off to sign in, remembering the code
+/// for when they get back.
+#[get("/api/auth/invite")]
+pub(crate) async fn get_auth_invite(
+    info: web::Query<InviteStartIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Turn away codes that are no good now, rather than after they've signed in.
+    match db::get_open_invite(&data.pool, info.code.clone()).await {
+        Ok(Some(_)) => {}
+        Ok(None) => {
+            let detail = "invite link is used up, expired or made up".to_string();
+            audit::record(&data, &req, ANONYMOUS, "auth.fail.bad_invite", detail).await;
+            flag_credential_failure(&req);
+            return forbidden();
+        }
+        Err(e) => {
+            log::error!("/api/auth/invite: failed to look up invite: {}", e);
+            return internal_error();
+        }
+    }
+
+    let (auth_url, cookie) = start_login(&data, Some(info.into_inner().code));
+    HttpResponse::SeeOther()
+        .cookie(cookie)
+        .append_header((header::LOCATION, auth_url))
+        .finish()
+}
+
+/// Gives a newly invited web_user the key their invite made for their phone, for a day after
+/// they used it.
+#[get("/api/invite/device")]
+pub(crate) async fn get_invite_device(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+
+    let since = unixtime_now().saturating_sub(PICKUP_SECS);
+    match db::get_invite_key(&data.pool, session.name.clone(), since).await {
+        Ok(Some((id, key, expiration))) => {
+            let payload = Provisioning::new(&data.config(), id, session.name, key.clone());
+            HttpResponse::Ok()
+                .insert_header(ContentType::json())
+                .insert_header(no_store())
+                .body(
+                    serde_json::to_string(&InviteDeviceOut {
+                        id,
+                        key,
+                        expiration,
+                        qr_payload: serde_json::to_string(&payload).unwrap(),
+                    })
+                    .unwrap(),
+                )
+        }
+        Ok(None) => bad_request("No device key from an invite to pick up."),
+        Err(e) => {
+            log::error!("/api/invite/device: failed to look up the key: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[get("/api/admin/invites")]
+pub(crate) async fn get_admin_invites(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    if let Err(response) = require_admin(&data, &req).await {
+        return response;
+    }
+
+    match db::list_invites(&data.pool).await {
+        Ok(invites) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&invites).unwrap()),
+        Err(e) => {
+            log::error!("/api/admin/invites: failed to list invites: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/admin/invites/create")]
+pub(crate) async fn post_admin_invites_create(
+    info: web::Json<InviteCreateIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_username(&info.username) {
+        return response;
+    }
+    if let Err(response) = check_days(info.days) {
+        return response;
+    }
+    if let Some(Err(response)) = info.device_days.map(check_days) {
+        return response;
+    }
+    // An invite for a name that's taken would make the invitee that person.
+    match db::username_in_use(&data.pool, info.username.clone()).await {
+        Ok(false) => {}
+        Ok(true) => return bad_request("That username is already taken."),
+        Err(e) => {
+            log::error!("/api/admin/invites/create: failed to check username: {}", e);
+            return internal_error();
+        }
+    }
+    let hours = info.hours.unwrap_or(DEFAULT_INVITE_HOURS);
+    if hours == 0 || hours > MAX_INVITE_HOURS {
+        return bad_request("hours must be between 1 and 720.");
+    }
+
+    let now = unixtime_now();
+    let invite = Invite {
+        id: 0,
+        code: generate_code(),
+        group_id: info.group_id,
+        username: info.username.clone(),
+        user_days: info.days,
+        device_days: info.device_days,
+        created_by: admin.clone(),
+        issued: now,
+        expiration: now + hours * 60 * 60,
+        redeemed: None,
+        redeemed_email: None,
+        key_id: None,
+    };
+    let (code, expiration) = (invite.code.clone(), invite.expiration);
+    let id = match db::insert_invite(&data.pool, invite).await {
+        Ok(Some(id)) => id,
+        Ok(None) => return bad_request("No such group."),
+        Err(e) => {
+            log::error!("/api/admin/invites/create: failed to store invite: {}", e);
+            return internal_error();
+        }
+    };
+    let detail = format!(
+        "id {} for {} in group id {}, {} hours",
+        id, info.username, info.group_id, hours
+    );
+    audit::record(&data, &req, &admin, "invite.create", detail).await;
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .insert_header(no_store())
+        .body(
+            serde_json::to_string(&InviteCreateOut {
+                id,
+                url: format!(
+                    "https://{}/api/auth/invite?code={}",
+                    data.config().domain_name,
+                    code
+                ),
+                code,
+                expiration,
+            })
+            .unwrap(),
+        )
+}
+
+#[post("/api/admin/invites/revoke")]
+pub(crate) async fn post_admin_invites_revoke(
+    info: web::Json<InviteRevokeIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+
+    match db::revoke_invite(&data.pool, info.id).await {
+        Ok(true) => {
+            let detail = format!("id {}", info.id);
+            audit::record(&data, &req, &admin, "invite.revoke", detail).await;
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => bad_request("No such id."),
+        Err(e) => {
+            log::error!("/api/admin/invites/revoke: failed to revoke invite: {}", e);
+            internal_error()
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use actix_web::{
+    http::header::{ContentType, AUTHORIZATION},
+    post, web, HttpRequest, HttpResponse, Responder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audit::{self, ANONYMOUS},
+    db,
+    misc::{forbidden, internal_error, no_store, too_many_requests},
+    ratelimit::{flag_credential_failure, retry_after_secs},
+    AppState,
+};
+
+/// Lets a key post location updates.
+pub(crate) const SCOPE_WRITE: &str = "location:write";
+/// Lets a key read the locations of its owner's devices.
+pub(crate) const SCOPE_READ_SELF: &str = "location:read:self";
+/// Lets a key read every location its owner could, as if they were signed in.
+pub(crate) const SCOPE_READ_SHARED: &str = "location:read:shared";
+/// Lets a key use the admin API.
+pub(crate) const SCOPE_ADMIN: &str = "admin";
+/// Every scope there is.
+const SCOPES: &[&str] = &[SCOPE_WRITE, SCOPE_READ_SELF, SCOPE_READ_SHARED, SCOPE_ADMIN];
+
+/// An api key that checked out, and who it belongs to.
+pub(crate) struct VerifiedKey {
+    pub(crate) id: u64,
+    pub(crate) username: String,
+    pub(crate) scopes: Vec<String>,
+}
+
+impl VerifiedKey {
+    pub(crate) fn has(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    /// Turns the request away, and notes it in the audit log, if the key doesn't have the
+    /// scope.
+    pub(crate) async fn require(
+        &self,
+        data: &AppState,
+        req: &HttpRequest,
+        scope: &str,
+    ) -> Result<(), HttpResponse> {
+        if self.has(scope) {
+            return Ok(());
+        }
+        log::debug!("{}: api key id {} lacks {}.", req.path(), self.id, scope);
+        let detail = format!(
+            "api key id {} tried {} without {}",
+            self.id,
+            req.path(),
+            scope
+        );
+        audit::record(data, req, &self.username, "auth.fail.missing_scope", detail).await;
+        Err(forbidden())
+    }
+}
+
+/// Splits scopes the way they're stored, space-separated.
+pub(crate) fn parse_scopes(stored: &str) -> Vec<String> {
+    stored.split_whitespace().map(str::to_string).collect()
+}
+
+/// Checks that scopes are all ones we know, and puts them the way they're stored.
+pub(crate) fn check_scopes(scopes: &[String]) -> Result<String, String> {
+    if scopes.is_empty() {
+        return Err("scopes can't be empty.".to_string());
+    }
+    if let Some(unknown) = scopes.iter().find(|s| !SCOPES.contains(&s.as_str())) {
+        return Err(format!(
+            "{} isn't a scope. Try {}.",
+            unknown,
+            SCOPES.join(", ")
+        ));
+    }
+    Ok(scopes.join(" "))
+}
+
+/// Checks the api key in a request's Authorization header, if there is one. Returns None if
+/// there isn't, so the caller can try a session instead, and the response to send back if
+/// the key's no good.
+pub(crate) async fn authenticate_key(
+    data: &AppState,
+    req: &HttpRequest,
+) -> Result<Option<VerifiedKey>, HttpResponse> {
+    let Some(key) = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+    else {
+        return Ok(None);
+    };
+
+    // Same limits as for location updates, so this isn't a faster way to guess keys.
+    if let Err(wait) = data.limiter.check_key(key, &data.config().rate_limit) {
+        log::debug!("{}: Rate limited.", req.path());
+        return Err(too_many_requests(retry_after_secs(wait)));
+    }
+    match db::verify_api_key(&data.pool, key.to_string()).await {
+        Ok(Some(verified)) => {
+            audit::note_key_use(data, req, verified.id, &verified.username).await;
+            Ok(Some(verified))
+        }
+        Ok(None) => {
+            log::debug!("{}: Bad API key.", req.path());
+            let detail = format!("{} with an unknown or expired key", req.path());
+            audit::record(data, req, ANONYMOUS, "auth.fail.bad_api_key", detail).await;
+            flag_credential_failure(req);
+            Err(forbidden())
+        }
+        Err(e) => {
+            log::error!("{}: failed to check api key: {}", req.path(), e);
+            Err(internal_error())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct KeyCurrentIn {
+    api_key: String,
+}
+
+#[derive(Serialize)]
+struct KeyCurrentOut {
+    id: u64,
+    /// The key the device should be using.
+    key: String,
+    expiration: u64,
+    /// Whether that's a different key than the one it sent.
+    rotated: bool,
+}
+
+/// Makes a new api key: 64 random bytes in base64, the same as gen_api_key.sh does.
+pub(crate) fn generate_key() -> String {
+    let mut key = [0u8; 64];
+    rand::thread_rng().fill_bytes(&mut key);
+    STANDARD.encode(key)
+}
+
+#[post("/api/key/current")]
+pub(crate) async fn post_key_current(
+    info: web::Json<KeyCurrentIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // This checks keys just like a location update does, so it's limited the same way.
+    if let Err(wait) = data
+        .limiter
+        .check_key(&info.api_key, &data.config().rate_limit)
+    {
+        log::debug!("/api/key/current: Rate limited.");
+        return too_many_requests(retry_after_secs(wait));
+    }
+
+    // Either the current key or one that's been rotated out but is still in its grace period
+    // will do.
+    let (id, username, key, expiration, rotated) =
+        match db::current_api_key(&data.pool, info.api_key.clone()).await {
+            Ok(Some(current)) => current,
+            Ok(None) => {
+                log::debug!("/api/key/current: Bad API key.");
+                let detail = "key lookup with an unknown or expired key".to_string();
+                audit::record(&data, &req, ANONYMOUS, "auth.fail.bad_api_key", detail).await;
+                flag_credential_failure(&req);
+                return forbidden();
+            }
+            Err(e) => {
+                log::error!("/api/key/current: failed to look up api key: {}", e);
+                return internal_error();
+            }
+        };
+    if rotated {
+        let detail = format!("api key id {} picked up its replacement", id);
+        audit::record(&data, &req, &username, "api_key.fetch_rotated", detail).await;
+    }
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .insert_header(no_store())
+        .body(
+            serde_json::to_string(&KeyCurrentOut {
+                id,
+                key,
+                expiration,
+                rotated,
+            })
+            .unwrap(),
+        )
+}
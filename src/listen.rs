@@ -0,0 +1,264 @@
+use std::{
+    fs::Permissions,
+    io,
+    net::{IpAddr, TcpListener},
+    os::unix::{fs::PermissionsExt, io::FromRawFd, net::UnixListener},
+    sync::Arc,
+};
+
+use actix_web::{dev::ServiceRequest, middleware::Logger, web, HttpRequest};
+use nix::{
+    sys::{
+        socket::{getsockname, AddressFamily, SockaddrLike, SockaddrStorage},
+        stat::{umask, Mode},
+    },
+    unistd::{chown, Group, User},
+};
+use rustls::ServerConfig;
+
+use crate::{
+    config::{ListenSpec, UnixListenSpec},
+    tls::{self, ReloadingCert},
+    AppState,
+};
+
+/// Somewhere to listen, ready to hand to the server.
+pub(crate) enum Listener {
+    Tcp((String, u16), Option<Box<ServerConfig>>),
+    /// A TCP socket that's already listening, from socket activation.
+    TcpSocket(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Turns a listen entry from the config into something the server can bind to. Any TLS
+/// certificate gets added to `certs`, so it can be reloaded later.
+pub(crate) fn open(spec: &ListenSpec, certs: &mut Vec<Arc<ReloadingCert>>) -> io::Result<Listener> {
+    match spec {
+        ListenSpec::Tcp(tcp) => {
+            let tls_config = match &tcp.tls {
+                Some(paths) => {
+                    let (tls_config, cert) = tls::server_config(paths)?;
+                    certs.push(cert);
+                    Some(Box::new(tls_config))
+                }
+                None => None,
+            };
+            Ok(Listener::Tcp((tcp.addr.clone(), tcp.port), tls_config))
+        }
+        ListenSpec::Unix(unix) => bind_unix(unix).map(Listener::Unix),
+    }
+}
+
+/// Takes over the sockets systemd opened for us, if we were socket activated. They're served
+/// as plain HTTP, same as a listen entry without TLS.
+pub(crate) fn activated() -> io::Result<Vec<Listener>> {
+    let mut listeners = Vec::new();
+    for fd in sd_notify::listen_fds()? {
+        let family = getsockname::<SockaddrStorage>(fd)?.family();
+        // SAFETY: systemd passed these over for us to own, and nothing else in here takes them.
+        listeners.push(unsafe {
+            match family {
+                Some(AddressFamily::Unix) => Listener::Unix(UnixListener::from_raw_fd(fd)),
+                _ => Listener::TcpSocket(TcpListener::from_raw_fd(fd)),
+            }
+        });
+    }
+    if !listeners.is_empty() {
+        log::info!("Socket activated with {} sockets.", listeners.len());
+    }
+    Ok(listeners)
+}
+
+/// Makes a Unix socket, replacing anything left over from last time, and sets its owner and
+/// permissions.
+fn bind_unix(spec: &UnixListenSpec) -> io::Result<UnixListener> {
+    let invalid = |why: String| io::Error::new(io::ErrorKind::InvalidInput, why);
+
+    // Check all the settings before touching the filesystem.
+    let mode = match &spec.mode {
+        Some(mode) => Some(
+            u32::from_str_radix(mode, 8)
+                .map_err(|_| invalid(format!("Bad socket mode {:?}, it should be octal.", mode)))?,
+        ),
+        None => None,
+    };
+    let uid = match &spec.owner {
+        Some(name) => Some(
+            User::from_name(name)?
+                .ok_or_else(|| invalid(format!("No such user {:?}.", name)))?
+                .uid,
+        ),
+        None => None,
+    };
+    let gid = match &spec.group {
+        Some(name) => Some(
+            Group::from_name(name)?
+                .ok_or_else(|| invalid(format!("No such group {:?}.", name)))?
+                .gid,
+        ),
+        None => None,
+    };
+
+    match std::fs::remove_file(&spec.path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    // Make it with no access for anyone else, so nobody can connect in before its owner and
+    // mode are set. The umask is for the whole process, but nothing else is making files while
+    // we're starting up.
+    let umask_was = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(&spec.path);
+    umask(umask_was);
+    let listener = listener?;
+    if uid.is_some() || gid.is_some() {
+        chown(spec.path.as_str(), uid, gid)?;
+    }
+    // Without a mode of its own, it gets what the umask would have given it.
+    let mode = mode.unwrap_or(0o777 & !umask_was.bits());
+    std::fs::set_permissions(&spec.path, Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Works out who a request really came from. Requests that came through one of our own proxies
+/// (or over a Unix socket, which only the proxy can reach) are credited to the nearest address
+/// in X-Forwarded-For that isn't one of our proxies. Everyone else gets their peer address,
+/// since they could put anything they like in the header.
+pub(crate) fn client_ip(req: &HttpRequest, trusted_proxies: &[IpAddr]) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    if peer.is_some_and(|ip| !trusted_proxies.contains(&ip)) {
+        return peer;
+    }
+
+    // Each proxy appends the address it got the request from, so walk it from the right.
+    let forwarded: Vec<&str> = req
+        .headers()
+        .get_all("x-forwarded-for")
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(str::trim)
+        .collect();
+    let mut client = peer;
+    for hop in forwarded.into_iter().rev() {
+        match hop.parse::<IpAddr>() {
+            Ok(ip) => {
+                client = Some(ip);
+                if !trusted_proxies.contains(&ip) {
+                    break;
+                }
+            }
+            // Not an address we can do anything with, so stop believing the header here.
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+/// Paths whose query strings carry something secret, like an invite code or share token, that
+/// shouldn't end up in the logs.
+const SECRET_QUERY_PATHS: [&str; 3] = ["/api/auth/invite", "/api/auth/redirect", "/api/share/view"];
+
+/// The request logger. It's the default format, except that it logs the client's address as
+/// worked out by `client_ip`, rather than the peer's, and leaves secrets out of the request
+/// line.
+pub(crate) fn logger() -> Logger {
+    Logger::new(r#"%{client_ip}xi "%{request_line}xi" %s %b "%{Referer}i" "%{User-Agent}i" %T"#)
+        .custom_request_replace("request_line", |req: &ServiceRequest| {
+            let query = req.query_string();
+            let target = match query.is_empty() || SECRET_QUERY_PATHS.contains(&req.path()) {
+                true => req.path().to_string(),
+                false => format!("{}?{}", req.path(), query),
+            };
+            format!("{} {} {:?}", req.method(), target, req.version())
+        })
+        .custom_request_replace("client_ip", |req: &ServiceRequest| {
+            let config = req
+                .app_data::<web::Data<AppState>>()
+                .map(|data| data.config());
+            let trusted = config
+                .as_ref()
+                .map(|c| c.trusted_proxies.as_slice())
+                .unwrap_or_default();
+            match client_ip(req.request(), trusted) {
+                Some(ip) => ip.to_string(),
+                None => "-".to_string(),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    const PROXY: &str = "10.0.0.1";
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// A request from `peer` (or over a Unix socket, if there isn't one), with the given
+    /// X-Forwarded-For headers.
+    fn request(peer: Option<&str>, forwarded: &[&str]) -> HttpRequest {
+        let mut req = TestRequest::default();
+        if let Some(peer) = peer {
+            req = req.peer_addr(SocketAddr::new(ip(peer), 54321));
+        }
+        for header in forwarded {
+            req = req.append_header(("X-Forwarded-For", *header));
+        }
+        req.to_http_request()
+    }
+
+    #[test]
+    fn untrusted_peer_is_taken_at_its_word() {
+        let req = request(Some("203.0.113.9"), &["198.51.100.7"]);
+        assert_eq!(client_ip(&req, &[ip(PROXY)]), Some(ip("203.0.113.9")));
+        // Nobody's trusted if there are no proxies.
+        let req = request(Some(PROXY), &["198.51.100.7"]);
+        assert_eq!(client_ip(&req, &[]), Some(ip(PROXY)));
+    }
+
+    #[test]
+    fn through_a_trusted_proxy() {
+        let req = request(Some(PROXY), &["198.51.100.7"]);
+        assert_eq!(client_ip(&req, &[ip(PROXY)]), Some(ip("198.51.100.7")));
+        // No header means the proxy made the request itself.
+        let req = request(Some(PROXY), &[]);
+        assert_eq!(client_ip(&req, &[ip(PROXY)]), Some(ip(PROXY)));
+    }
+
+    #[test]
+    fn through_a_chain_of_trusted_proxies() {
+        let proxies = [ip(PROXY), ip("10.0.0.2"), ip("::1")];
+        let req = request(Some(PROXY), &["198.51.100.7, ::1", "10.0.0.2"]);
+        assert_eq!(client_ip(&req, &proxies), Some(ip("198.51.100.7")));
+    }
+
+    #[test]
+    fn untrusted_hop_stops_the_walk() {
+        // Whatever the client put in front of its own address could be made up.
+        let req = request(Some(PROXY), &["192.0.2.66, 203.0.113.9, 10.0.0.2"]);
+        let proxies = [ip(PROXY), ip("10.0.0.2")];
+        assert_eq!(client_ip(&req, &proxies), Some(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn garbage_hop_stops_the_walk() {
+        let req = request(Some(PROXY), &["192.0.2.66, not-an-address, 10.0.0.2"]);
+        let proxies = [ip(PROXY), ip("10.0.0.2")];
+        assert_eq!(client_ip(&req, &proxies), Some(ip("10.0.0.2")));
+        let req = request(Some(PROXY), &["unknown"]);
+        assert_eq!(client_ip(&req, &proxies), Some(ip(PROXY)));
+    }
+
+    #[test]
+    fn over_a_unix_socket() {
+        let req = request(None, &["198.51.100.7"]);
+        assert_eq!(client_ip(&req, &[]), Some(ip("198.51.100.7")));
+        let req = request(None, &[]);
+        assert_eq!(client_ip(&req, &[]), None);
+    }
+}
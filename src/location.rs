@@ -1,23 +1,46 @@
 use std::time::{Duration, Instant};
 
-use actix_web::{get, http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder};
+use actix_web::{
+    get,
+    http::header::{ContentType, AUTHORIZATION},
+    post, web, HttpRequest, HttpResponse, Responder,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
 use dashmap::DashMap;
 use primitive_types::U512;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    auth::SessionToken,
+    audit::{self, ANONYMOUS},
+    auth::{hash_session_key, SessionToken},
     db,
-    misc::{self, forbidden},
+    filter::{filter_fix, RejectReason},
+    groups::require_visible,
+    keys::{authenticate_key, SCOPE_READ_SELF, SCOPE_READ_SHARED, SCOPE_WRITE},
+    metrics::count_location_update,
+    misc::{self, bad_request, forbidden, internal_error, too_many_requests, SECS_PER_DAY},
+    people::list_by_person,
+    privacy::{recorded_while_paused, viewer_access, Access},
+    ratelimit::{flag_credential_failure, retry_after_secs},
     AppState, LONG_EXPIRY_SECS, SHORT_EXPIRY_SECS,
 };
 
+/// The oldest an OwnTracks fix can be, in seconds. The app holds on to fixes while it's
+/// offline, but not for this long.
+const MAX_FIX_AGE_SECS: u64 = 7 * SECS_PER_DAY;
+
+/// How far ahead of our clock an OwnTracks fix's time can be, for phones whose clocks run fast.
+const MAX_FIX_AHEAD_SECS: u64 = 5 * 60;
+
 #[derive(Clone)]
 pub(crate) struct TokenExpiry {
     /// If a token is unused for a certain duration, it should expire.
     pub(crate) last_used: Instant,
     /// A token has a maximum lifetime, after which it will finally expire.
     pub(crate) issued: Instant,
+    /// The web_user the token was issued to. The cookie has a name in it too, but
+    /// the client can change that one.
+    pub(crate) name: String,
 }
 
 #[derive(Serialize, Clone)]
@@ -40,12 +63,56 @@ pub(crate) struct LocationIn {
     accuracy: f64,
 }
 
+/// A message from the OwnTracks app. Only locations matter to us, but it sends other kinds too.
+#[derive(Deserialize)]
+pub(crate) struct OwnTracksIn {
+    #[serde(rename = "_type")]
+    kind: String,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    /// Meters.
+    acc: Option<f64>,
+    /// When the fix was taken, in seconds since the unix epoch.
+    tst: Option<u64>,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct LocationGetIn {
     id: u64,
 }
 
-fn read_session_token(req: HttpRequest) -> Option<SessionToken> {
+#[derive(Deserialize)]
+pub(crate) struct LocationListIn {
+    #[serde(default)]
+    by: ListBy,
+    /// Only list devices in this group.
+    group: Option<u64>,
+}
+
+/// What /api/location/list lists.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ListBy {
+    /// Every device's api key id and name.
+    #[default]
+    Device,
+    /// Each person's best location out of all their devices.
+    Person,
+}
+
+#[derive(Serialize)]
+struct LocationGetOut {
+    #[serde(flatten)]
+    location: Location,
+    /// Something like "near Springfield, IL", if reverse geocoding is set up.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    near: Option<String>,
+    /// If the owner has paused sharing, when it'll resume, in seconds since the unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hidden_until: Option<u64>,
+}
+
+fn read_session_token(req: &HttpRequest) -> Option<SessionToken> {
     match req.cookies() {
         Ok(cookievec) => {
             for cookie in cookievec.iter() {
@@ -59,69 +126,194 @@ fn read_session_token(req: HttpRequest) -> Option<SessionToken> {
     }
 }
 
+/// Reads the session token from the request's cookies and checks that it's authentic,
+/// refreshing its last-used time. Returns the token if the request should be let through,
+/// with the name swapped out for the one we recorded when the session was issued.
+pub(crate) fn authenticate_session(req: HttpRequest, data: &AppState) -> Option<SessionToken> {
+    let mut token = read_session_token(&req)?;
+
+    log::trace!(
+        "{}: called with session key: {}",
+        req.path(),
+        token.session_key
+    );
+
+    // Confirm that the session key is authentic. One we never issued counts towards locking
+    // out the IP, the same as a bad api key.
+    let hashed = hash_session_key(token.session_key);
+    if !data.session_tokens.contains_key(&hashed) {
+        log::debug!("{}: Bad session key.", req.path());
+        let detail = format!("{} with a session we didn't issue", req.path());
+        audit::record_later(data, &req, ANONYMOUS, "auth.fail.bad_session", detail);
+        flag_credential_failure(&req);
+        return None;
+    }
+    token.name = verify_session_key(hashed, &data.session_tokens)?;
+    Some(token)
+}
+
+/// Someone who's allowed to look at locations.
+pub(crate) struct Reader {
+    /// The web_user, or the owner of the api key.
+    pub(crate) name: String,
+    /// Set for api keys that can only see their owner's own devices.
+    pub(crate) own_only: bool,
+}
+
+/// Works out who's asking to see locations: a device with an api key in the Authorization
+/// header that has one of the read scopes, or else a signed-in web_user, who can see
+/// everything. Returns the response to send back if it's neither.
+pub(crate) async fn authenticate_reader(
+    data: &AppState,
+    req: &HttpRequest,
+) -> Result<Reader, HttpResponse> {
+    if let Some(key) = authenticate_key(data, req).await? {
+        if key.has(SCOPE_READ_SHARED) {
+            return Ok(Reader {
+                name: key.username,
+                own_only: false,
+            });
+        }
+        key.require(data, req, SCOPE_READ_SELF).await?;
+        return Ok(Reader {
+            name: key.username,
+            own_only: true,
+        });
+    }
+
+    // Read the session token from the cookies and confirm that it's authentic.
+    match authenticate_session(req.clone(), data) {
+        Some(session) => Ok(Reader {
+            name: session.name,
+            own_only: false,
+        }),
+        None => Err(forbidden()),
+    }
+}
+
+/// Confirms that a web_user owns a device, meaning the api key was issued under their name.
+/// Returns the response to send back if they don't.
+pub(crate) async fn require_owner(
+    data: &AppState,
+    key_id: u64,
+    name: &str,
+) -> Result<(), HttpResponse> {
+    match db::get_key_owner(&data.pool, key_id).await {
+        Ok(Some(owner)) if owner == name => Ok(()),
+        Ok(_) => {
+            log::debug!("{} doesn't own api key id {}.", name, key_id);
+            Err(forbidden())
+        }
+        Err(e) => {
+            log::error!(
+                "Failed to look up the owner of api key id {}: {}",
+                key_id,
+                e
+            );
+            Err(internal_error())
+        }
+    }
+}
+
 #[get("/api/location/get")]
 pub(crate) async fn get_location_get(
     info: web::Query<LocationGetIn>,
     data: web::Data<AppState>,
     req: HttpRequest,
 ) -> impl Responder {
-    // Read the session token from the cookies.
-    let token = match read_session_token(req) {
-        Some(t) => t,
-        None => return forbidden(),
+    let reader = match authenticate_reader(&data, &req).await {
+        Ok(r) => r,
+        Err(response) => return response,
     };
+    if reader.own_only {
+        if let Err(response) = require_owner(&data, info.id, &reader.name).await {
+            return response;
+        }
+    }
+    if let Err(response) = require_visible(&data, info.id, &reader.name).await {
+        return response;
+    }
 
-    log::trace!(
-        "/api/location/get: called with session key: {}",
-        token.session_key
-    );
+    // Grab the last location measurement, cut down to what the owner lets this viewer see.
+    let access = match viewer_access(&data, info.id, Some(&reader.name)).await {
+        Ok(a) => a,
+        Err(e) => {
+            log::error!("/api/location/get: failed to check privacy policy: {}", e);
+            return internal_error();
+        }
+    };
+    // The newest fix might be from a pause that's over now, and that stays hidden.
+    let last_loc = data
+        .last_location
+        .get(&info.id)
+        .map(|loc| loc.value().clone());
+    let last_loc = match last_loc {
+        Some(loc) => match recorded_while_paused(&data, info.id, Some(&reader.name), &loc).await {
+            Ok(false) => access.apply(&loc, &data),
+            Ok(true) => None,
+            Err(e) => {
+                log::error!("/api/location/get: failed to check privacy pauses: {}", e);
+                return internal_error();
+            }
+        },
+        None => None,
+    };
+    let hidden_until = match access {
+        Access::Hidden { until } => Some(until),
+        Access::Visible(_) => None,
+    };
 
-    // Confirm that the session key is authentic.
-    if !verify_session_key(token.session_key, &data.session_tokens) {
-        return forbidden();
-    }
+    // Describe where it is, if we know any places nearby.
+    let near = match (data.geocoder(), &last_loc) {
+        (Some(g), Some(loc)) => g
+            .nearest(loc.latitude, loc.longitude)
+            .map(|label| format!("near {}", label)),
+        _ => None,
+    };
 
-    // Grab the last location measurement.
-    let last_loc: Location = {
-        match data.last_location.get(&info.id) {
-            Some(loc) => loc.value().to_owned(),
-            None => Location {
+    // Return our serialized data. If there's nothing to show, that's all zeros.
+    HttpResponse::Ok().insert_header(ContentType::json()).body(
+        serde_json::to_string(&LocationGetOut {
+            location: last_loc.unwrap_or(Location {
                 latitude: 0.0,
                 longitude: 0.0,
                 accuracy: 0.0,
                 time: 0,
-            },
-        }
-    };
-    // Return our serialized data.
-    HttpResponse::Ok()
-        .insert_header(ContentType::json())
-        .body(serde_json::to_string(&last_loc).unwrap())
+            }),
+            near,
+            hidden_until,
+        })
+        .unwrap(),
+    )
 }
 
 #[get("/api/location/list")]
 pub(crate) async fn get_location_list(
+    info: web::Query<LocationListIn>,
     data: web::Data<AppState>,
     req: HttpRequest,
 ) -> impl Responder {
-    // Read the session token from the cookies.
-    let token = match read_session_token(req) {
-        Some(t) => t,
-        None => return forbidden(),
+    let reader = match authenticate_reader(&data, &req).await {
+        Ok(r) => r,
+        Err(response) => return response,
     };
 
-    log::trace!(
-        "/api/location/list: called with session key: {}",
-        token.session_key
-    );
-
-    // Confirm that the session key is authentic.
-    if !verify_session_key(token.session_key, &data.session_tokens) {
-        return forbidden();
+    // Only devices in groups the reader shares, or in none at all, and only the ones in the
+    // group they asked about if they did.
+    let visible = match db::visible_key_ids(&data.pool, reader.name.clone(), info.group).await {
+        Ok(v) => v,
+        Err(e) => {
+            log::error!("/api/location/list: failed to check groups: {}", e);
+            return internal_error();
+        }
+    };
+    if let ListBy::Person = info.by {
+        return list_by_person(&data, &reader, &visible).await;
     }
 
-    // Grab the list of api_key ids and names.
-    let names: Vec<(u64, String)> = { data.names.lock().clone() };
+    // Grab the list of api_key ids and names, just the reader's own if that's all they can see.
+    let mut names: Vec<(u64, String)> = { data.names.lock().clone() };
+    names.retain(|(id, name)| visible.contains(id) && (!reader.own_only || *name == reader.name));
 
     // Serialize it and we're off to the races.
     HttpResponse::Ok()
@@ -129,7 +321,12 @@ pub(crate) async fn get_location_list(
         .body(serde_json::to_string(&names).unwrap())
 }
 
-fn verify_session_key(session_key: U512, session_tokens: &DashMap<U512, TokenExpiry>) -> bool {
+/// Checks that a session key's hash is one we issued and that it hasn't expired, and returns
+/// the name of the web_user it belongs to if so.
+fn verify_session_key(
+    session_key: U512,
+    session_tokens: &DashMap<U512, TokenExpiry>,
+) -> Option<String> {
     // Don't bother reconstructing the durations each time, just keep them around.
     static SHORT_EXPIRY: Duration = Duration::from_secs(SHORT_EXPIRY_SECS);
     static LONG_EXPIRY: Duration = Duration::from_secs(LONG_EXPIRY_SECS);
@@ -139,7 +336,7 @@ fn verify_session_key(session_key: U512, session_tokens: &DashMap<U512, TokenExp
         match session_tokens.get(&session_key) {
             None => {
                 log::debug!("/api/location/*: Bad session key.");
-                return false;
+                return None;
             }
             Some(e) => e.value().to_owned(),
         }
@@ -150,7 +347,7 @@ fn verify_session_key(session_key: U512, session_tokens: &DashMap<U512, TokenExp
         // If it is, remove it.
         session_tokens.remove(&session_key);
         log::debug!("/api/location/*: Expired session key.");
-        return false;
+        return None;
     }
 
     // We've gotten through authentication, update the token's last-used time.
@@ -160,47 +357,187 @@ fn verify_session_key(session_key: U512, session_tokens: &DashMap<U512, TokenExp
             TokenExpiry {
                 last_used: Instant::now(),
                 issued: expiry.issued,
+                name: expiry.name.clone(),
             },
         );
     }
-    true
+    Some(expiry.name)
 }
 
 #[derive(Serialize)]
-struct LocationUpdateOut {
+pub(crate) struct LocationUpdateOut {
     time: u64,
+    /// Set if the fix was recorded but didn't pass the filter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejected: Option<RejectReason>,
 }
 
 #[post("/api/location/update")]
 pub(crate) async fn post_location_update(
     info: web::Json<LocationIn>,
     data: web::Data<AppState>,
+    req: HttpRequest,
 ) -> impl Responder {
+    let info = info.into_inner();
+    let fix = Location {
+        latitude: info.latitude,
+        longitude: info.longitude,
+        accuracy: info.accuracy,
+        time: misc::unixtime_now(),
+    };
+    match record_fix(&data, &req, info.api_key, fix).await {
+        Ok(out) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&out).unwrap()),
+        Err(response) => response,
+    }
+}
+
+/// Takes locations from the OwnTracks app, in its own format, which is what the .otrc from
+/// /api/key/provision sets it up to send. The api key is the HTTP basic auth password.
+#[post("/api/location/owntracks")]
+pub(crate) async fn post_location_owntracks(
+    info: web::Json<OwnTracksIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let api_key = match basic_auth_password(&req) {
+        Some(k) => k,
+        None => return forbidden(),
+    };
+    if info.kind != "location" {
+        log::debug!("/api/location/owntracks: ignoring a {} message.", info.kind);
+        return owntracks_ok();
+    }
+    let fix = match (info.lat, info.lon, info.acc, info.tst) {
+        (Some(latitude), Some(longitude), Some(accuracy), Some(time)) => Location {
+            latitude,
+            longitude,
+            accuracy,
+            time,
+        },
+        _ => return bad_request("A location needs lat, lon, acc and tst."),
+    };
+    // The phone's clock is all we have to go on, so make sure it's not way off.
+    let now = misc::unixtime_now();
+    if fix.time > now + MAX_FIX_AHEAD_SECS || fix.time + MAX_FIX_AGE_SECS < now {
+        log::debug!(
+            "/api/location/owntracks: fix time {} is too far off.",
+            fix.time
+        );
+        return bad_request("tst is too far from the current time.");
+    }
+    match record_fix(&data, &req, api_key, fix).await {
+        Ok(_) => owntracks_ok(),
+        Err(response) => response,
+    }
+}
+
+/// OwnTracks wants a JSON array back, of things for it to show. We don't have any.
+fn owntracks_ok() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body("[]")
+}
+
+/// Reads the password out of an HTTP basic Authorization header.
+fn basic_auth_password(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get(AUTHORIZATION)?.to_str().ok()?;
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (_, password) = decoded.split_once(':')?;
+    Some(password.to_string())
+}
+
+/// Takes a fix from a device: checks its api key, stores it, and shows it if it passes the
+/// filter. The fix's time is when it was taken, which the caller has already checked makes
+/// sense. Returns the response to send back if the key's no good or something goes wrong.
+/// Says what's wrong with a fix's numbers, if anything. NaN fails every comparison, so it's
+/// turned away along with the infinities.
+fn check_fix(fix: &Location) -> Result<(), &'static str> {
+    if !(-90.0..=90.0).contains(&fix.latitude) {
+        return Err("Latitude must be between -90 and 90.");
+    }
+    if !(-180.0..=180.0).contains(&fix.longitude) {
+        return Err("Longitude must be between -180 and 180.");
+    }
+    if !(fix.accuracy >= 0.0 && fix.accuracy.is_finite()) {
+        return Err("Accuracy must be a number of meters, at least 0.");
+    }
+    Ok(())
+}
+
+pub(crate) async fn record_fix(
+    data: &AppState,
+    req: &HttpRequest,
+    api_key: String,
+    fix: Location,
+) -> Result<LocationUpdateOut, HttpResponse> {
+    // Don't let anyone hammer the database with key lookups.
+    if let Err(wait) = data.limiter.check_key(&api_key, &data.config().rate_limit) {
+        log::debug!("{}: Rate limited.", req.path());
+        count_location_update("rate_limited");
+        return Err(too_many_requests(retry_after_secs(wait)));
+    }
+
     // Verify the API key with the database and get the associated api_key id and name.
-    let id_name = match db::verify_api_key(&data.pool, info.api_key.clone()).await {
-        Ok(Some(name)) => name,
+    let key = match db::verify_api_key(&data.pool, api_key).await {
+        Ok(Some(key)) => key,
         _ => {
-            log::debug!("/api/location/update: Bad API key.");
-            return forbidden();
+            log::debug!("{}: Bad API key.", req.path());
+            count_location_update("bad_key");
+            let detail = "location update with an unknown or expired key".to_string();
+            audit::record(data, req, ANONYMOUS, "auth.fail.bad_api_key", detail).await;
+            flag_credential_failure(req);
+            return Err(forbidden());
         }
     };
+    audit::note_key_use(data, req, key.id, &key.username).await;
+    if let Err(response) = key.require(data, req, SCOPE_WRITE).await {
+        count_location_update("bad_scope");
+        return Err(response);
+    }
+    let id_name = (key.id, key.username);
 
-    // Record the current time.
-    let now = misc::unixtime_now();
+    // Garbage would poison the filter's track, so it doesn't get that far.
+    if let Err(why) = check_fix(&fix) {
+        log::debug!("{}: Bad fix: {}", req.path(), why);
+        count_location_update("invalid");
+        return Err(bad_request(why));
+    }
+
+    let time = fix.time;
+
+    // Run it past the filter, which gives us the smoothed position to show if it's any good.
+    let filtered = filter_fix(&data.tracks, id_name.0, &fix, &data.config().filter);
+    let rejected = filtered.as_ref().err().copied();
+
+    // Append the raw fix to the stored history, good or not.
+    if let Err(e) =
+        db::insert_location(&data.pool, id_name.0, fix, rejected.map(|r| r.as_str())).await
+    {
+        log::error!("{}: failed to store location: {}", req.path(), e);
+        count_location_update("error");
+        return Err(internal_error());
+    }
+
+    // It's stored, so the track can move on to it.
+    let display = match filtered {
+        Ok(filtered) => filtered.commit(&data.tracks, id_name.0, &data.config().filter),
+        Err(reason) => {
+            log::debug!(
+                "{}: rejected fix from {}: {}",
+                req.path(),
+                id_name.0,
+                reason.as_str()
+            );
+            count_location_update(reason.as_str());
+            return Ok(LocationUpdateOut { time, rejected });
+        }
+    };
 
     // Update the last-seen location.
-    let already_existed = data
-        .last_location
-        .insert(
-            id_name.0,
-            Location {
-                latitude: info.latitude,
-                longitude: info.longitude,
-                accuracy: info.accuracy,
-                time: now,
-            },
-        )
-        .is_some();
+    let already_existed = data.last_location.insert(id_name.0, display).is_some();
 
     // If we hadn't seen that client before, push their name and id into the list.
     if !already_existed {
@@ -208,8 +545,47 @@ pub(crate) async fn post_location_update(
         data.names.lock().push(id_name);
     }
 
+    count_location_update("accepted");
+
     // Let the client know that it was successful, and what time was recorded.
-    HttpResponse::Ok()
-        .insert_header(ContentType::json())
-        .body(serde_json::to_string(&LocationUpdateOut { time: now }).unwrap())
+    Ok(LocationUpdateOut {
+        time,
+        rejected: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix(latitude: f64, longitude: f64, accuracy: f64) -> Location {
+        Location {
+            latitude,
+            longitude,
+            accuracy,
+            time: 0,
+        }
+    }
+
+    #[test]
+    fn sane_fixes_pass() {
+        assert!(check_fix(&fix(51.5, -0.1, 10.0)).is_ok());
+        assert!(check_fix(&fix(90.0, 180.0, 0.0)).is_ok());
+        assert!(check_fix(&fix(-90.0, -180.0, 0.0)).is_ok());
+    }
+
+    #[test]
+    fn nonsense_is_turned_away() {
+        assert!(check_fix(&fix(f64::NAN, 0.0, 10.0)).is_err());
+        assert!(check_fix(&fix(0.0, f64::NAN, 10.0)).is_err());
+        assert!(check_fix(&fix(0.0, 0.0, f64::NAN)).is_err());
+        assert!(check_fix(&fix(f64::INFINITY, 0.0, 10.0)).is_err());
+        assert!(check_fix(&fix(0.0, f64::NEG_INFINITY, 10.0)).is_err());
+        assert!(check_fix(&fix(0.0, 0.0, f64::INFINITY)).is_err());
+        assert!(check_fix(&fix(90.5, 0.0, 10.0)).is_err());
+        assert!(check_fix(&fix(-91.0, 0.0, 10.0)).is_err());
+        assert!(check_fix(&fix(0.0, 180.5, 10.0)).is_err());
+        assert!(check_fix(&fix(0.0, -181.0, 10.0)).is_err());
+        assert!(check_fix(&fix(0.0, 0.0, -1.0)).is_err());
+    }
 }
@@ -1,34 +1,93 @@
-use std::fs::File;
+use std::{net::IpAddr, path::PathBuf, sync::Arc};
 
-use actix_web::middleware::Logger;
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
+use account::{get_account_export, post_account_delete};
+use actix_web::{get, rt, web, App, HttpResponse, HttpServer, Responder};
+use admin::{
+    get_admin_keys, get_admin_users, post_admin_keys_create, post_admin_keys_extend,
+    post_admin_keys_rename, post_admin_keys_revoke, post_admin_keys_rotate, post_admin_keys_scopes,
+    post_admin_reload, post_admin_users_create, post_admin_users_extend, post_admin_users_rename,
+    post_admin_users_revoke,
+};
+use audit::get_admin_audit;
 use auth::{generate_oauth, get_auth_redirect, get_auth_url, OAuth};
-use clap::Parser;
-use cli::Cli;
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use cli::{Cli, Command, ConfigCommand};
 use config::Config;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use db::{create_pool, Pool};
 use env_logger::Env;
-use location::{get_location_get, get_location_list, post_location_update, Location, TokenExpiry};
-use parking_lot::Mutex;
+use filter::TrackState;
+use geocode::Geocoder;
+use groups::{
+    get_admin_groups, get_groups, post_admin_groups_add, post_admin_groups_create,
+    post_admin_groups_delete, post_admin_groups_remove,
+};
+use health::{get_healthz, get_readyz};
+use invite::{
+    get_admin_invites, get_auth_invite, get_invite_device, post_admin_invites_create,
+    post_admin_invites_revoke,
+};
+use keys::post_key_current;
+use listen::Listener;
+use location::{
+    get_location_get, get_location_list, post_location_owntracks, post_location_update, Location,
+    TokenExpiry,
+};
+use metrics::{get_metrics, RequestMetrics};
+use parking_lot::{Mutex, RwLock};
+use people::post_people_primary;
 use primitive_types::U512;
+use privacy::{get_privacy_list, post_privacy_clear, post_privacy_set};
+use provision::get_key_provision;
+use ratelimit::{RateLimit, RateLimiter};
+use share::{get_share_list, get_share_view, post_share_create, post_share_revoke};
+use stats::get_location_stats;
+use timeline::get_location_timeline;
 
+mod account;
+mod admin;
+mod audit;
 mod auth;
 mod cli;
 mod config;
 mod db;
+mod filter;
+mod geo;
+mod geocode;
+mod groups;
+mod health;
+mod invite;
+mod keys;
+mod listen;
 mod location;
+mod metrics;
 mod misc;
+mod people;
+mod persist;
+mod privacy;
+mod provision;
+mod ratelimit;
+mod reload;
+mod retention;
+mod settings;
+mod share;
+mod stats;
+mod systemd;
+mod timeline;
+mod tls;
 
 const SHORT_EXPIRY_SECS: u64 = 60 * 30;
 const LONG_EXPIRY_SECS: u64 = 60 * 60 * 24;
 const LONG_EXPIRY_SECS_I: i64 = LONG_EXPIRY_SECS as i64;
 
 struct AppState {
-    // The session valid tokens and when they expire.
+    // The valid session tokens and when they expire, by the hash of their keys.
     session_tokens: DashMap<U512, TokenExpiry>,
-    /// The last location that we got from each client, by api key id.
+    /// The last location that we got from each client, by api key id. This is the
+    /// smoothed display position, not the raw fix.
     last_location: DashMap<u64, Location>,
+    /// The filter and smoother state for each client, by api key id.
+    tracks: DashMap<u64, TrackState>,
     /// A list of active api key ids and their names. This is appended to
     /// when we record the first location for a given api key id.
     names: Mutex<Vec<(u64, String)>>,
@@ -36,8 +95,32 @@ struct AppState {
     auth: OAuth,
     /// The connection pool for the database.
     pool: Pool,
-    /// The configuration options, parsed at startup.
-    config: Config,
+    /// The places index for reverse geocoding, if it's configured.
+    geocoder: RwLock<Option<Arc<Geocoder>>>,
+    /// The api key ids we've seen each address use, so the first time for each can go in the
+    /// audit log. The database remembers them across restarts.
+    key_ips: DashSet<(u64, IpAddr)>,
+    /// Who's been sending how much, for rate limiting.
+    limiter: RateLimiter,
+    /// The secret that share links are signed with.
+    share_secret: Vec<u8>,
+    /// The configuration options, parsed at startup and swapped out when reloaded.
+    config: RwLock<Arc<Config>>,
+    /// Where the config came from, for reloading it.
+    config_path: PathBuf,
+}
+
+impl AppState {
+    /// The current configuration. Grab it once per request, rather than over and over, so a
+    /// reload partway through doesn't leave it seeing a mix of old and new settings.
+    fn config(&self) -> Arc<Config> {
+        self.config.read().clone()
+    }
+
+    /// The current places index, if reverse geocoding is configured.
+    fn geocoder(&self) -> Option<Arc<Geocoder>> {
+        self.geocoder.read().clone()
+    }
 }
 
 #[get("/api/")]
@@ -47,49 +130,219 @@ async fn hello() -> impl Responder {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    // Initialize the log level from environment variables.
+    env_logger::init_from_env(Env::default().default_filter_or("info"));
+
     // Parse the flags to get the config file location.
     let cli = Cli::parse();
 
-    // Open the config file and parse it.
-    let configfile = File::open(cli.config).expect("Config file doesn't exist.");
-    let config: Config = serde_json::from_reader(configfile).expect("Bad config file format.");
+    // The schema doesn't need a config, and checking the config can't wait until it's loaded.
+    if let Some(
+        Command::Schema
+        | Command::Config {
+            command: ConfigCommand::Schema,
+        },
+    ) = cli.command
+    {
+        return settings::cli_schema();
+    }
+    let Some(config_path) = cli.config else {
+        Cli::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--config <FILE> is required",
+            )
+            .exit();
+    };
+    if let Some(Command::Config {
+        command: ConfigCommand::Check,
+    }) = cli.command
+    {
+        return settings::cli_check(&config_path);
+    }
+
+    // Put the config together from the file and whatever's layered on top, and check it.
+    let config = settings::load_or_exit(&config_path);
+
+    // If we've been asked to do an admin task instead of serving, do it and get out.
+    match cli.command {
+        Some(Command::ExportUser { username, output }) => {
+            return account::cli_export_user(&create_pool(&config), username, &output).await;
+        }
+        Some(Command::DeleteUser { username }) => {
+            return account::cli_delete_user(&create_pool(&config), username).await;
+        }
+        Some(Command::Provision {
+            id,
+            format,
+            qr,
+            output,
+        }) => {
+            let pool = create_pool(&config);
+            return provision::cli_provision(&pool, &config, id, format, qr, output.as_deref())
+                .await;
+        }
+        Some(Command::Schema | Command::Config { .. }) => unreachable!(),
+        None => {}
+    }
 
     // Clone the configured listen addresses, we'll need them in a moment.
     let listens = config.listen.clone();
+    let metrics_listen = config.metrics.as_ref().map(|m| m.listen.clone());
 
     // Build the global state.
     let state = web::Data::new(AppState {
         session_tokens: DashMap::with_capacity(2),
         last_location: DashMap::with_capacity(2),
+        tracks: DashMap::with_capacity(2),
         names: Mutex::new(Vec::with_capacity(2)),
         auth: generate_oauth(&config),
         pool: create_pool(&config),
-        config,
+        geocoder: RwLock::new(
+            config
+                .geocoder
+                .as_ref()
+                .map(|g| Arc::new(Geocoder::load(g).expect("Failed to load places file."))),
+        ),
+        key_ips: DashSet::with_capacity(8),
+        limiter: RateLimiter::new(),
+        share_secret: share::share_secret(&config),
+        config: RwLock::new(Arc::new(config)),
+        config_path,
     });
 
-    // Initialize the log level from environment variables.
-    env_logger::init_from_env(Env::default().default_filter_or("info"));
+    // Bring back the sessions and positions from before the last restart.
+    if let Err(e) = persist::restore_state(&state).await {
+        log::error!("Failed to restore state from the last run: {}", e);
+    }
+
+    // Start enforcing the retention rules in the background.
+    retention::spawn_retention(state.clone());
+    ratelimit::spawn_pruning(state.clone());
+    reload::spawn_sighup(state.clone());
+
+    // The certificates for any TLS listen addresses, so they can be reloaded when renewed.
+    let mut certs = Vec::new();
+
+    // If metrics have their own listen address, give them their own server there.
+    if let Some(Some(listen)) = &metrics_listen {
+        let state = state.clone();
+        let metrics_server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .service(get_metrics)
+                .wrap(listen::logger())
+        })
+        .workers(1);
+        let metrics_server = match listen::open(listen, &mut certs)? {
+            Listener::Tcp(addr, None) => metrics_server.bind(addr)?,
+            Listener::Tcp(addr, Some(tls_config)) => {
+                metrics_server.bind_rustls(addr, *tls_config)?
+            }
+            Listener::TcpSocket(lst) => metrics_server.listen(lst)?,
+            Listener::Unix(lst) => metrics_server.listen_uds(lst)?,
+        };
+        rt::spawn(metrics_server.run());
+    }
+
+    // Hang on to the state, the server closure's about to take it.
+    let flush_state = state.clone();
 
     // Construct the server object with all the APIs,
     // the global data, and the logger.
     let mut server = HttpServer::new(move || {
+        // Only serve metrics alongside the API if they're on and don't have a place of their own.
+        let metrics_here = matches!(metrics_listen, Some(None));
         App::new()
             .app_data(state.clone())
+            .configure(|cfg| {
+                if metrics_here {
+                    cfg.service(get_metrics);
+                }
+            })
             .service(hello)
+            .service(get_healthz)
+            .service(get_readyz)
             .service(get_location_get)
             .service(post_location_update)
+            .service(post_key_current)
+            .service(get_key_provision)
+            .service(post_location_owntracks)
             .service(get_location_list)
+            .service(get_location_timeline)
+            .service(get_location_stats)
+            .service(post_people_primary)
+            .service(get_groups)
+            .service(get_invite_device)
+            .service(get_privacy_list)
+            .service(post_privacy_set)
+            .service(post_privacy_clear)
+            .service(post_share_create)
+            .service(get_share_list)
+            .service(post_share_revoke)
+            .service(get_share_view)
+            .service(get_account_export)
+            .service(post_account_delete)
+            .service(post_admin_reload)
+            .service(get_admin_keys)
+            .service(post_admin_keys_create)
+            .service(post_admin_keys_extend)
+            .service(post_admin_keys_rotate)
+            .service(post_admin_keys_scopes)
+            .service(post_admin_keys_revoke)
+            .service(post_admin_keys_rename)
+            .service(get_admin_users)
+            .service(post_admin_users_create)
+            .service(post_admin_users_extend)
+            .service(post_admin_users_revoke)
+            .service(post_admin_users_rename)
+            .service(get_admin_groups)
+            .service(post_admin_groups_create)
+            .service(post_admin_groups_delete)
+            .service(post_admin_groups_add)
+            .service(post_admin_groups_remove)
+            .service(get_admin_invites)
+            .service(post_admin_invites_create)
+            .service(post_admin_invites_revoke)
+            .service(get_admin_audit)
             .service(get_auth_url)
             .service(get_auth_redirect)
-            .wrap(Logger::default())
+            .service(get_auth_invite)
+            .wrap(RateLimit)
+            .wrap(RequestMetrics)
+            .wrap(listen::logger())
     });
 
-    // Iterate the configured listen addresses and bind the server
-    // to each one.
+    // Take any sockets systemd made for us, then iterate the configured listen addresses
+    // and bind the server to each one, with TLS if it's asked for.
+    let mut listeners = listen::activated()?;
     for elem in listens {
-        server = server.bind((elem.addr, elem.port))?;
+        listeners.push(listen::open(&elem, &mut certs)?);
+    }
+    for listener in listeners {
+        server = match listener {
+            Listener::Tcp(addr, None) => server.bind(addr)?,
+            Listener::Tcp(addr, Some(tls_config)) => server.bind_rustls(addr, *tls_config)?,
+            Listener::TcpSocket(lst) => server.listen(lst)?,
+            Listener::Unix(lst) => server.listen_uds(lst)?,
+        };
     }
 
-    // Aaand we're home-free.
-    server.run().await
+    // Keep an eye out for renewed certificates.
+    tls::spawn_reloading(certs);
+
+    // Aaand we're home-free. The server stops taking connections on SIGTERM or SIGINT, and
+    // finishes off the requests it's got before this returns.
+    let server = server.run();
+    systemd::notify_ready();
+    systemd::spawn_watchdog();
+    let result = server.await;
+
+    // Save what we'd otherwise lose, so the next run can pick up from here.
+    systemd::notify_stopping();
+    match persist::save_state(&flush_state).await {
+        Ok(sessions) => log::info!("Saved {} sessions for the next run.", sessions),
+        Err(e) => log::error!("Failed to save sessions for the next run: {}", e),
+    }
+    result
 }
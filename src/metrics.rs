@@ -0,0 +1,293 @@
+use std::{
+    future::{ready, Future, Ready},
+    pin::Pin,
+    rc::Rc,
+    sync::OnceLock,
+    time::Instant,
+};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    get,
+    http::{header::AUTHORIZATION, Method},
+    web, Error, HttpRequest, HttpResponse, Responder,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    misc::{forbidden, internal_error, unixtime_now},
+    AppState,
+};
+
+/// Buckets for database timings, in seconds. SQLite on an SD card is usually quick, and
+/// when it isn't, it's really not.
+const DB_BUCKETS: &[f64] = &[0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// All the series we export. There's one of these for the whole process, since the database
+/// layer has no AppState to hang it off.
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// By method, route pattern and status code.
+    requests: IntCounterVec,
+    /// By method and route pattern.
+    request_seconds: HistogramVec,
+    /// By result: "accepted", or why not.
+    location_updates: IntCounterVec,
+    /// How long it took to get a connection out of the pool.
+    pub(crate) db_pool_wait_seconds: Histogram,
+    /// How long queries took, once they had a connection.
+    pub(crate) db_query_seconds: Histogram,
+    sessions: IntGauge,
+    pkce_verifiers: IntGauge,
+    /// By api key id.
+    last_fix_age_seconds: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("locationapp".to_string()), None)
+            .expect("Bad metrics prefix");
+        let requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled."),
+            &["method", "route", "status"],
+        )
+        .unwrap();
+        let request_seconds = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency."),
+            &["method", "route"],
+        )
+        .unwrap();
+        let location_updates = IntCounterVec::new(
+            Opts::new(
+                "location_updates_total",
+                "Location updates received, by whether they were accepted and why not.",
+            ),
+            &["result"],
+        )
+        .unwrap();
+        let db_pool_wait_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "db_pool_wait_seconds",
+                "Time spent waiting for a database connection.",
+            )
+            .buckets(DB_BUCKETS.to_vec()),
+        )
+        .unwrap();
+        let db_query_seconds = Histogram::with_opts(
+            HistogramOpts::new("db_query_duration_seconds", "Database query latency.")
+                .buckets(DB_BUCKETS.to_vec()),
+        )
+        .unwrap();
+        let sessions = IntGauge::new("active_sessions", "Live web sessions.").unwrap();
+        let pkce_verifiers = IntGauge::new(
+            "pending_pkce_verifiers",
+            "OAuth logins that have started but not finished.",
+        )
+        .unwrap();
+        let last_fix_age_seconds = IntGaugeVec::new(
+            Opts::new(
+                "last_fix_age_seconds",
+                "Seconds since each device's last accepted fix.",
+            ),
+            &["key_id"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests.clone())).unwrap();
+        registry
+            .register(Box::new(request_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(location_updates.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_pool_wait_seconds.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(db_query_seconds.clone()))
+            .unwrap();
+        registry.register(Box::new(sessions.clone())).unwrap();
+        registry.register(Box::new(pkce_verifiers.clone())).unwrap();
+        registry
+            .register(Box::new(last_fix_age_seconds.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            requests,
+            request_seconds,
+            location_updates,
+            db_pool_wait_seconds,
+            db_query_seconds,
+            sessions,
+            pkce_verifiers,
+            last_fix_age_seconds,
+        }
+    }
+
+    /// Takes readings of the things we only measure at scrape time, then writes everything out
+    /// in the Prometheus text format.
+    fn render(&self, data: &AppState) -> prometheus::Result<Vec<u8>> {
+        self.sessions.set(data.session_tokens.len() as i64);
+        self.pkce_verifiers.set(data.auth.pending_logins() as i64);
+
+        // Start from scratch, so deleted devices drop out.
+        let now = unixtime_now();
+        self.last_fix_age_seconds.reset();
+        for loc in data.last_location.iter() {
+            self.last_fix_age_seconds
+                .with_label_values(&[&loc.key().to_string()])
+                .set(now.saturating_sub(loc.value().time) as i64);
+        }
+
+        let mut out = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut out)?;
+        Ok(out)
+    }
+}
+
+/// Gets the process's metrics.
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Counts a location update, by what became of it: "accepted", or why not.
+pub(crate) fn count_location_update(result: &str) {
+    metrics()
+        .location_updates
+        .with_label_values(&[result])
+        .inc();
+}
+
+#[get("/metrics")]
+pub(crate) async fn get_metrics(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    // This is only served if metrics were on at startup, and a reload can't turn them off, but
+    // the token can change. If there's one configured, the scraper has to have it.
+    let config = data.config();
+    if let Some(token) = config.metrics.as_ref().and_then(|m| m.token.as_ref()) {
+        let given = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "));
+        // Compare in constant time, so the token can't be worked out a byte at a time.
+        if !given.is_some_and(|given| bool::from(given.as_bytes().ct_eq(token.as_bytes()))) {
+            log::debug!("/metrics: Bad token.");
+            return forbidden();
+        }
+    }
+
+    match metrics().render(&data) {
+        Ok(out) => HttpResponse::Ok()
+            .content_type(TextEncoder::new().format_type())
+            .body(out),
+        Err(e) => {
+            log::error!("/metrics: failed to encode metrics: {}", e);
+            internal_error()
+        }
+    }
+}
+
+/// Middleware that counts requests and times them, by route.
+pub(crate) struct RequestMetrics;
+
+/// The methods that get their own label. Anyone can make up a method, so the rest are lumped
+/// together rather than each getting its own series.
+const KNOWN_METHODS: [Method; 9] = [
+    Method::GET,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::HEAD,
+    Method::OPTIONS,
+    Method::CONNECT,
+    Method::PATCH,
+    Method::TRACE,
+];
+
+/// The method label for a request: its method if it's a standard one, or "other".
+fn method_label(method: &Method) -> &str {
+    if KNOWN_METHODS.contains(method) {
+        method.as_str()
+    } else {
+        "other"
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub(crate) struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let response = fut.await?;
+            // Label by the route pattern rather than the path, so query strings and ids
+            // don't blow up the number of series.
+            let request = response.request();
+            let method = method_label(request.method());
+            let route = request
+                .match_pattern()
+                .unwrap_or_else(|| "unmatched".to_string());
+            let m = metrics();
+            m.requests
+                .with_label_values(&[method, &route, response.status().as_str()])
+                .inc();
+            m.request_seconds
+                .with_label_values(&[method, &route])
+                .observe(start.elapsed().as_secs_f64());
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn made_up_methods_are_other() {
+        assert_eq!(method_label(&Method::GET), "GET");
+        assert_eq!(method_label(&Method::PATCH), "PATCH");
+        assert_eq!(method_label(&Method::from_bytes(b"BREW").unwrap()), "other");
+        assert_eq!(
+            method_label(&Method::from_bytes(b"PROPFIND").unwrap()),
+            "other"
+        );
+    }
+}
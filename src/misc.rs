@@ -1,6 +1,12 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use actix_web::{http::header::ContentType, HttpResponse};
+use actix_web::{
+    cookie::time::{Date, Month},
+    http::header::{CacheControl, CacheDirective, ContentType, RETRY_AFTER},
+    HttpResponse,
+};
+
+pub const SECS_PER_DAY: u64 = 60 * 60 * 24;
 
 /// Gets the current unix time in seconds. Pretty self-explanatory.
 pub fn unixtime_now() -> u64 {
@@ -10,9 +16,54 @@ pub fn unixtime_now() -> u64 {
         .as_secs()
 }
 
+/// Parses a YYYY-MM-DD string into a date.
+pub fn parse_date(date: &str) -> Option<Date> {
+    let mut parts = date.splitn(3, '-');
+    let year: i32 = parts.next()?.parse().ok()?;
+    let month: u8 = parts.next()?.parse().ok()?;
+    let day: u8 = parts.next()?.parse().ok()?;
+    Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()
+}
+
+/// Works out the stretch of unix time, start inclusive and end exclusive, that a YYYY-MM-DD day
+/// covers for someone whose clock is utc_offset minutes ahead of UTC.
+pub fn day_bounds(date: &str, utc_offset: i64) -> Option<(u64, u64)> {
+    let start = parse_date(date)?.midnight().assume_utc().unix_timestamp();
+    let start = u64::try_from(start.checked_sub(utc_offset.checked_mul(60)?)?).ok()?;
+    Some((start, start.checked_add(SECS_PER_DAY)?))
+}
+
+/// The Cache-Control header for responses with a key in them, so browsers and proxies don't
+/// hang on to it.
+pub fn no_store() -> CacheControl {
+    CacheControl(vec![CacheDirective::NoStore])
+}
+
 // This is the API's 403 page.
 pub fn forbidden() -> HttpResponse {
     HttpResponse::Forbidden()
         .insert_header(ContentType::json())
         .body("{\"err\":\"Authorization failed.\"}")
 }
+
+// This is the API's 500 page.
+pub fn internal_error() -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .insert_header(ContentType::json())
+        .body("{\"err\":\"Something went wrong.\"}")
+}
+
+// This is the API's 429 page.
+pub fn too_many_requests(retry_after_secs: u64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .insert_header(ContentType::json())
+        .insert_header((RETRY_AFTER, retry_after_secs.to_string()))
+        .body("{\"err\":\"Too many requests.\"}")
+}
+
+// This is the API's 400 page.
+pub fn bad_request(why: &str) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .insert_header(ContentType::json())
+        .body(serde_json::json!({ "err": why }).to_string())
+}
@@ -0,0 +1,212 @@
+use std::collections::HashSet;
+
+use actix_web::{http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db,
+    location::{authenticate_session, require_owner, Location, Reader},
+    misc::{forbidden, internal_error},
+    privacy::{recorded_while_paused, viewer_access},
+    AppState,
+};
+
+/// How far behind a person's newest fix another device's can be and still be in the running
+/// for where they are. Anything older than that has been overtaken.
+const FRESH_SECS: u64 = 5 * 60;
+
+/// Someone with one or more devices, as they're kept in the people table.
+pub(crate) struct Person {
+    pub(crate) id: u64,
+    pub(crate) name: String,
+    /// The api key id of the device that wins ties, if they've picked one.
+    pub(crate) primary_key_id: Option<u64>,
+    /// Their devices' api key ids.
+    pub(crate) key_ids: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct PersonLocationOut {
+    /// The person's id.
+    person: u64,
+    name: String,
+    /// The api key id of the device the location came from.
+    key_id: u64,
+    location: Location,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PrimaryIn {
+    /// The api key id of the device to make primary.
+    id: u64,
+}
+
+/// Picks the best of a person's devices' latest fixes, and returns its index. Of the ones that
+/// are about as recent as the newest, that's the primary device's if it's among them, and
+/// otherwise whichever is most accurate.
+fn best_fix(candidates: &[(u64, Location)], primary: Option<u64>) -> Option<usize> {
+    let newest = candidates.iter().map(|(_, loc)| loc.time).max()?;
+    let fresh = candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, loc))| loc.time + FRESH_SECS >= newest);
+    if let Some((i, _)) = fresh
+        .clone()
+        .find(|(_, (key_id, _))| Some(*key_id) == primary)
+    {
+        return Some(i);
+    }
+    fresh
+        .min_by(|(_, (_, a)), (_, (_, b))| a.accuracy.total_cmp(&b.accuracy))
+        .map(|(i, _)| i)
+}
+
+/// Lists one location per person, the best of what their visible devices have reported that
+/// the reader gets to see.
+pub(crate) async fn list_by_person(
+    data: &AppState,
+    reader: &Reader,
+    visible: &HashSet<u64>,
+) -> HttpResponse {
+    let mut people = match db::list_people(&data.pool).await {
+        Ok(p) => p,
+        Err(e) => {
+            log::error!("/api/location/list: failed to list people: {}", e);
+            return internal_error();
+        }
+    };
+    if reader.own_only {
+        people.retain(|person| person.name == reader.name);
+    }
+
+    let mut out = Vec::with_capacity(people.len());
+    for person in people {
+        // Pick from the raw fixes, so one viewer's blurring doesn't change which device wins.
+        let mut candidates = Vec::with_capacity(person.key_ids.len());
+        for &key_id in person.key_ids.iter().filter(|id| visible.contains(id)) {
+            if let Some(loc) = data.last_location.get(&key_id) {
+                candidates.push((key_id, loc.value().clone()));
+            }
+        }
+        let mut visible = Vec::with_capacity(candidates.len());
+        let mut accesses = Vec::with_capacity(candidates.len());
+        for (key_id, loc) in candidates {
+            let access = match viewer_access(data, key_id, Some(&reader.name)).await {
+                Ok(access) => access,
+                Err(e) => {
+                    log::error!("/api/location/list: failed to check privacy policy: {}", e);
+                    return internal_error();
+                }
+            };
+            match recorded_while_paused(data, key_id, Some(&reader.name), &loc).await {
+                Ok(false) if access.apply(&loc, data).is_some() => {
+                    visible.push((key_id, loc));
+                    accesses.push(access);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("/api/location/list: failed to check privacy pauses: {}", e);
+                    return internal_error();
+                }
+            }
+        }
+        let Some(i) = best_fix(&visible, person.primary_key_id) else {
+            continue;
+        };
+        let (key_id, loc) = &visible[i];
+        if let Some(location) = accesses[i].apply(loc, data) {
+            out.push(PersonLocationOut {
+                person: person.id,
+                name: person.name,
+                key_id: *key_id,
+                location,
+            });
+        }
+    }
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body(serde_json::to_string(&out).unwrap())
+}
+
+#[post("/api/people/primary")]
+pub(crate) async fn post_people_primary(
+    info: web::Json<PrimaryIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+    if let Err(response) = require_owner(&data, info.id, &session.name).await {
+        return response;
+    }
+
+    match db::set_primary_device(&data.pool, info.id).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            log::error!(
+                "/api/people/primary: failed to set the primary device: {}",
+                e
+            );
+            internal_error()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fix(accuracy: f64, time: u64) -> Location {
+        Location {
+            latitude: 0.0,
+            longitude: 0.0,
+            accuracy,
+            time,
+        }
+    }
+
+    #[test]
+    fn nothing_to_pick_from() {
+        assert_eq!(best_fix(&[], None), None);
+        assert_eq!(best_fix(&[], Some(1)), None);
+    }
+
+    #[test]
+    fn most_accurate_of_the_fresh_ones() {
+        let candidates = [
+            (1, fix(50.0, 1000)),
+            (2, fix(10.0, 990)),
+            (3, fix(5.0, 500)),
+        ];
+        // Device 3 is the most accurate, but it's been overtaken.
+        assert_eq!(best_fix(&candidates, None), Some(1));
+    }
+
+    #[test]
+    fn primary_wins_while_its_fresh() {
+        let candidates = [(1, fix(5.0, 1000)), (2, fix(80.0, 1000 - FRESH_SECS))];
+        assert_eq!(best_fix(&candidates, Some(2)), Some(1));
+        // A second older and it's out of the running, primary or not.
+        let candidates = [(1, fix(5.0, 1000)), (2, fix(80.0, 999 - FRESH_SECS))];
+        assert_eq!(best_fix(&candidates, Some(2)), Some(0));
+    }
+
+    #[test]
+    fn primary_that_isnt_a_candidate() {
+        let candidates = [(1, fix(20.0, 1000)), (2, fix(10.0, 1000))];
+        assert_eq!(best_fix(&candidates, Some(7)), Some(1));
+    }
+
+    #[test]
+    fn ties_go_to_the_first() {
+        let candidates = [
+            (1, fix(10.0, 1000)),
+            (2, fix(10.0, 1000)),
+            (3, fix(10.0, 990)),
+        ];
+        assert_eq!(best_fix(&candidates, None), Some(0));
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+use primitive_types::U512;
+
+use crate::{db, misc::unixtime_now, AppState, TokenExpiry, LONG_EXPIRY_SECS, SHORT_EXPIRY_SECS};
+
+/// A sessions row: a web session, kept in the database while the server's down.
+pub(crate) struct SavedSession {
+    /// The hash of the session key, in hex. The key itself is never written down.
+    pub(crate) token: String,
+    pub(crate) username: String,
+    /// Seconds since the unix epoch.
+    pub(crate) issued: u64,
+    /// Seconds since the unix epoch.
+    pub(crate) last_used: u64,
+}
+
+/// Turns a unix time in the past into an Instant, if it's not from before the clock started.
+fn instant_at(now: u64, time: u64) -> Option<Instant> {
+    Instant::now().checked_sub(Duration::from_secs(now.saturating_sub(time)))
+}
+
+/// Writes out whatever only lives in memory and would be missed after a restart. Location
+/// fixes are already stored as they come in, so that's just the sessions.
+pub(crate) async fn save_state(data: &AppState) -> Result<usize, actix_web::Error> {
+    let now = unixtime_now();
+    let sessions: Vec<SavedSession> = data
+        .session_tokens
+        .iter()
+        .map(|s| SavedSession {
+            token: format!("{:x}", s.key()),
+            username: s.value().name.clone(),
+            issued: now.saturating_sub(s.value().issued.elapsed().as_secs()),
+            last_used: now.saturating_sub(s.value().last_used.elapsed().as_secs()),
+        })
+        .collect();
+    let count = sessions.len();
+    db::save_sessions(&data.pool, sessions).await?;
+    Ok(count)
+}
+
+/// Picks up where the last run left off: the sessions it saved, and each device's last
+/// position from the stored history.
+pub(crate) async fn restore_state(data: &AppState) -> Result<(), actix_web::Error> {
+    let now = unixtime_now();
+    let mut sessions = 0;
+    for saved in db::take_sessions(&data.pool).await? {
+        // Don't bring back anything that would've expired while we were down.
+        if now.saturating_sub(saved.last_used) >= SHORT_EXPIRY_SECS
+            || now.saturating_sub(saved.issued) >= LONG_EXPIRY_SECS
+        {
+            continue;
+        }
+        let (Ok(token), Some(issued), Some(last_used)) = (
+            U512::from_str_radix(&saved.token, 16),
+            instant_at(now, saved.issued),
+            instant_at(now, saved.last_used),
+        ) else {
+            continue;
+        };
+        data.session_tokens.insert(
+            token,
+            TokenExpiry {
+                last_used,
+                issued,
+                name: saved.username,
+            },
+        );
+        sessions += 1;
+    }
+
+    let latest = db::latest_locations(&data.pool).await?;
+    let devices = latest.len();
+    let mut names = data.names.lock();
+    for (key_id, username, location) in latest {
+        data.last_location.insert(key_id, location);
+        names.push((key_id, username));
+    }
+
+    log::info!(
+        "Restored {} sessions and the last positions of {} devices.",
+        sessions,
+        devices
+    );
+    Ok(())
+}
@@ -0,0 +1,241 @@
+use actix_web::{get, http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db,
+    geo::reduce_precision,
+    location::{authenticate_session, require_owner, Location},
+    misc::{bad_request, forbidden, internal_error, unixtime_now},
+    AppState,
+};
+
+/// The viewer name that a policy uses to apply to everybody without a policy of their own.
+pub(crate) const EVERYONE: &str = "*";
+
+/// How coarse "city-level" is, in meters, when there's no geocoder to find the city with.
+const CITY_FALLBACK_METERS: f64 = 10_000.0;
+
+/// How precise a location a viewer gets to see.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub(crate) enum Precision {
+    /// The location exactly as the device reported it.
+    Exact,
+    /// The location snapped to a grid this many meters on a side.
+    Grid { meters: f64 },
+    /// Just the nearest city.
+    City,
+}
+
+/// What a device's owner lets one viewer (or everyone) see of that device.
+#[derive(Serialize, Clone)]
+pub(crate) struct PrivacyPolicy {
+    /// The api key id the policy covers.
+    pub(crate) key_id: u64,
+    /// The web_user the policy applies to, or EVERYONE.
+    pub(crate) viewer: String,
+    #[serde(flatten)]
+    pub(crate) precision: Precision,
+    /// If set and in the future, the location is hidden entirely until then.
+    pub(crate) paused_until: Option<u64>,
+}
+
+/// What a viewer gets to see of a device.
+pub(crate) enum Access {
+    /// Its locations, at this precision.
+    Visible(Precision),
+    /// Nothing. The owner has paused sharing until this time, in seconds since the unix epoch.
+    Hidden { until: u64 },
+}
+
+impl Access {
+    /// Cuts a location down to what the viewer gets to see of it, if anything.
+    pub(crate) fn apply(&self, loc: &Location, data: &AppState) -> Option<Location> {
+        match self {
+            Access::Visible(precision) => Some(coarsen(loc, *precision, data)),
+            Access::Hidden { .. } => None,
+        }
+    }
+
+    /// Whether the viewer gets to see everything, exactly as it was recorded.
+    pub(crate) fn is_exact(&self) -> bool {
+        matches!(self, Access::Visible(Precision::Exact))
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PrivacyListIn {
+    /// The api key id to list policies for.
+    id: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PrivacySetIn {
+    /// The api key id to set a policy for.
+    id: u64,
+    /// The web_user it applies to. Leave it out to set the policy for everyone.
+    viewer: Option<String>,
+    #[serde(flatten)]
+    precision: Precision,
+    paused_until: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PrivacyClearIn {
+    /// The api key id to clear a policy for.
+    id: u64,
+    /// The web_user whose policy to clear. Leave it out for the everyone policy.
+    viewer: Option<String>,
+}
+
+/// Applies a precision setting to a location.
+fn coarsen(loc: &Location, precision: Precision, data: &AppState) -> Location {
+    match precision {
+        Precision::Exact => loc.clone(),
+        Precision::Grid { meters } => reduce_precision(loc, meters),
+        // Put them in the middle of the nearest city if we know of one, otherwise just
+        // blur them a lot.
+        Precision::City => match data
+            .geocoder()
+            .as_ref()
+            .and_then(|g| g.nearest_place(loc.latitude, loc.longitude))
+        {
+            Some(place) => Location {
+                latitude: place.latitude,
+                longitude: place.longitude,
+                accuracy: loc.accuracy.max(CITY_FALLBACK_METERS),
+                time: loc.time,
+            },
+            None => reduce_precision(loc, CITY_FALLBACK_METERS),
+        },
+    }
+}
+
+/// Works out what a viewer gets to see of a device, going by the owner's policies. The owner
+/// always sees everything. A viewer of None is someone without a web_user at all, like a
+/// share link's recipient, who only gets the everyone policy.
+pub(crate) async fn viewer_access(
+    data: &AppState,
+    key_id: u64,
+    viewer: Option<&str>,
+) -> Result<Access, actix_web::Error> {
+    if let Some(viewer) = viewer {
+        if db::get_key_owner(&data.pool, key_id).await?.as_deref() == Some(viewer) {
+            return Ok(Access::Visible(Precision::Exact));
+        }
+    }
+
+    let policy =
+        match db::get_privacy_policy(&data.pool, key_id, viewer.map(str::to_string)).await? {
+            Some(p) => p,
+            None => return Ok(Access::Visible(Precision::Exact)),
+        };
+    if let Some(until) = policy.paused_until.filter(|&u| u > unixtime_now()) {
+        return Ok(Access::Hidden { until });
+    }
+    Ok(Access::Visible(policy.precision))
+}
+
+/// Whether a fix is hidden from a viewer because sharing was paused for them when the device
+/// recorded it, even if the pause is over by now. Viewers are as in viewer_access.
+pub(crate) async fn recorded_while_paused(
+    data: &AppState,
+    key_id: u64,
+    viewer: Option<&str>,
+    loc: &Location,
+) -> Result<bool, actix_web::Error> {
+    let viewer = viewer.unwrap_or(EVERYONE).to_string();
+    db::paused_at(&data.pool, key_id, viewer, loc.time).await
+}
+
+#[get("/api/privacy/list")]
+pub(crate) async fn get_privacy_list(
+    info: web::Query<PrivacyListIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+    if let Err(resp) = require_owner(&data, info.id, &session.name).await {
+        return resp;
+    }
+
+    match db::list_privacy_policies(&data.pool, info.id).await {
+        Ok(policies) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&policies).unwrap()),
+        Err(e) => {
+            log::error!("/api/privacy/list: failed to list policies: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/privacy/set")]
+pub(crate) async fn post_privacy_set(
+    info: web::Json<PrivacySetIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+    if let Err(resp) = require_owner(&data, info.id, &session.name).await {
+        return resp;
+    }
+
+    if let Precision::Grid { meters } = info.precision {
+        if !meters.is_finite() || meters <= 0.0 {
+            return bad_request("meters must be a positive number.");
+        }
+    }
+
+    let info = info.into_inner();
+    let policy = PrivacyPolicy {
+        key_id: info.id,
+        viewer: info.viewer.unwrap_or_else(|| EVERYONE.to_string()),
+        precision: info.precision,
+        paused_until: info.paused_until,
+    };
+    match db::set_privacy_policy(&data.pool, policy).await {
+        Ok(()) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body("{}"),
+        Err(e) => {
+            log::error!("/api/privacy/set: failed to store policy: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/privacy/clear")]
+pub(crate) async fn post_privacy_clear(
+    info: web::Json<PrivacyClearIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+    if let Err(resp) = require_owner(&data, info.id, &session.name).await {
+        return resp;
+    }
+
+    let viewer = info.viewer.clone().unwrap_or_else(|| EVERYONE.to_string());
+    match db::delete_privacy_policy(&data.pool, info.id, viewer).await {
+        Ok(()) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body("{}"),
+        Err(e) => {
+            log::error!("/api/privacy/clear: failed to delete policy: {}", e);
+            internal_error()
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::{io::Cursor, path::Path};
+
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::ValueEnum;
+use image::{DynamicImage, ImageFormat, Luma};
+use oauth2::url::Url;
+use qrcode::{render::svg, QrCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    admin::require_admin,
+    audit,
+    config::Config,
+    db::{self, Pool},
+    misc::{bad_request, internal_error, no_store},
+    AppState,
+};
+
+/// The smallest a QR code is drawn, in pixels on a side, so it's easy to scan off a screen.
+const QR_MIN_PIXELS: u32 = 256;
+
+/// What a phone needs to start reporting locations, all in one place so it can go in a QR
+/// code.
+#[derive(Serialize)]
+pub(crate) struct Provisioning {
+    /// The server, like "https://sub.my-domain.com".
+    pub(crate) server: String,
+    /// Where to post locations to.
+    pub(crate) url: String,
+    /// Whose device it is.
+    pub(crate) username: String,
+    pub(crate) key_id: u64,
+    pub(crate) key: String,
+    /// How often to report, from the provisioning section of the config.
+    pub(crate) moving_interval_secs: u64,
+    pub(crate) idle_interval_secs: u64,
+    pub(crate) displacement_meters: u64,
+}
+
+/// The forms a provisioning payload comes in.
+#[derive(Deserialize, Clone, Copy, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Format {
+    /// The payload itself.
+    #[default]
+    Json,
+    /// An OwnTracks configuration file, to open on the phone.
+    Otrc,
+    /// A QR code, as a PNG image.
+    Png,
+    /// A QR code, as an SVG image.
+    Svg,
+}
+
+/// What goes in a QR code.
+#[derive(Deserialize, Clone, Copy, Default, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QrContent {
+    /// The JSON payload, for our own app.
+    #[default]
+    Json,
+    /// An owntracks:///config link, which the OwnTracks app picks up when it's scanned.
+    Owntracks,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ProvisionIn {
+    /// The api key id to set a phone up with.
+    id: u64,
+    #[serde(default)]
+    format: Format,
+    #[serde(default)]
+    qr: QrContent,
+}
+
+impl Provisioning {
+    pub(crate) fn new(config: &Config, key_id: u64, username: String, key: String) -> Provisioning {
+        let server = format!("https://{}", config.domain_name);
+        Provisioning {
+            url: format!("{}/api/location/update", server),
+            server,
+            username,
+            key_id,
+            key,
+            moving_interval_secs: config.provisioning.moving_interval_secs,
+            idle_interval_secs: config.provisioning.idle_interval_secs,
+            displacement_meters: config.provisioning.displacement_meters,
+        }
+    }
+
+    /// An OwnTracks configuration that reports to /api/location/owntracks, with the key as the
+    /// password.
+    fn otrc(&self) -> serde_json::Value {
+        json!({
+            "_type": "configuration",
+            // HTTP, rather than MQTT.
+            "mode": 3,
+            "url": format!("{}/api/location/owntracks", self.server),
+            "auth": true,
+            "username": self.username,
+            "password": self.key,
+            "deviceId": format!("key{}", self.key_id),
+            // Significant changes mode, which goes by the interval and displacement.
+            "monitoring": 1,
+            "locatorInterval": self.idle_interval_secs,
+            "locatorDisplacement": self.displacement_meters,
+            "moveModeLocatorInterval": self.moving_interval_secs,
+        })
+    }
+
+    /// The text for a QR code.
+    fn qr_text(&self, qr: QrContent) -> String {
+        match qr {
+            QrContent::Json => serde_json::to_string(self).unwrap(),
+            QrContent::Owntracks => {
+                let mut link = Url::parse("owntracks:///config").unwrap();
+                link.query_pairs_mut()
+                    .append_pair("inline", &STANDARD.encode(self.otrc().to_string()));
+                link.to_string()
+            }
+        }
+    }
+
+    /// Puts the payload in the given format, and returns it with its content type.
+    pub(crate) fn render(
+        &self,
+        format: Format,
+        qr: QrContent,
+    ) -> Result<(&'static str, Vec<u8>), String> {
+        let code = || QrCode::new(self.qr_text(qr)).map_err(|e| e.to_string());
+        match format {
+            Format::Json => Ok(("application/json", serde_json::to_vec(self).unwrap())),
+            Format::Otrc => Ok(("application/json", self.otrc().to_string().into_bytes())),
+            Format::Png => {
+                let image = code()?
+                    .render::<Luma<u8>>()
+                    .min_dimensions(QR_MIN_PIXELS, QR_MIN_PIXELS)
+                    .build();
+                let mut png = Cursor::new(Vec::new());
+                DynamicImage::ImageLuma8(image)
+                    .write_to(&mut png, ImageFormat::Png)
+                    .map_err(|e| e.to_string())?;
+                Ok(("image/png", png.into_inner()))
+            }
+            Format::Svg => {
+                let image = code()?
+                    .render::<svg::Color>()
+                    .min_dimensions(QR_MIN_PIXELS, QR_MIN_PIXELS)
+                    .build();
+                Ok(("image/svg+xml", image.into_bytes()))
+            }
+        }
+    }
+}
+
+/// What to call a format in the audit log.
+fn format_name(format: Format) -> &'static str {
+    match format {
+        Format::Json => "json",
+        Format::Otrc => "otrc",
+        Format::Png => "png",
+        Format::Svg => "svg",
+    }
+}
+
+/// Gives what's needed to set a phone up with an api key. Only admins can get it, since it has
+/// the key in it, and they could make a new one anyway.
+#[get("/api/key/provision")]
+pub(crate) async fn get_key_provision(
+    info: web::Query<ProvisionIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let admin = match require_admin(&data, &req).await {
+        Ok(a) => a,
+        Err(response) => return response,
+    };
+
+    let (username, key, _) = match db::get_api_key(&data.pool, info.id).await {
+        Ok(Some(k)) => k,
+        Ok(None) => return bad_request("No such id."),
+        Err(e) => {
+            log::error!("/api/key/provision: failed to look up api key: {}", e);
+            return internal_error();
+        }
+    };
+
+    let payload = Provisioning::new(&data.config(), info.id, username, key);
+    let (content_type, body) = match payload.render(info.format, info.qr) {
+        Ok(rendered) => rendered,
+        Err(e) => {
+            log::error!("/api/key/provision: failed to render payload: {}", e);
+            return internal_error();
+        }
+    };
+    let detail = format!("api key id {} as {}", info.id, format_name(info.format));
+    audit::record(&data, &req, &admin, "api_key.provision", detail).await;
+
+    HttpResponse::Ok()
+        .insert_header(("Content-Type", content_type))
+        .insert_header(no_store())
+        .body(body)
+}
+
+/// The provision command: writes what's needed to set a phone up with an api key to a file, or
+/// to stdout.
+pub(crate) async fn cli_provision(
+    pool: &Pool,
+    config: &Config,
+    id: u64,
+    format: Format,
+    qr: QrContent,
+    output: Option<&Path>,
+) -> std::io::Result<()> {
+    let (username, key, _) = db::get_api_key(pool, id)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No api key with id {} that's still good.", id),
+            )
+        })?;
+    let (_, body) = Provisioning::new(config, id, username, key)
+        .render(format, qr)
+        .map_err(std::io::Error::other)?;
+    match output {
+        Some(output) => std::fs::write(output, body),
+        None => std::io::Write::write_all(&mut std::io::stdout(), &body),
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    future::{ready, Future, Ready},
+    hash::Hash,
+    net::{IpAddr, Ipv6Addr},
+    pin::Pin,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    rt, web, Error, HttpMessage, HttpRequest,
+};
+use dashmap::DashMap;
+
+use crate::{
+    audit::{self, ANONYMOUS},
+    config::{BucketConfig, RateLimitConfig},
+    listen::client_ip,
+    misc::too_many_requests,
+    AppState,
+};
+
+/// How often to clear out buckets and failure counts that don't matter any more.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Who a request is charged to when there's no telling who sent it, which happens over a Unix
+/// socket when the proxy doesn't send X-Forwarded-For. They all share the one bucket, and
+/// nothing can really connect from the unspecified address, so it's nobody else's.
+const LOCAL: IpAddr = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+/// A token bucket. It holds up to `burst` tokens and refills at `per_minute`, and each
+/// request takes one out.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The credential failures one IP has had lately.
+struct Failures {
+    count: u32,
+    window_start: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Keeps track of who's been asking for what, so nobody can hammer the database or guess
+/// api keys at full speed.
+pub(crate) struct RateLimiter {
+    ips: DashMap<IpAddr, Bucket>,
+    keys: DashMap<String, Bucket>,
+    failures: DashMap<IpAddr, Failures>,
+}
+
+/// Takes a token out of the bucket for `id`, or says how long until there'll be one.
+fn take<K: Hash + Eq>(
+    buckets: &DashMap<K, Bucket>,
+    id: K,
+    config: BucketConfig,
+) -> Result<(), Duration> {
+    // Zero turns the limit off.
+    if config.per_minute == 0 {
+        return Ok(());
+    }
+    let rate = config.per_minute as f64 / 60.0;
+    let burst = config.burst.max(1) as f64;
+    let now = Instant::now();
+
+    let mut bucket = buckets.entry(id).or_insert(Bucket {
+        tokens: burst,
+        updated: now,
+    });
+    bucket.tokens =
+        (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+    bucket.updated = now;
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new() -> RateLimiter {
+        RateLimiter {
+            ips: DashMap::with_capacity(8),
+            keys: DashMap::with_capacity(8),
+            failures: DashMap::with_capacity(8),
+        }
+    }
+
+    /// Checks whether a request from this IP may go ahead. If not, says how long it should wait.
+    pub(crate) fn check_ip(&self, ip: IpAddr, config: &RateLimitConfig) -> Result<(), Duration> {
+        if let Some(failures) = self.failures.get(&ip) {
+            if let Some(until) = failures.locked_until {
+                let now = Instant::now();
+                if until > now {
+                    return Err(until - now);
+                }
+            }
+        }
+        take(&self.ips, ip, config.per_ip)
+    }
+
+    /// Checks whether a location update with this api key may go ahead. If not, says how long
+    /// it should wait.
+    pub(crate) fn check_key(&self, key: &str, config: &RateLimitConfig) -> Result<(), Duration> {
+        take(&self.keys, key.to_string(), config.per_key)
+    }
+
+    /// Notes that an IP just had a credential failure, and locks it out if it's been having a
+    /// lot of them.
+    /// Returns whether that was the one that locked it out.
+    pub(crate) fn record_failure(&self, ip: IpAddr, config: &RateLimitConfig) -> bool {
+        if config.lockout_failures == 0 {
+            return false;
+        }
+        let window = Duration::from_secs(config.lockout_window_minutes * 60);
+        let now = Instant::now();
+
+        let mut failures = self.failures.entry(ip).or_insert(Failures {
+            count: 0,
+            window_start: now,
+            locked_until: None,
+        });
+        if now.duration_since(failures.window_start) > window {
+            failures.count = 0;
+            failures.window_start = now;
+        }
+        failures.count += 1;
+        if failures.count >= config.lockout_failures {
+            log::warn!("Locking out {} after {} failures.", ip, failures.count);
+            failures.locked_until = Some(now + Duration::from_secs(config.lockout_minutes * 60));
+            failures.count = 0;
+            failures.window_start = now;
+            return true;
+        }
+        false
+    }
+
+    /// Forgets about buckets that have filled back up and failures that have aged out, since
+    /// they'd behave the same as if they'd never been there.
+    fn prune(&self, config: &RateLimitConfig) {
+        let full_after = |c: BucketConfig| {
+            if c.per_minute == 0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64(c.burst.max(1) as f64 * 60.0 / c.per_minute as f64)
+            }
+        };
+        let (ip_full, key_full) = (full_after(config.per_ip), full_after(config.per_key));
+        let window = Duration::from_secs(config.lockout_window_minutes * 60);
+        let now = Instant::now();
+
+        self.ips
+            .retain(|_, b| now.duration_since(b.updated) < ip_full);
+        self.keys
+            .retain(|_, b| now.duration_since(b.updated) < key_full);
+        self.failures.retain(|_, f| {
+            f.locked_until.is_some_and(|until| until > now)
+                || now.duration_since(f.window_start) <= window
+        });
+    }
+}
+
+/// Starts the background job that keeps the rate limiter's memory use in check.
+pub(crate) fn spawn_pruning(state: web::Data<AppState>) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+            state.limiter.prune(&state.config().rate_limit);
+        }
+    });
+}
+
+/// Left on a request that came with an api key, session or invite code that was no good.
+struct CredentialFailure;
+
+/// Marks a request as having come with an api key, session or invite code that was no good.
+/// Only those count towards locking out the IP. Other 403s, like a privacy pause or a missing
+/// scope, don't mean anyone's guessing.
+pub(crate) fn flag_credential_failure(req: &HttpRequest) {
+    req.extensions_mut().insert(CredentialFailure);
+}
+
+/// Rounds a wait up to whole seconds, for the Retry-After header.
+pub(crate) fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+/// Middleware that turns away clients that are sending too much, or that have been
+/// getting credentials wrong too often, with a 429.
+pub(crate) struct RateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub(crate) struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let data = req.app_data::<web::Data<AppState>>().cloned();
+        let ip = data
+            .as_ref()
+            .map(|data| client_ip(req.request(), &data.config().trusted_proxies).unwrap_or(LOCAL));
+
+        // Turn them away before doing any work at all, if they're over the limit.
+        if let (Some(data), Some(ip)) = (&data, ip) {
+            if let Err(wait) = data.limiter.check_ip(ip, &data.config().rate_limit) {
+                log::debug!("Rate limiting {} on {}.", ip, req.path());
+                let response = too_many_requests(retry_after_secs(wait));
+                return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+            }
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let response = fut.await?;
+            // Count up the bad credentials, so that anyone guessing at keys gets locked out.
+            if response
+                .request()
+                .extensions()
+                .contains::<CredentialFailure>()
+            {
+                if let (Some(data), Some(ip)) = (&data, ip) {
+                    let config = data.config();
+                    if data.limiter.record_failure(ip, &config.rate_limit) {
+                        let detail = format!(
+                            "locked out for {} minutes after too many failures",
+                            config.rate_limit.lockout_minutes
+                        );
+                        audit::record(data, response.request(), ANONYMOUS, "auth.lockout", detail)
+                            .await;
+                    }
+                }
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
@@ -0,0 +1,233 @@
+use std::{path::Path, sync::Arc};
+
+use actix_web::{
+    rt::{
+        self,
+        signal::unix::{signal, SignalKind},
+    },
+    web,
+};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{auth::build_client, config::Config, geocode::Geocoder, settings, systemd, AppState};
+
+/// Settings that are only looked at during startup. Changes to these are noticed, but don't
+/// do anything until the server's restarted. "metrics" and "retention" only count here when
+/// they're switched on or off altogether.
+const RESTART_REQUIRED: &[&str] = &[
+    "listen",
+    "db_path",
+    "share_secret",
+    "metrics",
+    "metrics.listen",
+    "retention",
+    "retention.interval_minutes",
+];
+
+/// What a reload changed.
+#[derive(Serialize)]
+pub(crate) struct Reloaded {
+    /// Settings that changed and are now in effect.
+    pub(crate) applied: Vec<String>,
+    /// Settings that changed but won't take effect until a restart.
+    needs_restart: Vec<String>,
+}
+
+/// Lists the settings that differ between two configs, going one level into sections, so
+/// they come out like "rate_limit.per_ip".
+fn changed_settings(old: &Value, new: &Value) -> Vec<String> {
+    let (Value::Object(old), Value::Object(new)) = (old, new) else {
+        return Vec::new();
+    };
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    let mut changed = Vec::new();
+    for key in keys {
+        match (old.get(key), new.get(key)) {
+            (Some(Value::Object(old_section)), Some(Value::Object(new_section))) => {
+                let mut inner: Vec<&String> =
+                    old_section.keys().chain(new_section.keys()).collect();
+                inner.sort();
+                inner.dedup();
+                for name in inner {
+                    if old_section.get(name) != new_section.get(name) {
+                        changed.push(format!("{}.{}", key, name));
+                    }
+                }
+            }
+            (old_value, new_value) if old_value != new_value => changed.push(key.clone()),
+            _ => {}
+        }
+    }
+    changed
+}
+
+/// Puts the startup-only settings back the way they were, so the live config always says
+/// what's really in effect. Otherwise, say, moving metrics to their own listen address would
+/// drop the token check on the main one, which is still serving them.
+fn keep_startup_settings(new: &mut Config, old: &Config) {
+    new.listen = old.listen.clone();
+    new.db_path = old.db_path.clone();
+    new.share_secret = old.share_secret.clone();
+    match (&mut new.metrics, &old.metrics) {
+        (Some(new_metrics), Some(old_metrics)) => new_metrics.listen = old_metrics.listen.clone(),
+        _ => new.metrics = old.metrics.clone(),
+    }
+    match (&mut new.retention, &old.retention) {
+        (Some(new_retention), Some(old_retention)) => {
+            new_retention.interval_minutes = old_retention.interval_minutes
+        }
+        _ => new.retention = old.retention.clone(),
+    }
+}
+
+/// The new config, what changed, and the new places index if that needs replacing.
+type Loaded = (Config, Vec<String>, Option<Option<Geocoder>>);
+
+/// Reads and checks the config file, and loads anything it points to that's changed. This
+/// all blocks, so it goes on the thread pool.
+fn load(path: &Path, old: &Config) -> Result<Loaded, String> {
+    let mut config = settings::load(path)?;
+
+    let to_value = |c: &Config| serde_json::to_value(c).map_err(|e| e.to_string());
+    let changed = changed_settings(&to_value(old)?, &to_value(&config)?);
+    keep_startup_settings(&mut config, old);
+    // Check it again with the startup settings back in, since that's what will actually be
+    // running.
+    config.check()?;
+
+    // Only reread the places file if the geocoder settings moved.
+    let geocoder = if changed
+        .iter()
+        .any(|c| c.split('.').next() == Some("geocoder"))
+    {
+        match &config.geocoder {
+            Some(g) => Some(Some(
+                Geocoder::load(g).map_err(|e| format!("Failed to load places file: {}", e))?,
+            )),
+            None => Some(None),
+        }
+    } else {
+        None
+    };
+    Ok((config, changed, geocoder))
+}
+
+/// Rereads the config file and switches over to it. If anything about it is wrong, nothing
+/// changes and the old config stays in effect.
+pub(crate) async fn reload(data: &AppState) -> Result<Reloaded, String> {
+    let old = data.config();
+    let path = data.config_path.clone();
+    let (config, changed, geocoder) = web::block(move || load(&path, &old))
+        .await
+        .map_err(|e| e.to_string())??;
+    let client = build_client(&config)?;
+
+    // Everything checks out, so swap it all in.
+    data.auth.set_client(client);
+    if let Some(geocoder) = geocoder {
+        *data.geocoder.write() = geocoder.map(Arc::new);
+    }
+    *data.config.write() = Arc::new(config);
+
+    let (needs_restart, applied): (Vec<String>, Vec<String>) = changed
+        .into_iter()
+        .partition(|c| RESTART_REQUIRED.contains(&c.as_str()));
+    log::info!(
+        "Reloaded config from {}. Changed: {}.",
+        data.config_path.display(),
+        if applied.is_empty() {
+            "nothing".to_string()
+        } else {
+            applied.join(", ")
+        }
+    );
+    if !needs_restart.is_empty() {
+        log::warn!(
+            "These settings changed, but need a restart to take effect: {}.",
+            needs_restart.join(", ")
+        );
+    }
+    Ok(Reloaded {
+        applied,
+        needs_restart,
+    })
+}
+
+/// Starts listening for SIGHUP, and reloads the config each time one comes in.
+pub(crate) fn spawn_sighup(state: web::Data<AppState>) {
+    rt::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                log::error!(
+                    "Failed to listen for SIGHUP, config reloading is off: {}",
+                    e
+                );
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            systemd::notify_reloading();
+            if let Err(e) = reload(&state).await {
+                log::error!("Config reload failed, keeping the old config: {}", e);
+            }
+            systemd::notify_ready();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn nothing_changed() {
+        let config = json!({ "domain_name": "a.example", "rate_limit": { "per_ip": 10 } });
+        assert!(changed_settings(&config, &config.clone()).is_empty());
+    }
+
+    #[test]
+    fn top_level_settings() {
+        let old = json!({ "domain_name": "a.example", "db_path": "x.db" });
+        let new = json!({ "domain_name": "b.example", "db_path": "x.db" });
+        assert_eq!(changed_settings(&old, &new), ["domain_name"]);
+    }
+
+    #[test]
+    fn settings_in_sections() {
+        let old = json!({ "rate_limit": { "per_ip": 10, "burst": 5 }, "timeline": { "a": 1 } });
+        let new = json!({ "rate_limit": { "per_ip": 20, "burst": 5 }, "timeline": { "a": 1 } });
+        assert_eq!(changed_settings(&old, &new), ["rate_limit.per_ip"]);
+    }
+
+    #[test]
+    fn only_one_level_into_sections() {
+        let old = json!({ "geocoder": { "extra": { "a": 1, "b": 2 } } });
+        let new = json!({ "geocoder": { "extra": { "a": 1, "b": 3 } } });
+        assert_eq!(changed_settings(&old, &new), ["geocoder.extra"]);
+    }
+
+    #[test]
+    fn settings_added_and_removed() {
+        let old = json!({ "a": 1, "section": { "kept": 1, "dropped": 2 } });
+        let new = json!({ "b": 1, "section": { "kept": 1, "added": 3 } });
+        assert_eq!(
+            changed_settings(&old, &new),
+            ["a", "b", "section.added", "section.dropped"]
+        );
+    }
+
+    #[test]
+    fn sections_switched_on_and_off() {
+        let off = json!({ "metrics": null });
+        let on = json!({ "metrics": { "token": "t" } });
+        assert_eq!(changed_settings(&off, &on), ["metrics"]);
+        assert_eq!(changed_settings(&on, &off), ["metrics"]);
+    }
+}
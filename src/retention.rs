@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use actix_web::{rt, web};
+
+use crate::{
+    config::RetentionConfig,
+    db,
+    misc::{unixtime_now, SECS_PER_DAY},
+    AppState,
+};
+
+/// Starts the background job that enforces the retention rules, if there are any.
+pub(crate) fn spawn_retention(state: web::Data<AppState>) {
+    let interval_minutes = match &state.config().retention {
+        Some(retention) => retention.interval_minutes.max(1),
+        None => return,
+    };
+    rt::spawn(async move {
+        let mut interval =
+            rt::time::interval(Duration::from_secs(interval_minutes.saturating_mul(60)));
+        loop {
+            interval.tick().await;
+            if let Some(retention) = &state.config().retention {
+                if let Err(e) = enforce(&state, retention).await {
+                    log::error!("Retention run failed: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Keeps calling a batched delete until it comes back short, and adds up how many rows went.
+/// Each batch is its own transaction, so other requests get a look in between.
+async fn drain<F, Fut>(batch_size: u64, mut delete_batch: F) -> Result<usize, actix_web::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<usize, actix_web::Error>>,
+{
+    let mut total = 0;
+    loop {
+        let n = delete_batch().await?;
+        total += n;
+        if (n as u64) < batch_size {
+            return Ok(total);
+        }
+    }
+}
+
+/// Goes through every device's history and applies its owner's retention rule.
+async fn enforce(data: &AppState, config: &RetentionConfig) -> Result<(), actix_web::Error> {
+    let now = unixtime_now();
+    let batch_size = config.batch_size.max(1);
+    let (mut deleted_total, mut thinned_total) = (0, 0);
+
+    for (key_id, username) in db::list_key_owners(&data.pool).await? {
+        let rule = config
+            .user_overrides
+            .get(&username)
+            .unwrap_or(&config.default);
+
+        // Get rid of the really old stuff first, so there's less to thin out.
+        let deleted = match rule.delete_days {
+            Some(days) => {
+                let before = now.saturating_sub(days.saturating_mul(SECS_PER_DAY));
+                drain(batch_size, || {
+                    db::delete_locations_before(&data.pool, key_id, before, batch_size)
+                })
+                .await?
+            }
+            None => 0,
+        };
+
+        let thinned = match (rule.full_days, rule.downsample_minutes) {
+            (Some(days), Some(minutes)) if minutes > 0 => {
+                let before = now.saturating_sub(days.saturating_mul(SECS_PER_DAY));
+                drain(batch_size, || {
+                    db::downsample_locations_before(
+                        &data.pool,
+                        key_id,
+                        before,
+                        minutes.saturating_mul(60),
+                        batch_size,
+                    )
+                })
+                .await?
+            }
+            _ => 0,
+        };
+
+        if deleted + thinned > 0 {
+            log::debug!(
+                "Retention: api key id {} ({}): deleted {}, downsampled away {}.",
+                key_id,
+                username,
+                deleted,
+                thinned
+            );
+        }
+        deleted_total += deleted;
+        thinned_total += thinned;
+    }
+
+    let audit_deleted = match config.audit_days {
+        Some(days) => {
+            let before = now.saturating_sub(days.saturating_mul(SECS_PER_DAY));
+            drain(batch_size, || {
+                db::delete_audit_entries_before(&data.pool, before, batch_size)
+            })
+            .await?
+        }
+        None => 0,
+    };
+
+    log::info!(
+        "Retention run done: deleted {} old fixes, downsampled away {} more, and deleted {} old audit log entries.",
+        deleted_total,
+        thinned_total,
+        audit_deleted
+    );
+    Ok(())
+}
@@ -0,0 +1,551 @@
+use std::{
+    env,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use jsonschema::JSONSchema;
+use oauth2::url::Url;
+use serde_json::{Map, Value};
+
+use crate::config::Config;
+
+/// The config schema that build.rs generates from config.rs.
+pub(crate) const SCHEMA: &str = include_str!(concat!(env!("OUT_DIR"), "/schema.json"));
+
+/// Environment variables starting with this override settings from the file.
+const ENV_PREFIX: &str = "LOCATIONAPP_";
+
+/// Settings that shouldn't be printed out.
+const SECRETS: &[&[&str]] = &[
+    &["oauth_provider", "client_secret"],
+    &["share_secret"],
+    &["metrics", "token"],
+];
+
+/// The config file as it was written, for pointing at the line a problem is on, and what was
+/// layered on top of it.
+struct Source {
+    name: String,
+    text: String,
+    toml: bool,
+    /// Settings that came from somewhere else, and where, in the order they were applied.
+    layered: Vec<(Vec<String>, String)>,
+}
+
+impl Source {
+    /// Where a setting came from, if it wasn't the file. Whatever was applied last to it or to
+    /// a section it's in wins.
+    fn layer_of(&self, path: &[String]) -> Option<&str> {
+        self.layered
+            .iter()
+            .rev()
+            .find(|(layered, _)| path.starts_with(layered))
+            .map(|(_, origin)| origin.as_str())
+    }
+}
+
+/// Something wrong with the config, and the setting it's wrong with.
+struct Problem {
+    path: Vec<String>,
+    message: String,
+}
+
+impl Problem {
+    fn new(path: &[&str], message: impl Into<String>) -> Problem {
+        Problem {
+            path: path.iter().map(|key| key.to_string()).collect(),
+            message: message.into(),
+        }
+    }
+
+    /// Says what's wrong and where: the setting, and either the environment variable or file it
+    /// came from, or the line and column in the config file.
+    fn describe(&self, source: &Source) -> String {
+        let setting = if self.path.is_empty() {
+            "(top level)".to_string()
+        } else {
+            self.path.join(".")
+        };
+        if let Some(origin) = source.layer_of(&self.path) {
+            return format!("{}: {}: {}", origin, setting, self.message);
+        }
+        match locate(source, &self.path) {
+            Some((line, column)) => format!(
+                "{} line {} column {}: {}: {}",
+                source.name, line, column, setting, self.message
+            ),
+            None => format!("{}: {}: {}", source.name, setting, self.message),
+        }
+    }
+}
+
+/// Reads the config file, as TOML if it's named like it, and as JSON otherwise.
+fn read_file(path: &Path) -> Result<(Value, Source), String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read config file {}: {}", path.display(), e))?;
+    let source = Source {
+        name: path.display().to_string(),
+        toml: path.extension().is_some_and(|ext| ext == "toml"),
+        text,
+        layered: Vec::new(),
+    };
+    // Both of these say where in the file they gave up.
+    let value = if source.toml {
+        toml::from_str(&source.text).map_err(|e| format!("{}: {}", source.name, e))?
+    } else {
+        serde_json::from_str(&source.text).map_err(|e| format!("{}: {}", source.name, e))?
+    };
+    Ok((value, source))
+}
+
+/// Finds where a setting is in the file, as a line and column counting from 1. This just looks
+/// for each key in turn after the one before, which is good enough for config files. List
+/// indices are skipped over, leaving us pointing at the list.
+fn locate(source: &Source, path: &[String]) -> Option<(usize, usize)> {
+    let text = &source.text;
+    let mut offset = None;
+    for key in path {
+        let from = offset.unwrap_or(0);
+        match find_key(text, from, key, source.toml) {
+            Some(found) => offset = Some(found),
+            None if key.parse::<usize>().is_ok() => continue,
+            None => return None,
+        }
+    }
+    let offset = offset?;
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    let line = text[..offset].matches('\n').count() + 1;
+    let column = text[line_start..offset].chars().count() + 1;
+    Some((line, column))
+}
+
+/// Finds the next place after `from` where `key` is used as a key: quoted and followed by a
+/// colon in JSON, or bare or quoted at the start of a line, a table header or a dotted key in
+/// TOML.
+fn find_key(text: &str, from: usize, key: &str, toml: bool) -> Option<usize> {
+    let is_key_char = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    let mut at = from;
+    while let Some(i) = text[at..].find(key) {
+        let (mut start, mut end) = (at + i, at + i + key.len());
+        at = end;
+        let quoted = text[..start].ends_with('"') && text[end..].starts_with('"');
+        if quoted {
+            (start, end) = (start - 1, end + 1);
+        } else if !toml
+            || text[..start].ends_with(is_key_char)
+            || text[end..].starts_with(is_key_char)
+        {
+            // Just part of some other word.
+            continue;
+        }
+        let before = text[..start].trim_end_matches([' ', '\t']).chars().last();
+        let after = text[end..].trim_start_matches([' ', '\t']).chars().next();
+        let fits = if toml {
+            matches!(before, None | Some('\n' | '[' | '.' | '{' | ','))
+                && matches!(after, Some('=' | '.' | ']'))
+        } else {
+            after == Some(':')
+        };
+        if fits {
+            return Some(start);
+        }
+    }
+    None
+}
+
+/// The embedded schema, parsed.
+fn schema() -> Value {
+    serde_json::from_str(SCHEMA).expect("Embedded schema isn't JSON.")
+}
+
+/// Finds the schema's default for the setting at `path`, following references into the
+/// definitions as we go.
+fn schema_default(schema: &Value, path: &[String]) -> Option<Value> {
+    let mut node = schema;
+    for key in path {
+        // Sections show up as a bare reference, or wrapped in allOf, or in anyOf if they're
+        // optional.
+        let reference = node
+            .get("$ref")
+            .or_else(|| node.pointer("/allOf/0/$ref"))
+            .or_else(|| node.pointer("/anyOf/0/$ref"))
+            .and_then(Value::as_str);
+        if let Some(name) = reference.and_then(|r| r.strip_prefix("#/definitions/")) {
+            node = schema.get("definitions")?.get(name)?;
+        }
+        node = node.get("properties")?.get(key)?;
+    }
+    node.get("default").cloned()
+}
+
+/// Applies LOCATIONAPP_* environment variables on top of the file. Double underscores go down
+/// a level, so LOCATIONAPP_RATE_LIMIT__PER_IP__BURST sets rate_limit.per_ip.burst. Values are
+/// taken as JSON if they parse as JSON, except where the file already has a string, so
+/// numbers and lists work but a numeric client id stays a string. Sections the file leaves
+/// out start from their defaults, so setting one field doesn't lose the others.
+fn apply_env(
+    config: &mut Value,
+    schema: &Value,
+    vars: impl Iterator<Item = (String, String)>,
+    source: &mut Source,
+) {
+    for (name, raw) in vars {
+        let Some(setting) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let path: Vec<String> = setting.split("__").map(str::to_lowercase).collect();
+        let Some((last, parents)) = path.split_last() else {
+            continue;
+        };
+
+        // Find the section to put it in, making any that aren't there yet.
+        let mut section = &mut *config;
+        for (depth, key) in parents.iter().enumerate() {
+            if !section.is_object() {
+                *section = Value::Object(Map::new());
+            }
+            section = section
+                .as_object_mut()
+                .unwrap()
+                .entry(key.clone())
+                .or_insert_with(|| {
+                    schema_default(schema, &parents[..=depth]).unwrap_or(Value::Null)
+                });
+        }
+        if !section.is_object() {
+            *section = Value::Object(Map::new());
+        }
+        let section = section.as_object_mut().unwrap();
+
+        let value = match (section.get(last), serde_json::from_str(&raw)) {
+            (Some(Value::String(_)), _) | (_, Err(_)) => Value::String(raw),
+            (_, Ok(parsed)) => parsed,
+        };
+        section.insert(last.clone(), value);
+        source
+            .layered
+            .push((path, format!("environment variable {}", name)));
+    }
+}
+
+/// Replaces any setting `x_file` with a setting `x` holding the contents of that file, minus
+/// the trailing newline. Relative paths are looked up in systemd's credentials directory, if
+/// there is one. `at` is where `config` is, for noting where the settings came from.
+fn resolve_files(config: &mut Value, at: &[String], source: &mut Source) -> Result<(), String> {
+    let Value::Object(section) = config else {
+        return Ok(());
+    };
+    let indirect: Vec<String> = section
+        .iter()
+        .filter(|(key, value)| key.ends_with("_file") && value.is_string())
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in indirect {
+        let Some(Value::String(file)) = section.remove(&key) else {
+            continue;
+        };
+        let mut path = PathBuf::from(&file);
+        if path.is_relative() {
+            if let Some(dir) = env::var_os("CREDENTIALS_DIRECTORY") {
+                path = PathBuf::from(dir).join(path);
+            }
+        }
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Couldn't read {} for {}: {}", path.display(), key, e))?;
+        let setting = key.trim_end_matches("_file").to_string();
+        section.insert(
+            setting.clone(),
+            Value::String(contents.trim_end_matches(['\r', '\n']).to_string()),
+        );
+        let origin = format!("{} (from {})", path.display(), key);
+        source.layered.push(([at, &[setting]].concat(), origin));
+    }
+    for (key, value) in section.iter_mut() {
+        resolve_files(value, &[at, std::slice::from_ref(key)].concat(), source)?;
+    }
+    Ok(())
+}
+
+/// Checks a config against the schema, and lists everything wrong with it if it doesn't fit.
+fn validate(config: &Value, schema: &Value) -> Vec<Problem> {
+    let schema = JSONSchema::compile(schema).expect("Embedded schema isn't a valid schema.");
+    let Err(errors) = schema.validate(config) else {
+        return Vec::new();
+    };
+    errors
+        .map(|e| Problem {
+            path: e
+                .instance_path
+                .to_string()
+                .split('/')
+                .skip(1)
+                .map(|key| key.replace("~1", "/").replace("~0", "~"))
+                .collect(),
+            // These say what was expected, as in '"x" is not of type "integer"'.
+            message: e.to_string(),
+        })
+        .collect()
+}
+
+// This lives here rather than in config.rs, which build.rs includes but has no use for.
+impl Config {
+    /// Checks the rules that the types alone can't, and says what's wrong if any are broken.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        // Metrics shouldn't be open to the world, so insist on at least one of the ways of
+        // keeping them private.
+        if let Some(metrics) = &self.metrics {
+            if metrics.token.is_none() && metrics.listen.is_none() {
+                return Err(
+                    "metrics needs a token, a separate listen address, or both.".to_string()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks the rules that the schema can't express.
+fn check_semantics(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let urls = [
+        (
+            &["oauth_provider", "auth_url"][..],
+            &config.oauth_provider.auth_url,
+        ),
+        (
+            &["oauth_provider", "token_url"][..],
+            &config.oauth_provider.token_url,
+        ),
+        (&["userinfo_endpoint"][..], &config.userinfo_endpoint),
+        (&["redirect_after_auth"][..], &config.redirect_after_auth),
+    ];
+    for (path, url) in urls {
+        match Url::parse(url) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+            Ok(url) => problems.push(Problem::new(
+                path,
+                format!("expected an http or https URL, not {}", url.scheme()),
+            )),
+            Err(e) => problems.push(Problem::new(path, format!("not a valid URL: {}", e))),
+        }
+    }
+    // The redirect URL gets built from this, so it has to make a good host.
+    let redirect = format!("https://{}/api/auth/redirect", config.domain_name);
+    if !Url::parse(&redirect).is_ok_and(|url| url.path() == "/api/auth/redirect") {
+        problems.push(Problem::new(
+            &["domain_name"],
+            "expected a host name, like sub.my-domain.com",
+        ));
+    }
+    if config.listen.is_empty() {
+        problems.push(Problem::new(
+            &["listen"],
+            "expected somewhere to listen, but the list is empty",
+        ));
+    }
+    // Without the email, we can't tell who's logged in.
+    let wants_email =
+        config.oauth_provider.scopes.iter().any(|scope| {
+            scope == "email" || scope.ends_with(".email") || scope.ends_with("/email")
+        });
+    if !wants_email {
+        problems.push(Problem::new(
+            &["oauth_provider", "scopes"],
+            "expected a scope that grants the user's email address",
+        ));
+    }
+    if let Err(e) = config.check() {
+        problems.push(Problem::new(&[], e));
+    }
+    problems
+}
+
+/// Lists the problems under a heading, if there are any.
+fn report(heading: &str, problems: Vec<Problem>, source: &Source) -> Result<(), String> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let problems: Vec<String> = problems.iter().map(|p| p.describe(source)).collect();
+    Err(format!("{}\n{}", heading, problems.join("\n")))
+}
+
+/// Puts the config together from the file, the environment and any secret files, and checks it.
+pub(crate) fn load(path: &Path) -> Result<Config, String> {
+    let schema = schema();
+    let (mut merged, mut source) = read_file(path)?;
+    apply_env(&mut merged, &schema, env::vars(), &mut source);
+    resolve_files(&mut merged, &[], &mut source)?;
+    report(
+        "Config doesn't fit the schema:",
+        validate(&merged, &schema),
+        &source,
+    )?;
+    // The schema should have caught anything that won't deserialize, but just in case, keep
+    // track of where we were.
+    let config: Config = serde_path_to_error::deserialize(merged).map_err(|e| {
+        let path = e.path().to_string();
+        let problem = Problem {
+            path: path.split('.').map(str::to_string).collect(),
+            message: e.into_inner().to_string(),
+        };
+        problem.describe(&source)
+    })?;
+    report("Config has problems:", check_semantics(&config), &source)?;
+    Ok(config)
+}
+
+/// Loads the config, or says what's wrong with it and exits.
+pub(crate) fn load_or_exit(path: &Path) -> Config {
+    load(path).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
+}
+
+/// The config as JSON, with the secrets blanked out.
+pub(crate) fn redacted(config: &Config) -> Value {
+    let mut value = serde_json::to_value(config).expect("Config didn't serialize.");
+    for path in SECRETS {
+        let mut target = Some(&mut value);
+        for key in *path {
+            target = target.and_then(|v| v.get_mut(*key));
+        }
+        if let Some(secret) = target.filter(|v| !v.is_null()) {
+            *secret = Value::String("<redacted>".to_string());
+        }
+    }
+    value
+}
+
+/// The config check command: puts the config together, and prints it (minus secrets) if it's
+/// good, or what's wrong with it if not.
+pub(crate) fn cli_check(path: &Path) -> std::io::Result<()> {
+    let config = load_or_exit(path);
+    println!("{}", serde_json::to_string_pretty(&redacted(&config))?);
+    eprintln!("Config is OK.");
+    Ok(())
+}
+
+/// The schema command, and config schema: prints the schema, for editors and other tools to
+/// check configs against.
+pub(crate) fn cli_schema() -> std::io::Result<()> {
+    std::io::stdout().write_all(SCHEMA.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(text: &str, toml: bool) -> Source {
+        Source {
+            name: "config".to_string(),
+            text: text.to_string(),
+            toml,
+            layered: Vec::new(),
+        }
+    }
+
+    fn path(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|k| k.to_string()).collect()
+    }
+
+    #[test]
+    fn json_keys_not_values() {
+        let text = r#"{ "name": "per_ip", "per_ip": 5 }"#;
+        assert_eq!(find_key(text, 0, "per_ip", false), Some(20));
+        assert_eq!(find_key(text, 0, "burst", false), None);
+    }
+
+    #[test]
+    fn toml_keys_not_parts_of_other_words() {
+        let text = "max_per_ip = 3\nname = \"per_ip\"\nper_ip = 5\n";
+        assert_eq!(find_key(text, 0, "per_ip", true), Some(31));
+    }
+
+    #[test]
+    fn toml_headers_dotted_and_inline_keys() {
+        for text in [
+            "[rate_limit]\nper_ip = 5\n",
+            "rate_limit.per_ip = 5\n",
+            "rate_limit = { burst = 1, per_ip = 5 }\n",
+        ] {
+            assert!(find_key(text, 0, "rate_limit", true).is_some(), "{}", text);
+            assert!(find_key(text, 0, "per_ip", true).is_some(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn nested_json_keys() {
+        let text =
+            "{\n  \"timeline\": { \"per_ip\": 1 },\n  \"rate_limit\": {\n    \"per_ip\": 2\n  }\n}";
+        let json = source(text, false);
+        assert_eq!(
+            locate(&json, &path(&["rate_limit", "per_ip"])),
+            Some((4, 5))
+        );
+        assert_eq!(locate(&json, &path(&["timeline", "per_ip"])), Some((2, 17)));
+        assert_eq!(locate(&json, &path(&["rate_limit", "burst"])), None);
+    }
+
+    #[test]
+    fn nested_toml_keys() {
+        let text = "per_ip = 1\n\n[rate_limit]\nburst = 2\nper_ip = 3\n";
+        let toml = source(text, true);
+        assert_eq!(
+            locate(&toml, &path(&["rate_limit", "per_ip"])),
+            Some((5, 1))
+        );
+        assert_eq!(locate(&toml, &path(&["per_ip"])), Some((1, 1)));
+    }
+
+    #[test]
+    fn list_indices_point_at_the_list() {
+        let json = source("{\n  \"trusted_proxies\": [\"x\", \"y\"]\n}", false);
+        assert_eq!(
+            locate(&json, &path(&["trusted_proxies", "1"])),
+            Some((2, 3))
+        );
+    }
+
+    #[test]
+    fn layered_settings_say_where_they_came_from() {
+        let mut layered = source("{\n  \"rate_limit\": { \"per_ip\": 1 }\n}", false);
+        let vars = [
+            (
+                "LOCATIONAPP_RATE_LIMIT__PER_IP".to_string(),
+                "x".to_string(),
+            ),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let mut config = serde_json::from_str(&layered.text).unwrap();
+        apply_env(&mut config, &schema(), vars.into_iter(), &mut layered);
+        assert_eq!(config["rate_limit"]["per_ip"], "x");
+
+        let secret = std::env::temp_dir().join(format!("share-{}", std::process::id()));
+        std::fs::write(&secret, "hunter2\n").unwrap();
+        config["share_secret_file"] = Value::String(secret.display().to_string());
+        resolve_files(&mut config, &[], &mut layered).unwrap();
+        std::fs::remove_file(&secret).unwrap();
+        assert_eq!(config["share_secret"], "hunter2");
+
+        let problem = Problem::new(&["rate_limit", "per_ip", "burst"], "bad");
+        assert_eq!(
+            problem.describe(&layered),
+            "environment variable LOCATIONAPP_RATE_LIMIT__PER_IP: rate_limit.per_ip.burst: bad"
+        );
+        let problem = Problem::new(&["share_secret"], "bad");
+        assert_eq!(
+            problem.describe(&layered),
+            format!(
+                "{} (from share_secret_file): share_secret: bad",
+                secret.display()
+            )
+        );
+        let problem = Problem::new(&["rate_limit"], "bad");
+        assert_eq!(
+            problem.describe(&layered),
+            "config line 2 column 3: rate_limit: bad"
+        );
+    }
+}
@@ -0,0 +1,335 @@
+use actix_web::{get, http::header::ContentType, post, web, HttpRequest, HttpResponse, Responder};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    config::Config,
+    db,
+    geo::reduce_precision,
+    location::{authenticate_session, require_owner, Location},
+    misc::{bad_request, forbidden, internal_error, unixtime_now},
+    privacy::{recorded_while_paused, viewer_access, EVERYONE},
+    AppState,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The longest a share link can live for, in hours.
+const MAX_SHARE_HOURS: u64 = 24 * 30;
+
+/// The most trail a share link can show, in minutes.
+const MAX_TRAIL_MINUTES: u64 = 24 * 60;
+
+/// A link that lets someone without a web_user see one device's location for a while.
+#[derive(Serialize)]
+pub(crate) struct ShareLink {
+    pub(crate) id: u64,
+    /// The api key id whose location is shared.
+    pub(crate) key_id: u64,
+    /// The web_user who made the link, and who can revoke it.
+    pub(crate) created_by: String,
+    /// Seconds since the unix epoch.
+    pub(crate) issued: u64,
+    /// Seconds since the unix epoch.
+    pub(crate) expiration: u64,
+    /// If set, locations are snapped to a grid this many meters on a side.
+    pub(crate) precision_meters: Option<f64>,
+    /// If set, this many minutes of history are shown along with the last location.
+    pub(crate) trail_minutes: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ShareCreateIn {
+    /// The api key id to share.
+    id: u64,
+    /// How long the link should work for.
+    hours: u64,
+    precision_meters: Option<f64>,
+    trail_minutes: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ShareCreateOut {
+    id: u64,
+    token: String,
+    url: String,
+    expiration: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ShareRevokeIn {
+    /// The share link's id.
+    id: u64,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ShareViewIn {
+    token: String,
+}
+
+#[derive(Serialize)]
+struct ShareViewOut {
+    /// Absent if the device hasn't reported since the server started.
+    location: Option<Location>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    near: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trail: Option<Vec<Location>>,
+    /// When the link stops working, in seconds since the unix epoch.
+    expiration: u64,
+}
+
+/// Works out the secret to sign share links with, from the config or out of thin air.
+pub(crate) fn share_secret(config: &Config) -> Vec<u8> {
+    match &config.share_secret {
+        Some(secret) => secret.as_bytes().to_vec(),
+        None => {
+            log::warn!("No share_secret configured, share links won't survive a restart.");
+            rand::random::<[u8; 32]>().to_vec()
+        }
+    }
+}
+
+/// Makes the signature for a share link's id and expiration.
+fn sign(secret: &[u8], id: u64, expiration: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC takes keys of any size");
+    mac.update(format!("{}.{}", id, expiration).as_bytes());
+    mac
+}
+
+/// Builds the token handed out for a share link. It looks like "id.expiration.signature".
+fn make_token(secret: &[u8], id: u64, expiration: u64) -> String {
+    let signature = sign(secret, id, expiration).finalize().into_bytes();
+    format!(
+        "{}.{}.{}",
+        id,
+        expiration,
+        URL_SAFE_NO_PAD.encode(signature)
+    )
+}
+
+/// Checks a share token's signature and expiry, and gives back the link's id if it's good.
+/// This doesn't touch the database, so forged tokens are cheap to turn away.
+fn check_token(secret: &[u8], token: &str) -> Option<u64> {
+    let mut parts = token.splitn(3, '.');
+    let id: u64 = parts.next()?.parse().ok()?;
+    let expiration: u64 = parts.next()?.parse().ok()?;
+    let signature = URL_SAFE_NO_PAD.decode(parts.next()?).ok()?;
+    sign(secret, id, expiration).verify_slice(&signature).ok()?;
+    if expiration <= unixtime_now() {
+        return None;
+    }
+    Some(id)
+}
+
+#[post("/api/share/create")]
+pub(crate) async fn post_share_create(
+    info: web::Json<ShareCreateIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+
+    // Only the device's owner gets to share it.
+    if let Err(resp) = require_owner(&data, info.id, &session.name).await {
+        return resp;
+    }
+
+    if info.hours == 0 || info.hours > MAX_SHARE_HOURS {
+        return bad_request("hours must be between 1 and 720.");
+    }
+    if info.trail_minutes.is_some_and(|t| t > MAX_TRAIL_MINUTES) {
+        return bad_request("trail_minutes can be at most 1440.");
+    }
+    if info
+        .precision_meters
+        .is_some_and(|p| !p.is_finite() || p < 0.0)
+    {
+        return bad_request("precision_meters must be a positive number.");
+    }
+
+    // Record the link, then sign its id.
+    let now = unixtime_now();
+    let link = ShareLink {
+        id: 0,
+        key_id: info.id,
+        created_by: session.name,
+        issued: now,
+        expiration: now + info.hours * 60 * 60,
+        precision_meters: info.precision_meters,
+        trail_minutes: info.trail_minutes,
+    };
+    let expiration = link.expiration;
+    let id = match db::insert_share_link(&data.pool, link).await {
+        Ok(id) => id,
+        Err(e) => {
+            log::error!("/api/share/create: failed to store share link: {}", e);
+            return internal_error();
+        }
+    };
+    let token = make_token(&data.share_secret, id, expiration);
+
+    HttpResponse::Ok().insert_header(ContentType::json()).body(
+        serde_json::to_string(&ShareCreateOut {
+            id,
+            url: format!(
+                "https://{}/api/share/view?token={}",
+                data.config().domain_name,
+                token
+            ),
+            token,
+            expiration,
+        })
+        .unwrap(),
+    )
+}
+
+#[get("/api/share/list")]
+pub(crate) async fn get_share_list(data: web::Data<AppState>, req: HttpRequest) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+
+    match db::list_share_links(&data.pool, session.name).await {
+        Ok(links) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body(serde_json::to_string(&links).unwrap()),
+        Err(e) => {
+            log::error!("/api/share/list: failed to list share links: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[post("/api/share/revoke")]
+pub(crate) async fn post_share_revoke(
+    info: web::Json<ShareRevokeIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+
+    // Only the link's creator can revoke it, which the query takes care of.
+    match db::revoke_share_link(&data.pool, info.id, session.name).await {
+        Ok(true) => HttpResponse::Ok()
+            .insert_header(ContentType::json())
+            .body("{}"),
+        Ok(false) => forbidden(),
+        Err(e) => {
+            log::error!("/api/share/revoke: failed to revoke share link: {}", e);
+            internal_error()
+        }
+    }
+}
+
+#[get("/api/share/view")]
+pub(crate) async fn get_share_view(
+    info: web::Query<ShareViewIn>,
+    data: web::Data<AppState>,
+) -> impl Responder {
+    // Check the signature before bothering the database.
+    let id = match check_token(&data.share_secret, &info.token) {
+        Some(id) => id,
+        None => {
+            log::debug!("/api/share/view: Bad share token.");
+            return forbidden();
+        }
+    };
+
+    // Make sure it hasn't been revoked.
+    let link = match db::get_share_link(&data.pool, id).await {
+        Ok(Some(link)) => link,
+        Ok(None) => {
+            log::debug!("/api/share/view: Share link {} revoked or expired.", id);
+            return forbidden();
+        }
+        Err(e) => {
+            log::error!("/api/share/view: failed to look up share link: {}", e);
+            return internal_error();
+        }
+    };
+
+    // The owner's privacy policy for everyone applies on top of the link's own precision.
+    let access = match viewer_access(&data, link.key_id, None).await {
+        Ok(a) => a,
+        Err(e) => {
+            log::error!("/api/share/view: failed to check privacy policy: {}", e);
+            return internal_error();
+        }
+    };
+    let coarsen = |loc: &Location| {
+        let loc = access.apply(loc, &data)?;
+        Some(match link.precision_meters {
+            Some(p) => reduce_precision(&loc, p),
+            None => loc,
+        })
+    };
+
+    // The newest fix might be from a pause that's over now, and that stays hidden.
+    let last_loc = data
+        .last_location
+        .get(&link.key_id)
+        .map(|loc| loc.value().clone());
+    let location = match last_loc {
+        Some(loc) => match recorded_while_paused(&data, link.key_id, None, &loc).await {
+            Ok(false) => coarsen(&loc),
+            Ok(true) => None,
+            Err(e) => {
+                log::error!("/api/share/view: failed to check privacy pauses: {}", e);
+                return internal_error();
+            }
+        },
+        None => None,
+    };
+    let near = match (data.geocoder(), &location) {
+        (Some(g), Some(loc)) => g
+            .nearest(loc.latitude, loc.longitude)
+            .map(|label| format!("near {}", label)),
+        _ => None,
+    };
+
+    // The trail goes through the same treatment, so it comes out empty while sharing's paused.
+    let trail = match link.trail_minutes {
+        Some(minutes) => {
+            let now = unixtime_now();
+            match db::get_locations(
+                &data.pool,
+                link.key_id,
+                EVERYONE.to_string(),
+                now - minutes * 60,
+                now + 1,
+            )
+            .await
+            {
+                Ok(points) => Some(points.iter().filter_map(coarsen).collect()),
+                Err(e) => {
+                    log::error!("/api/share/view: failed to read history: {}", e);
+                    return internal_error();
+                }
+            }
+        }
+        None => None,
+    };
+
+    HttpResponse::Ok().insert_header(ContentType::json()).body(
+        serde_json::to_string(&ShareViewOut {
+            location,
+            near,
+            trail,
+            expiration: link.expiration,
+        })
+        .unwrap(),
+    )
+}
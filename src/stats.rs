@@ -0,0 +1,248 @@
+use actix_web::{get, http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    db,
+    geo::distance_between,
+    groups::require_visible,
+    location::{authenticate_session, Location},
+    misc::{bad_request, day_bounds, forbidden, internal_error, SECS_PER_DAY},
+    privacy::viewer_access,
+    AppState,
+};
+
+/// Below this speed, in meters per second, we count the device as standing still. It keeps
+/// GPS jitter from adding up to moving time.
+const MOVING_SPEED_MPS: f64 = 0.5;
+
+/// The longest range, in days, that one request can ask for.
+const MAX_RANGE_DAYS: u64 = 366;
+
+#[derive(Deserialize)]
+pub(crate) struct StatsIn {
+    /// The api key id to compute stats for.
+    id: u64,
+    /// The first day, as YYYY-MM-DD.
+    from: String,
+    /// The last day, inclusive, as YYYY-MM-DD.
+    to: String,
+    /// The viewer's offset from UTC in minutes, so that days mean their days.
+    #[serde(default)]
+    utc_offset: i64,
+}
+
+#[derive(Serialize, Default)]
+struct Stats {
+    /// Meters.
+    distance: f64,
+    /// Seconds.
+    moving_time: u64,
+    /// Meters per second.
+    max_speed: f64,
+    /// Meters per second, while moving.
+    average_speed: f64,
+    /// Every fix received, including ones the filter rejected.
+    updates: u64,
+    /// Fixes the filter rejected.
+    rejected: u64,
+    /// Meters, over the accepted fixes.
+    median_accuracy: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct DayStats {
+    /// Seconds since the unix epoch, the start of the day.
+    start: u64,
+    #[serde(flatten)]
+    stats: Stats,
+}
+
+#[derive(Serialize)]
+struct StatsOut {
+    total: Stats,
+    days: Vec<DayStats>,
+}
+
+/// The middle value, or the average of the middle two. None if there aren't any.
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    values.sort_by(f64::total_cmp);
+    match values.len() {
+        0 => None,
+        n if n % 2 == 1 => Some(values[n / 2]),
+        n => Some((values[n / 2 - 1] + values[n / 2]) / 2.0),
+    }
+}
+
+/// Works out the movement stats for a time-ordered run of points. The counts are left for
+/// the caller, since they come from the database.
+fn compute(points: &[Location]) -> Stats {
+    let mut stats = Stats::default();
+    for w in points.windows(2) {
+        let distance = distance_between(&w[0], &w[1]);
+        stats.distance += distance;
+
+        // Two fixes in the same second don't tell us anything about speed.
+        let elapsed = w[1].time - w[0].time;
+        if elapsed == 0 {
+            continue;
+        }
+        let speed = distance / elapsed as f64;
+        stats.max_speed = stats.max_speed.max(speed);
+        if speed >= MOVING_SPEED_MPS {
+            stats.moving_time += elapsed;
+        }
+    }
+    if stats.moving_time > 0 {
+        stats.average_speed = stats.distance / stats.moving_time as f64;
+    }
+
+    stats.median_accuracy = median(points.iter().map(|p| p.accuracy).collect());
+    stats
+}
+
+#[get("/api/location/stats")]
+pub(crate) async fn get_location_stats(
+    info: web::Query<StatsIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+    if let Err(response) = require_visible(&data, info.id, &session.name).await {
+        return response;
+    }
+
+    // Stats off blurred points would be nonsense, so only viewers who can see exact
+    // locations get them.
+    match viewer_access(&data, info.id, Some(&session.name)).await {
+        Ok(access) if access.is_exact() => {}
+        Ok(_) => return forbidden(),
+        Err(e) => {
+            log::error!("/api/location/stats: failed to check privacy policy: {}", e);
+            return internal_error();
+        }
+    }
+
+    // Work out which stretch of unix time the requested days cover.
+    let (start, end) = match (
+        day_bounds(&info.from, info.utc_offset),
+        day_bounds(&info.to, info.utc_offset),
+    ) {
+        (Some((start, _)), Some((_, end))) => (start, end),
+        _ => return bad_request("Bad date, expected YYYY-MM-DD."),
+    };
+    if end <= start || end - start > MAX_RANGE_DAYS * SECS_PER_DAY {
+        return bad_request("Bad date range.");
+    }
+
+    let points =
+        match db::get_locations(&data.pool, info.id, session.name.clone(), start, end).await {
+            Ok(p) => p,
+            Err(e) => {
+                log::error!("/api/location/stats: failed to read history: {}", e);
+                return internal_error();
+            }
+        };
+
+    let counts =
+        match db::count_locations_by_day(&data.pool, info.id, session.name.clone(), start, end)
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                log::error!("/api/location/stats: failed to count updates: {}", e);
+                return internal_error();
+            }
+        };
+
+    // Do the whole range, then each day in it. The days are each their own little track,
+    // so movement across midnight only counts towards the total.
+    let mut total = compute(&points);
+    let mut days = Vec::new();
+    for (day, day_start) in (start..end).step_by(SECS_PER_DAY as usize).enumerate() {
+        let day_end = day_start + SECS_PER_DAY;
+        let from = points.partition_point(|p| p.time < day_start);
+        let to = points.partition_point(|p| p.time < day_end);
+        let mut stats = compute(&points[from..to]);
+        if let Some(&(_, updates, rejected)) = counts.iter().find(|c| c.0 == day as u64) {
+            stats.updates = updates;
+            stats.rejected = rejected;
+        }
+        total.updates += stats.updates;
+        total.rejected += stats.rejected;
+        days.push(DayStats {
+            start: day_start,
+            stats,
+        });
+    }
+
+    HttpResponse::Ok()
+        .insert_header(ContentType::json())
+        .body(serde_json::to_string(&StatsOut { total, days }).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(latitude: f64, accuracy: f64, time: u64) -> Location {
+        Location {
+            latitude,
+            longitude: 0.0,
+            accuracy,
+            time,
+        }
+    }
+
+    #[test]
+    fn median_of_nothing() {
+        assert_eq!(median(vec![]), None);
+    }
+
+    #[test]
+    fn median_of_an_odd_number() {
+        assert_eq!(median(vec![7.0]), Some(7.0));
+        assert_eq!(median(vec![30.0, 5.0, 10.0]), Some(10.0));
+    }
+
+    #[test]
+    fn median_of_an_even_number() {
+        assert_eq!(median(vec![40.0, 10.0, 5.0, 20.0]), Some(15.0));
+    }
+
+    #[test]
+    fn median_with_ties() {
+        assert_eq!(median(vec![5.0, 5.0, 5.0, 100.0]), Some(5.0));
+        assert_eq!(median(vec![5.0, 100.0, 5.0, 100.0]), Some(52.5));
+    }
+
+    #[test]
+    fn no_points() {
+        let stats = compute(&[]);
+        assert_eq!((stats.distance, stats.moving_time), (0.0, 0));
+        assert_eq!(stats.median_accuracy, None);
+    }
+
+    #[test]
+    fn standing_still_isnt_moving() {
+        // About 11 meters in ten minutes is GPS jitter.
+        let stats = compute(&[at(0.0, 10.0, 0), at(0.0001, 10.0, 600)]);
+        assert_eq!(stats.moving_time, 0);
+        assert_eq!(stats.average_speed, 0.0);
+        assert!((stats.distance - 11.1).abs() < 0.1);
+    }
+
+    #[test]
+    fn moving() {
+        // About 1.1 km a minute, then a fix in the same second that says nothing about speed.
+        let points = [at(0.0, 10.0, 0), at(0.01, 20.0, 60), at(0.01, 30.0, 60)];
+        let stats = compute(&points);
+        assert_eq!(stats.moving_time, 60);
+        assert!((stats.max_speed - 18.53).abs() < 0.01);
+        assert_eq!(stats.average_speed, stats.distance / 60.0);
+        assert_eq!(stats.median_accuracy, Some(20.0));
+    }
+}
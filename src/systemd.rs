@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use actix_web::rt;
+use sd_notify::NotifyState;
+
+/// Tells systemd something, if it's listening. Outside of systemd this does nothing.
+fn notify(state: NotifyState) {
+    if let Err(e) = sd_notify::notify(false, &[state]) {
+        log::warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+/// Tells systemd we're up and taking requests.
+pub(crate) fn notify_ready() {
+    notify(NotifyState::Ready);
+}
+
+/// Tells systemd we're rereading the config. Follow it up with `notify_ready`.
+pub(crate) fn notify_reloading() {
+    notify(NotifyState::Reloading);
+}
+
+/// Tells systemd we're on our way out.
+pub(crate) fn notify_stopping() {
+    notify(NotifyState::Stopping);
+}
+
+/// If systemd wants watchdog pings, starts sending them at twice the rate it asks for. They come
+/// from the main runtime, so if that wedges, they stop and systemd restarts us.
+pub(crate) fn spawn_watchdog() {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let period = Duration::from_micros(usec / 2);
+    log::info!("Pinging the systemd watchdog every {:?}.", period);
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(period);
+        loop {
+            interval.tick().await;
+            notify(NotifyState::Watchdog);
+        }
+    });
+}
@@ -0,0 +1,346 @@
+use actix_web::{get, http::header::ContentType, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::TimelineConfig,
+    db,
+    geo::{centroid, distance_between, path_length},
+    groups::require_visible,
+    location::{authenticate_session, Location},
+    misc::{bad_request, day_bounds, forbidden, internal_error},
+    privacy::{viewer_access, Access},
+    AppState,
+};
+
+#[derive(Deserialize)]
+pub(crate) struct TimelineIn {
+    /// The api key id to build the timeline for.
+    id: u64,
+    /// The day, as YYYY-MM-DD.
+    date: String,
+    /// The viewer's offset from UTC in minutes, so that "the day" means their day.
+    #[serde(default)]
+    utc_offset: i64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum TimelineEntry {
+    /// The device lingered in one place.
+    Stay {
+        /// Seconds since the unix epoch.
+        start: u64,
+        /// Seconds since the unix epoch.
+        end: u64,
+        /// Degrees, the centroid of the stay's points.
+        latitude: f64,
+        /// Degrees, the centroid of the stay's points.
+        longitude: f64,
+        points: usize,
+    },
+    /// The device moved between stays.
+    Trip {
+        /// Seconds since the unix epoch.
+        start: u64,
+        /// Seconds since the unix epoch.
+        end: u64,
+        /// Meters.
+        distance: f64,
+        /// Meters per second.
+        average_speed: f64,
+        points: usize,
+    },
+}
+
+#[derive(Serialize)]
+struct TimelineOut {
+    date: String,
+    entries: Vec<TimelineEntry>,
+}
+
+/// Builds a trip entry out of a run of points. The run should include the last point of
+/// the previous stay and the first point of the next one, if there are any.
+fn make_trip(points: &[Location]) -> Option<TimelineEntry> {
+    if points.len() < 2 {
+        return None;
+    }
+    let (first, last) = (points.first()?, points.last()?);
+    let distance = path_length(points);
+    let duration = last.time - first.time;
+    Some(TimelineEntry::Trip {
+        start: first.time,
+        end: last.time,
+        distance,
+        average_speed: if duration > 0 {
+            distance / duration as f64
+        } else {
+            0.0
+        },
+        points: points.len(),
+    })
+}
+
+/// Splits a time-ordered track into stays and the trips between them.
+///
+/// A stay is a run of consecutive points that all lie within the configured radius of the
+/// run's first point, spanning at least the configured number of minutes. Everything else
+/// is travel.
+fn segment(points: &[Location], config: &TimelineConfig) -> Vec<TimelineEntry> {
+    let min_duration = config.stay_min_minutes * 60;
+    let mut entries = Vec::new();
+    // The index of the last point of the most recent stay, where the next trip departs from.
+    let mut trip_start: Option<usize> = None;
+    let mut i = 0;
+
+    while i < points.len() {
+        // Extend the candidate stay as far as the radius allows.
+        let anchor = &points[i];
+        let mut j = i;
+        while j + 1 < points.len()
+            && distance_between(anchor, &points[j + 1]) <= config.stay_radius_meters
+        {
+            j += 1;
+        }
+
+        if points[j].time - anchor.time < min_duration {
+            // Not long enough, so this point is part of a trip.
+            i += 1;
+            continue;
+        }
+
+        // Whatever came between the last stay and this one was a trip.
+        let from = trip_start.unwrap_or(0);
+        if let Some(trip) = make_trip(&points[from..=i]) {
+            entries.push(trip);
+        }
+
+        let stay = &points[i..=j];
+        let (latitude, longitude) = centroid(stay);
+        entries.push(TimelineEntry::Stay {
+            start: anchor.time,
+            end: points[j].time,
+            latitude,
+            longitude,
+            points: stay.len(),
+        });
+
+        trip_start = Some(j);
+        i = j + 1;
+    }
+
+    // Anything after the last stay is a trip that's still going (or ended off the record).
+    if let Some(trip) = make_trip(&points[trip_start.unwrap_or(0)..]) {
+        entries.push(trip);
+    }
+    entries
+}
+
+#[get("/api/location/timeline")]
+pub(crate) async fn get_location_timeline(
+    info: web::Query<TimelineIn>,
+    data: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the session token from the cookies and confirm that it's authentic.
+    let session = match authenticate_session(req, &data) {
+        Some(s) => s,
+        None => return forbidden(),
+    };
+    if let Err(response) = require_visible(&data, info.id, &session.name).await {
+        return response;
+    }
+
+    // Find out how much of this device's history the viewer is allowed to see.
+    let access = match viewer_access(&data, info.id, Some(&session.name)).await {
+        Ok(Access::Hidden { .. }) => return forbidden(),
+        Ok(a) => a,
+        Err(e) => {
+            log::error!(
+                "/api/location/timeline: failed to check privacy policy: {}",
+                e
+            );
+            return internal_error();
+        }
+    };
+
+    // Work out which stretch of unix time the requested day covers.
+    let (start, end) = match day_bounds(&info.date, info.utc_offset) {
+        Some(b) => b,
+        None => return bad_request("Bad date, expected YYYY-MM-DD."),
+    };
+
+    // Blur the points before segmenting, so the stays come out no more precise than allowed.
+    let points: Vec<Location> =
+        match db::get_locations(&data.pool, info.id, session.name.clone(), start, end).await {
+            Ok(p) => p
+                .iter()
+                .filter_map(|loc| access.apply(loc, &data))
+                .collect(),
+            Err(e) => {
+                log::error!("/api/location/timeline: failed to read history: {}", e);
+                return internal_error();
+            }
+        };
+
+    // Chop the day's track up into stays and trips, and send it off.
+    HttpResponse::Ok().insert_header(ContentType::json()).body(
+        serde_json::to_string(&TimelineOut {
+            date: info.date.clone(),
+            entries: segment(&points, &data.config().timeline),
+        })
+        .unwrap(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// About 11 meters of latitude.
+    const STEP: f64 = 0.0001;
+
+    fn at(latitude: f64, longitude: f64, time: u64) -> Location {
+        Location {
+            latitude,
+            longitude,
+            accuracy: 10.0,
+            time,
+        }
+    }
+
+    /// Points a minute apart that don't go anywhere.
+    fn linger(latitude: f64, longitude: f64, from: u64, minutes: u64) -> Vec<Location> {
+        (0..=minutes)
+            .map(|m| at(latitude, longitude, from + m * 60))
+            .collect()
+    }
+
+    /// Points a minute apart heading north, about 1.1 km each.
+    fn travel(latitude: f64, longitude: f64, from: u64, minutes: u64) -> Vec<Location> {
+        (1..minutes)
+            .map(|m| at(latitude + m as f64 * 100.0 * STEP, longitude, from + m * 60))
+            .collect()
+    }
+
+    #[test]
+    fn no_points() {
+        assert!(segment(&[], &TimelineConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn a_single_point_is_nothing() {
+        let points = [at(51.5, -0.1, 1000)];
+        assert!(segment(&points, &TimelineConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn one_long_stay() {
+        let points = linger(51.5, -0.1, 0, 30);
+        let entries = segment(&points, &TimelineConfig::default());
+        assert_eq!(entries.len(), 1);
+        let TimelineEntry::Stay {
+            start,
+            end,
+            latitude,
+            longitude,
+            points,
+        } = entries[0]
+        else {
+            panic!("expected a stay");
+        };
+        assert_eq!((start, end, points), (0, 30 * 60, 31));
+        assert!((latitude - 51.5).abs() < 1e-9 && (longitude + 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn just_long_enough_to_stay() {
+        let config = TimelineConfig::default();
+        let points = linger(51.5, -0.1, 0, config.stay_min_minutes);
+        assert!(matches!(
+            segment(&points, &config)[..],
+            [TimelineEntry::Stay { points: 11, .. }]
+        ));
+        // A minute short, and it's a trip that goes nowhere.
+        let points = linger(51.5, -0.1, 0, config.stay_min_minutes - 1);
+        assert!(matches!(
+            segment(&points, &config)[..],
+            [TimelineEntry::Trip { distance, .. }] if distance == 0.0
+        ));
+    }
+
+    #[test]
+    fn stay_trip_stay() {
+        let mut points = linger(51.5, -0.1, 0, 20);
+        points.extend(travel(51.5, -0.1, 20 * 60, 5));
+        points.extend(linger(51.5 + 500.0 * STEP, -0.1, 25 * 60, 20));
+        let entries = segment(&points, &TimelineConfig::default());
+        assert_eq!(entries.len(), 3);
+        assert!(matches!(
+            entries[0],
+            TimelineEntry::Stay {
+                start: 0,
+                end: 1200,
+                points: 21,
+                ..
+            }
+        ));
+        // The trip runs from the end of one stay to the start of the next.
+        let TimelineEntry::Trip {
+            start,
+            end,
+            distance,
+            average_speed,
+            points,
+        } = entries[1]
+        else {
+            panic!("expected a trip");
+        };
+        assert_eq!((start, end, points), (1200, 1500, 6));
+        assert!((distance - 5566.0).abs() < 10.0);
+        assert!((average_speed - distance / 300.0).abs() < 1e-9);
+        assert!(matches!(
+            entries[2],
+            TimelineEntry::Stay {
+                start: 1500,
+                end: 2700,
+                points: 21,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn trailing_trip() {
+        let mut points = linger(51.5, -0.1, 0, 15);
+        points.extend(travel(51.5, -0.1, 15 * 60, 4));
+        let entries = segment(&points, &TimelineConfig::default());
+        assert!(matches!(
+            entries[..],
+            [
+                TimelineEntry::Stay { end: 900, .. },
+                TimelineEntry::Trip {
+                    start: 900,
+                    end: 1080,
+                    points: 4,
+                    ..
+                },
+            ]
+        ));
+    }
+
+    #[test]
+    fn stay_across_the_antimeridian() {
+        let points: Vec<Location> = (0..=20)
+            .map(|m| {
+                let longitude = if m % 2 == 0 { 179.9998 } else { -179.9998 };
+                at(-16.5, longitude, m * 60)
+            })
+            .collect();
+        let entries = segment(&points, &TimelineConfig::default());
+        let [TimelineEntry::Stay { longitude, .. }] = entries[..] else {
+            panic!("expected a single stay");
+        };
+        // Right next to the antimeridian, not out by Greenwich.
+        assert!(longitude.abs() > 179.999);
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::rt;
+use parking_lot::{Mutex, RwLock};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, ServerConfig,
+};
+use rustls_pemfile::Item;
+
+use crate::config::TlsConfig;
+
+/// How often to look at the certificate files to see if they've been renewed.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Hands out whatever certificate was last loaded from disk, so that a renewed certificate
+/// can be swapped in without restarting.
+pub(crate) struct ReloadingCert {
+    paths: TlsConfig,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// When the cert and key files were last changed, as of the last load.
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ResolvesServerCert for ReloadingCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().clone())
+    }
+}
+
+/// When a file was last changed, if we can tell.
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Reads a certificate chain and its private key from PEM files.
+fn load_certified_key(paths: &TlsConfig) -> io::Result<CertifiedKey> {
+    let bad = |why: String| io::Error::new(io::ErrorKind::InvalidData, why);
+
+    let mut cert_file = BufReader::new(File::open(&paths.cert_path)?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut cert_file)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(bad(format!("No certificates in {}.", paths.cert_path)));
+    }
+
+    // Take the first private key in the file, whatever sort it is.
+    let mut key_file = BufReader::new(File::open(&paths.key_path)?);
+    let key = rustls_pemfile::read_all(&mut key_file)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| bad(format!("No private key in {}.", paths.key_path)))?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| bad(format!("Unsupported private key in {}.", paths.key_path)))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+impl ReloadingCert {
+    /// Loads the certificate for the first time. This one's allowed to fail loudly.
+    fn load(paths: &TlsConfig) -> io::Result<ReloadingCert> {
+        // Note the times first, so a change partway through the load gets picked up next check.
+        let times = (modified(&paths.cert_path), modified(&paths.key_path));
+        let key = load_certified_key(paths)?;
+        Ok(ReloadingCert {
+            paths: paths.clone(),
+            current: RwLock::new(Arc::new(key)),
+            modified: Mutex::new(times),
+        })
+    }
+
+    /// Loads the certificate again if either file has changed. If the new one's no good
+    /// (half-written, say), keeps serving the old one and tries again next time.
+    fn reload_if_changed(&self) {
+        let times = (
+            modified(&self.paths.cert_path),
+            modified(&self.paths.key_path),
+        );
+        let mut last = self.modified.lock();
+        if *last == times {
+            return;
+        }
+        match load_certified_key(&self.paths) {
+            Ok(key) => {
+                *self.current.write() = Arc::new(key);
+                *last = times;
+                log::info!("Reloaded TLS certificate from {}.", self.paths.cert_path);
+            }
+            Err(e) => log::error!(
+                "Failed to reload TLS certificate from {}: {}",
+                self.paths.cert_path,
+                e
+            ),
+        }
+    }
+}
+
+/// Builds the rustls config for a listen address, along with the handle that lets us swap
+/// its certificate out later.
+pub(crate) fn server_config(paths: &TlsConfig) -> io::Result<(ServerConfig, Arc<ReloadingCert>)> {
+    let cert = Arc::new(ReloadingCert::load(paths)?);
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(cert.clone());
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok((config, cert))
+}
+
+/// Starts the background job that picks up renewed certificates, if there are any to watch.
+pub(crate) fn spawn_reloading(certs: Vec<Arc<ReloadingCert>>) {
+    if certs.is_empty() {
+        return;
+    }
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(RELOAD_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            for cert in &certs {
+                cert.reload_if_changed();
+            }
+        }
+    });
+}